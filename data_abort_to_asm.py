@@ -1,5 +0,0 @@
-l = eval(input("src: "))
-for x in l:
-    print(hex(x)[2:])
-
-print(hex(int(input("\nBase Adress:")))[2:])
\ No newline at end of file
@@ -0,0 +1,56 @@
+"""Writes the symbol table of the kernel into its .ksyms section.
+
+The crash reports use it to show function names (see rust_os/src/symbols.rs).
+Usage: python3 ksyms.py <kernel elf>
+NM and OBJCOPY can be overwritten with environment variables.
+"""
+import os
+import re
+import struct
+import subprocess
+import sys
+import tempfile
+
+KSYMS_SIZE = 64 * 1024
+MAX_NAME = 64
+NM = os.environ.get("NM", "arm-none-eabi-nm")
+OBJCOPY = os.environ.get("OBJCOPY", "arm-none-eabi-objcopy")
+
+
+def symbols(elf):
+    out = subprocess.run(
+        [NM, "--defined-only", "-C", "-n", elf], check=True, capture_output=True, text=True
+    ).stdout
+    for line in out.splitlines():
+        parts = line.split(maxsplit=2)
+        # only functions
+        if len(parts) == 3 and parts[1] in "tT":
+            # strip the rust hash
+            name = re.sub(r"::h[0-9a-f]{16}$", "", parts[2])
+            yield int(parts[0], 16), name[:MAX_NAME]
+
+
+def table(syms):
+    syms = sorted(dict(syms).items())
+    names = b""
+    entries = b""
+    for addr, name in syms:
+        entries += struct.pack("<II", addr, len(names))
+        names += name.encode() + b"\0"
+    data = b"KSYM" + struct.pack("<I", len(syms)) + entries + names
+    if len(data) > KSYMS_SIZE:
+        sys.exit(f"symbol table too large: {len(data)} > {KSYMS_SIZE} bytes")
+    # same size as the reserved section, so that nothing moves
+    return data.ljust(KSYMS_SIZE, b"\0")
+
+
+def main(elf):
+    data = table(symbols(elf))
+    with tempfile.NamedTemporaryFile() as f:
+        f.write(data)
+        f.flush()
+        subprocess.run([OBJCOPY, "--update-section", f".ksyms={f.name}", elf], check=True)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1])
@@ -0,0 +1,66 @@
+//! Blockgeräte
+//!
+//! Ein Blockgerät liest und schreibt ganze Sektoren. Dateisysteme arbeiten nur über [BlockDevice]
+//! und wissen nichts von der Hardware dahinter.
+//! - cache: ein write-back Cache vor einem anderen Blockgerät
+//! - ram_disk: ein Blockgerät im Kernel-Speicher
+
+pub mod cache;
+pub mod ram_disk;
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub type Sector = [u8; SECTOR_SIZE];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockError {
+    /// The sector is behind the end of the device
+    OutOfRange,
+    /// There is nothing to read from, e.g. no card in the slot
+    NoMedium,
+    /// Any other error of the device
+    Device(&'static str),
+}
+
+impl BlockError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockError::OutOfRange => "Sector out of range",
+            BlockError::NoMedium => "No medium",
+            BlockError::Device(err) => err,
+        }
+    }
+}
+
+impl From<BlockError> for &'static str {
+    fn from(err: BlockError) -> Self {
+        err.as_str()
+    }
+}
+
+impl core::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A device that stores sectors
+pub trait BlockDevice {
+    /// The size of a sector in bytes. Everything above the devices works with SECTOR_SIZE
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    /// The number of sectors
+    fn sector_count(&self) -> u32;
+
+    fn read(&mut self, sector: u32, buf: &mut Sector) -> Result<(), BlockError>;
+
+    /// May only reach the device on the next flush
+    fn write(&mut self, sector: u32, buf: &Sector) -> Result<(), BlockError>;
+
+    /// Writes everything that is still buffered to the device
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Ein Block-Cache
+//!
+//! Hält die zuletzt benutzten Sektoren eines anderen Blockgeräts. Geschriebene Sektoren gehen erst
+//! auf das Gerät, wenn ihr Platz gebraucht wird oder bei flush (write-back).
+//! Verdrängt wird der am längsten nicht benutzte Sektor.
+
+use super::{BlockDevice, BlockError, Sector, SECTOR_SIZE};
+
+#[derive(Clone, Copy)]
+struct Line {
+    sector: Option<u32>,
+    /// Changed since it was read from the device
+    dirty: bool,
+    /// The clock of the last access
+    used: u32,
+    data: Sector,
+}
+
+const EMPTY: Line = Line {
+    sector: None,
+    dirty: false,
+    used: 0,
+    data: [0; SECTOR_SIZE],
+};
+
+pub struct BlockCache<const N: usize> {
+    device: &'static mut dyn BlockDevice,
+    lines: [Line; N],
+    /// Counts the accesses
+    clock: u32,
+}
+
+impl<const N: usize> BlockCache<N> {
+    pub fn new(device: &'static mut dyn BlockDevice) -> BlockCache<N> {
+        BlockCache {
+            device,
+            lines: [EMPTY; N],
+            clock: 0,
+        }
+    }
+
+    /// Writes the line back to the device, if it changed
+    fn clean(&mut self, line: usize) -> Result<(), BlockError> {
+        let line = &mut self.lines[line];
+        if let (Some(sector), true) = (line.sector, line.dirty) {
+            self.device.write(sector, &line.data)?;
+            line.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// The line that holds the sector. A new line is only filled from the device if load is set
+    fn line(&mut self, sector: u32, load: bool) -> Result<usize, BlockError> {
+        if sector >= self.device.sector_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        self.clock = self.clock.wrapping_add(1);
+        let index = match self
+            .lines
+            .iter()
+            .position(|line| line.sector == Some(sector))
+        {
+            Some(hit) => hit,
+            None => {
+                // A free line, otherwise the least recently used one
+                let clock = self.clock;
+                let victim = (0..N)
+                    .max_by_key(|&i| match self.lines[i].sector {
+                        Some(_) => clock.wrapping_sub(self.lines[i].used),
+                        None => u32::MAX,
+                    })
+                    .unwrap();
+                self.clean(victim)?;
+                let line = &mut self.lines[victim];
+                line.sector = None;
+                if load {
+                    self.device.read(sector, &mut line.data)?;
+                }
+                line.sector = Some(sector);
+                victim
+            }
+        };
+        self.lines[index].used = self.clock;
+        Ok(index)
+    }
+}
+
+impl<const N: usize> BlockDevice for BlockCache<N> {
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u32 {
+        self.device.sector_count()
+    }
+
+    fn read(&mut self, sector: u32, buf: &mut Sector) -> Result<(), BlockError> {
+        let line = self.line(sector, true)?;
+        buf.copy_from_slice(&self.lines[line].data);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u32, buf: &Sector) -> Result<(), BlockError> {
+        let line = self.line(sector, false)?;
+        let line = &mut self.lines[line];
+        line.data.copy_from_slice(buf);
+        line.dirty = true;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        for line in 0..N {
+            self.clean(line)?;
+        }
+        self.device.flush()
+    }
+}
@@ -0,0 +1,39 @@
+//! Eine RAM-Disk
+//!
+//! Die Sektoren liegen in einem statischen Array des Kernels, ihr Inhalt ist nach einem Reset weg.
+
+use super::{BlockDevice, BlockError, Sector};
+
+pub struct RamDisk {
+    sectors: &'static mut [Sector],
+}
+
+impl RamDisk {
+    pub fn new(sectors: &'static mut [Sector]) -> RamDisk {
+        RamDisk { sectors }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_count(&self) -> u32 {
+        self.sectors.len() as u32
+    }
+
+    fn read(&mut self, sector: u32, buf: &mut Sector) -> Result<(), BlockError> {
+        let data = self
+            .sectors
+            .get(sector as usize)
+            .ok_or(BlockError::OutOfRange)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u32, buf: &Sector) -> Result<(), BlockError> {
+        let data = self
+            .sectors
+            .get_mut(sector as usize)
+            .ok_or(BlockError::OutOfRange)?;
+        data.copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+//! Die Uhrzeit
+//!
+//! Der Real-time Timer des System-Timers hat nur 20 Bit und läuft nach etwa 17 Minuten über.
+//! Wir erweitern ihn bei jedem Lesen und bei jedem Timer-Interrupt zu einem 64-Bit-Zähler seit dem Boot,
+//! damit kein Überlauf verloren geht, weckt die Uhr den Scheduler auch im tickless mode regelmäßig.
+//! Die Uhrzeit ist dieser Zähler plus die Unix-Zeit beim Boot. Die kommt entweder aus einer RTC
+//! (DS1307 oder DS3231) am TWI oder aus dem SetTime-Syscall. Es gibt keine Zeitzonen, alles ist UTC.
+
+use crate::{
+    consts::RTC_TWI_ADDRESS,
+    info,
+    sys_timer::{ticks_between, SysTimer, RTT_HZ},
+    twi::Twi,
+    util::without_interrupts,
+    warn,
+};
+use core::{fmt, ptr::addr_of_mut};
+
+/// How often the clock has to look at the real-time timer, well below its overflow
+const UPDATE_MS: u32 = 4 * 60 * 1000;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// Days from 0000-03-01 to 1970-01-01, see days_from_civil
+const UNIX_EPOCH_DAYS: u64 = 719_468;
+const DAYS_PER_ERA: u64 = 146_097;
+// RTC registers (BCD)
+/// Stops the oscillator of a DS1307
+const CLOCK_HALT: u8 = 1 << 7;
+const HOURS_12: u8 = 1 << 6;
+
+/// Seconds and microseconds since 1970-01-01 00:00:00 UTC. The argument of GetTimeOfDay
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeVal {
+    pub secs: u32,
+    pub usecs: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: u32,
+    /// 1 - 12
+    pub month: u8,
+    /// 1 - 31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+struct Clock {
+    /// The real-time timer at the last update
+    last: u32,
+    /// Ticks of the real-time timer since boot
+    ticks: u64,
+    /// When the clock has to be updated at the latest
+    next: u32,
+    /// The Unix time at boot in ticks, None until the clock is set
+    boot: Option<u64>,
+    /// Whether there is an RTC that keeps the time
+    rtc: bool,
+}
+
+static mut CLOCK: Clock = Clock {
+    last: 0,
+    ticks: 0,
+    next: 0,
+    boot: None,
+    rtc: false,
+};
+
+/// Days since 1970-01-01 of a date, for years from 1970 on (see http://howardhinnant.github.io/date_algorithms.html)
+fn days_from_civil(year: u32, month: u8, day: u8) -> u64 {
+    // The year starts in March, so the leap day is at its end
+    let year = if month <= 2 { year - 1 } else { year } as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month = (month as u64 + 9) % 12;
+    let day_of_year = (153 * month + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS
+}
+
+/// The date of the given days since 1970-01-01
+fn civil_from_days(days: u64) -> (u32, u8, u8) {
+    let days = days + UNIX_EPOCH_DAYS;
+    let era = days / DAYS_PER_ERA;
+    let day_of_era = days - era * DAYS_PER_ERA;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year as u32, month as u8, day as u8)
+}
+
+fn is_leap_year(year: u32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+impl DateTime {
+    pub fn from_unix(secs: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        let time = secs % SECS_PER_DAY;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Seconds since 1970-01-01. Fails for invalid dates and years before 1970
+    pub fn to_unix(self) -> Result<u64, &'static str> {
+        let days_in_month = match self.month {
+            2 if is_leap_year(self.year) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            1..=12 => 31,
+            _ => return Err("Invalid month"),
+        };
+        if self.year < 1970 || self.day == 0 || self.day > days_in_month {
+            return Err("Invalid date");
+        }
+        if self.hour > 23 || self.minute > 59 || self.second > 59 {
+            return Err("Invalid time");
+        }
+        Ok(
+            days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+                + self.hour as u64 * 3600
+                + self.minute as u64 * 60
+                + self.second as u64,
+        )
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[inline(always)]
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xF)
+}
+
+#[inline(always)]
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Reads the RTC, None if its oscillator is stopped. Years are 2000 - 2099
+fn read_rtc(device: u8) -> Result<Option<DateTime>, &'static str> {
+    let mut regs = [0; 7];
+    Twi::new().read_register(device, 0, &mut regs)?;
+    if regs[0] & CLOCK_HALT != 0 {
+        return Ok(None);
+    }
+    let hour = match regs[2] & HOURS_12 {
+        0 => from_bcd(regs[2] & 0x3F),
+        // 12 hour mode, bit 5 is PM
+        _ => from_bcd(regs[2] & 0x1F) % 12 + if regs[2] & (1 << 5) != 0 { 12 } else { 0 },
+    };
+    Ok(Some(DateTime {
+        year: 2000 + from_bcd(regs[6]) as u32,
+        month: from_bcd(regs[5] & 0x1F),
+        day: from_bcd(regs[4] & 0x3F),
+        hour,
+        minute: from_bcd(regs[1] & 0x7F),
+        second: from_bcd(regs[0] & 0x7F),
+    }))
+}
+
+/// Sets the RTC in 24 hour mode and starts its oscillator
+fn write_rtc(device: u8, time: &DateTime) -> Result<(), &'static str> {
+    if !(2000..2100).contains(&time.year) {
+        return Err("The RTC only knows the years 2000 - 2099");
+    }
+    let days = days_from_civil(time.year, time.month, time.day);
+    // 1970-01-01 was a Thursday, the RTC counts Sunday as 1
+    let weekday = ((days + 4) % 7) as u8 + 1;
+    let regs = [
+        to_bcd(time.second),
+        to_bcd(time.minute),
+        to_bcd(time.hour),
+        weekday,
+        to_bcd(time.day),
+        to_bcd(time.month),
+        to_bcd((time.year - 2000) as u8),
+    ];
+    Twi::new().write_register(device, 0, &regs)
+}
+
+/// Adds the ticks since the last call. Has to run at least every UPDATE_MS
+fn advance(now: u32) -> u64 {
+    let clock = unsafe { &mut *addr_of_mut!(CLOCK) };
+    clock.ticks += ticks_between(clock.last, now) as u64;
+    clock.last = now;
+    clock.next = SysTimer::new().deadline_in(UPDATE_MS);
+    clock.ticks
+}
+
+fn update() -> u64 {
+    without_interrupts(|| advance(SysTimer::new().now()))
+}
+
+/// Starts counting from 0. Called by the sys timer when it restarts the real-time timer
+pub fn restart() {
+    without_interrupts(|| unsafe {
+        CLOCK.last = 0;
+        CLOCK.ticks = 0;
+        CLOCK.next = SysTimer::new().deadline_in(UPDATE_MS);
+    })
+}
+
+/// Reads the time from the RTC, if there is one. Needs the TWI
+pub fn init() {
+    let Some(device) = RTC_TWI_ADDRESS else {
+        return;
+    };
+    match read_rtc(device) {
+        Ok(time) => {
+            unsafe { CLOCK.rtc = true };
+            match time.map(DateTime::to_unix) {
+                Some(Ok(secs)) => {
+                    set_unix(secs, 0);
+                    info!("Read the time from the RTC: {}", DateTime::from_unix(secs));
+                }
+                Some(Err(err)) => warn!("The RTC has no valid time: {err}"),
+                None => info!("The RTC is stopped, SetTime starts it"),
+            }
+        }
+        Err(err) => warn!("No RTC: {err}"),
+    }
+}
+
+/// Called by the sys timer on every interrupt
+#[inline(always)]
+pub fn tick(now: u32) {
+    advance(now);
+}
+
+/// When the sys timer has to wake up at the latest, so that no overflow of the real-time timer is missed
+pub fn next_deadline() -> Option<u32> {
+    Some(unsafe { CLOCK.next })
+}
+
+/// Milliseconds since boot
+pub fn uptime_ms() -> u64 {
+    update() * 1000 / RTT_HZ as u64
+}
+
+fn set_unix(secs: u64, usecs: u32) {
+    let ticks = secs * RTT_HZ as u64 + usecs as u64 * RTT_HZ as u64 / 1_000_000;
+    let uptime = update();
+    unsafe { CLOCK.boot = Some(ticks.saturating_sub(uptime)) };
+}
+
+/// The current time, None if it was never set
+pub fn now() -> Option<TimeVal> {
+    let boot = unsafe { CLOCK.boot }?;
+    let ticks = boot + update();
+    Some(TimeVal {
+        secs: (ticks / RTT_HZ as u64) as u32,
+        usecs: ((ticks % RTT_HZ as u64) * 1_000_000 / RTT_HZ as u64) as u32,
+    })
+}
+
+/// Sets the clock and the RTC, if there is one. Used by the SetTime syscall
+pub fn set(time: TimeVal) -> Result<(), &'static str> {
+    if time.usecs >= 1_000_000 {
+        return Err("Invalid microseconds");
+    }
+    set_unix(time.secs as u64, time.usecs);
+    let date = DateTime::from_unix(time.secs as u64);
+    info!("Set the clock to {date}");
+    match (RTC_TWI_ADDRESS, unsafe { CLOCK.rtc }) {
+        (Some(device), true) => write_rtc(device, &date),
+        _ => Ok(()),
+    }
+}
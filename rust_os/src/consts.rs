@@ -1,6 +1,15 @@
 //! All kind of constants
 //!
-//! Includes our memory layout, the number of possible threads, stack sizes and interrupt time slices
+//! Includes our memory layout, the number of possible threads, stack sizes, interrupt time slices, the master clock, the FIQ,
+//! the kernel log configuration, the RTC, the status LEDs, the watchdog, the network, the memory card, the RAM disk and what happens on a panic
+
+use crate::{
+    exceptions::{FiqHandler, SrcType},
+    led::{Led, YELLOW},
+    log::Level,
+    power_management::ClockSource,
+    serial::SerialConfig,
+};
 
 /*
 Our memory layout is pretty simple:
@@ -16,22 +25,103 @@ Our memory layout is pretty simple:
 // Kernel stuff
 pub const KERNEL_STACK_SIZE: usize = 64 * 1024; // 64 kB
 pub const KERNEL_MEM: usize = 0x2400_0000;
+// svc, und, abt, irq, fiq and sys each have their own stack
+pub const KERNEL_STACK_NUMBER: usize = 6;
+// The lowest bytes of every kernel mode stack are canaries, see kernel_stack.rs
+pub const KERNEL_STACK_GUARD_SIZE: usize = 64;
+// The sys mode stack from _start. After boot it is used by (nested) interrupt handlers
+pub const SYS_STACK_TOP: usize = KERNEL_MEM - (KERNEL_STACK_NUMBER - 1) * KERNEL_STACK_SIZE;
 
 // User stuff
 // If THREAD_NUMBER is changed, also change in thread.rs
 pub const THREAD_NUMBER: usize = 16;
 // points to top
-pub const USER_MEM: usize = 0x2400_0000 - 5 * KERNEL_STACK_SIZE;
+pub const USER_MEM: usize = 0x2400_0000 - KERNEL_STACK_NUMBER * KERNEL_STACK_SIZE;
 // align this to 4
 pub const USER_STACK_SIZE: usize = ((USER_MEM - 0x2200_0000) / THREAD_NUMBER) / 4 * 4;
+// The lowest bytes of every user stack are a guard region filled with canaries
+// If one of them changes, the thread has overflowed its stack
+pub const STACK_GUARD_SIZE: usize = 256;
+pub const STACK_CANARY: u32 = 0xDEAD_BEEF;
 
 // Time slicing
 pub const TIME_SLICE: u32 = 32768;
 pub const MS_PER_SLICE: u32 = TIME_SLICE * 1000 / 32768;
+// In tickless mode there are no time slice interrupts while the idle thread runs,
+// the sys timer only wakes the cpu for the next sleeping thread
+pub const TICKLESS: bool = true;
+
+// The master clock as (source, prescaler, divider), see PMC::set_master_clock. None keeps the one of the boot loader.
+// The PLL must already be locked and the SDRAM timing of the boot loader must still fit
+pub const MASTER_CLOCK: Option<(ClockSource, u32, u32)> = None;
+
+// The fast interrupt (FIQ pin, AIC source 0) as (handler, source type), see AIC::route_fiq. None leaves it off
+pub const FIQ: Option<(FiqHandler, SrcType)> = None;
+
+// Kernel log
+pub const LOG_BUFFER_SIZE: usize = 8 * 1024;
+// Records above this level are dropped
+pub const LOG_LEVEL: Level = Level::Info;
+// Overrides LOG_LEVEL for all modules starting with the given path, e.g. ("rust_os::thread", Level::Trace)
+pub const LOG_FILTERS: &[(&str, Level)] = &[("rust_os::driver", Level::Info)];
+
+// Whether the dbgu answers gdb (ctrl+c or a '$' enter the stub, see gdb.rs).
+// Off by default, since then any '$' typed on the console halts the whole system
+pub const GDB_STUB: bool = false;
+
+// USART0 - USART3 (the TTYs 1 - 4). USART2 can't use flow control, its pins are the DBGU
+pub const USARTS: [SerialConfig; 4] = [SerialConfig {
+    baud_rate: 115_200,
+    flow_control: false,
+}; 4];
+
+// Connects MOSI to MISO inside the SPI, to test without a device
+pub const SPI_LOOPBACK: bool = false;
+
+// The TWI (I2C) bus clock, 100 kHz is standard mode
+pub const TWI_CLOCK_HZ: u32 = 100_000;
+// The address of a DS1307 or DS3231 on the TWI that keeps the time (see clock.rs), None if there is none
+pub const RTC_TWI_ADDRESS: Option<u8> = Some(0x68);
+
+// Network (see net.rs). The MAC is locally administered, change it if several boards share a network
+pub const NET_MAC: [u8; 6] = [0x02, 0x00, 0x91, 0x92, 0x00, 0x01];
+pub const NET_IP: [u8; 4] = [10, 0, 2, 15];
+pub const NET_NETMASK: [u8; 4] = [255, 255, 255, 0];
+pub const NET_GATEWAY: [u8; 4] = [10, 0, 2, 2];
+// Whether the PHY is connected over RMII (the AT91RM9200-EK) or MII. The MII also takes PB12 - PB19
+pub const NET_RMII: bool = true;
+
+// The memory card. The 4-bit bus needs the pins PB3 - PB5
+pub const MCI_CLOCK_HZ: u32 = 25_000_000;
+pub const MCI_WIDE_BUS: bool = true;
+// Where the file system of the card is mounted (see fs.rs)
+pub const SD_MOUNT: &str = "/sd";
+// How many sectors of the card are cached, writes reach the card when a file is closed
+pub const BLOCK_CACHE_SECTORS: usize = 16;
+
+// The RAM disk in kernel memory (4 MB), formatted with FAT16 at boot
+pub const RAM_DISK_SECTORS: usize = 8192;
+pub const RAM_MOUNT: &str = "/ram";
+
+// Status LEDs (see led.rs). None turns the indicator off
+// With only one LED on the board, heartbeat and idle would overwrite each other
+pub const HEARTBEAT_LED: Option<Led> = Some(YELLOW);
+pub const HEARTBEAT_MS: u32 = 500;
+pub const IDLE_LED: Option<Led> = None;
+pub const PANIC_LED: Option<Led> = Some(YELLOW);
+
+// The watchdog resets the board if it isn't petted for this long (at most 255 s), None disables it
+// After a panic it is paused, unless PANIC_RESET_TICKS is set
+pub const WATCHDOG_MS: Option<u32> = Some(4000);
+
+// After a panic the watchdog resets the board after this many watchdog ticks (256 Hz).
+// None waits for a key on the dbgu and resets then, or just halts if PANIC_WAIT_FOR_KEY is false
+pub const PANIC_RESET_TICKS: Option<u16> = None;
+pub const PANIC_WAIT_FOR_KEY: bool = true;
 
 // Execution Modes (unfortunately actual Rust enums are pretty terrible)
 pub const USR_MODE: u32 = 0x10;
-// pub const FIQ_MODE: u32 = 0x11;
+pub const FIQ_MODE: u32 = 0x11;
 pub const IRQ_MODE: u32 = 0x12;
 pub const SVC_MODE: u32 = 0x13;
 pub const ABT_MODE: u32 = 0x17;
@@ -44,6 +134,7 @@ pub const MODE_RESET: u32 = SYS_MODE;
 pub fn show_mode(mode: u32) -> &'static str {
     match mode {
         USR_MODE => "User",
+        FIQ_MODE => "Fast Interrupt",
         IRQ_MODE => "Interrupt",
         SVC_MODE => "Supervisor",
         ABT_MODE => "Abort",
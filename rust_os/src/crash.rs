@@ -0,0 +1,114 @@
+//! Crash reports for exceptions and panics
+//!
+//! A report contains all registers, the decoded spsr and a backtrace.
+//! The backtrace follows the frame pointers (r11), so the kernel is built with
+//! `-Cforce-frame-pointers=yes`. Every frame saves the previous fp at [fp] and lr at [fp + 4].
+
+use crate::{consts::KERNEL_MEM, get_psr, print, println, show_mode, symbols, Registers};
+use core::{arch::asm, ptr::read_volatile};
+
+const MAX_FRAMES: usize = 16;
+const RAM_START: u32 = 0x2000_0000;
+
+/// Prints all registers, the spsr and a backtrace of the interrupted context.
+/// Must be called from an exception handler, so that the spsr belongs to regs
+pub fn report(regs: &Registers) {
+    get_psr!(psr = spsr);
+    print_registers(regs);
+    print_psr("spsr", psr);
+    backtrace(regs.pc, Some(regs.lr), regs.r11);
+}
+
+pub fn print_registers(regs: &Registers) {
+    let values = [
+        regs.r0, regs.r1, regs.r2, regs.r3, regs.r4, regs.r5, regs.r6, regs.r7, regs.r8, regs.r9,
+        regs.r10, regs.r11, regs.r12, regs.sp, regs.lr, regs.pc,
+    ];
+    const NAMES: [&str; 16] = [
+        "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "fp", "r12", "sp", "lr",
+        "pc",
+    ];
+    for (i, (name, value)) in NAMES.iter().zip(values).enumerate() {
+        print!("{name:>3}: {value:08x}");
+        print!("{}", if i % 4 == 3 { "\n" } else { "  " });
+    }
+}
+
+/// Prints a program status register with mode and flags
+pub fn print_psr(name: &str, psr: u32) {
+    let flag = |bit: u32, c: char| {
+        if psr & (1 << bit) != 0 {
+            c
+        } else {
+            c.to_ascii_lowercase()
+        }
+    };
+    println!(
+        "{name}: {psr:08x} ({} mode, flags {}{}{}{}, irq {}, fiq {}, {})",
+        show_mode(psr & crate::MODE_RESET),
+        flag(31, 'N'),
+        flag(30, 'Z'),
+        flag(29, 'C'),
+        flag(28, 'V'),
+        if psr & (1 << 7) != 0 { "masked" } else { "on" },
+        if psr & (1 << 6) != 0 { "masked" } else { "on" },
+        if psr & (1 << 5) != 0 { "thumb" } else { "arm" },
+    );
+}
+
+/// Prints an address with the name of the function it belongs to
+fn print_frame(index: usize, addr: u32) {
+    match symbols::lookup(addr) {
+        Some((name, offset)) => println!("  #{index:<2} {addr:08x} {name}+{offset:#x}"),
+        None => println!("  #{index:<2} {addr:08x} <unknown>"),
+    }
+}
+
+#[inline(always)]
+fn valid_frame(fp: u32) -> bool {
+    fp % 4 == 0 && fp >= RAM_START && fp < KERNEL_MEM as u32 - 4
+}
+
+/// Prints a backtrace starting at pc.
+/// lr is the return address of the innermost function if known, it might not have saved it yet
+pub fn backtrace(pc: u32, lr: Option<u32>, mut fp: u32) {
+    println!("Backtrace:");
+    print_frame(0, pc);
+    let mut index = 1;
+    if let Some(lr) = lr {
+        // lr points behind the call
+        print_frame(index, lr.wrapping_sub(4));
+        index += 1;
+    }
+    let mut first = true;
+    while valid_frame(fp) && index < MAX_FRAMES {
+        let prev_fp = unsafe { read_volatile(fp as *const u32) };
+        let ret = unsafe { read_volatile((fp + 4) as *const u32) };
+        // If the innermost function has already saved lr, we printed it already
+        if !(first && Some(ret) == lr) {
+            print_frame(index, ret.wrapping_sub(4));
+            index += 1;
+        }
+        first = false;
+        // The stack grows downwards, so the frames must go upwards
+        if prev_fp <= fp {
+            return;
+        }
+        fp = prev_fp;
+    }
+}
+
+/// Prints a backtrace of the caller
+#[inline(always)]
+pub fn backtrace_here() {
+    let (pc, fp): (u32, u32);
+    unsafe {
+        asm!(
+            "mov {pc}, pc",
+            "mov {fp}, r11",
+            pc = out(reg) pc,
+            fp = out(reg) fp,
+        )
+    }
+    backtrace(pc, None, fp);
+}
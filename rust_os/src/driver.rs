@@ -1,19 +1,87 @@
 //! Die Driver des kernels.
 //!
 //! Driver kümmern sich um die Steuerung und Abstraktion von externen und internen Geräten
+//! - emac: Der Ethernet-Controller, darüber liegt [crate::net]
 //! - exceptions: exception- und interrupt-handling
+//! - gpio: Die PIO-Controller für alle Pins
+//! - led: Die LEDs des Boards über gpio
+//! - mci: Das Multimedia Card Interface für SD- und MMC-Karten
 //! - memory_controller
+//! - pdc: Der DMA-Controller der Peripherien
 //! - power_management: Feine Kontrolle über den Stromverbrauch des Prozessors
 //! - serial: Die DBGU für println! und so
+//! - spi: Der SPI-Master für Flash und Sensoren
 //! - sys_timer: Unter anderem für den Timer-Interrupt zuständig
+//! - timer_counter: Die TCs für PWM, Messungen, µs-Delays und Profiling
+//! - twi: Der I2C-Master für EEPROMs und RTCs
 //! - mmu (Memory Management Unit): Teilweise Überschneidungen mit dem memory_controller
+//! - watchdog: Setzt das Board zurück, wenn der Kernel oder ein Supervisor hängt
+//! - registry: Hier werden alle Driver registriert. Sie verteilt auch die Interrupts
+//!
+//! Ein Gerät wird eingebunden, indem es [Driver] implementiert und in `start` registriert wird.
 
+pub mod emac;
 pub mod exceptions;
+pub mod gpio;
+pub mod led;
+pub mod mci;
 pub mod memory_controller;
 pub mod mmu;
+pub mod pdc;
 pub mod power_management;
+pub mod registry;
 pub mod serial;
+pub mod spi;
 pub mod sys_timer;
+pub mod timer_counter;
+pub mod twi;
+pub mod watchdog;
 
-// unused
-pub mod led;
+use crate::thread::ThreadList;
+use exceptions::SrcType;
+use gpio::Pin;
+
+/// Die Interrupt-Leitung eines Drivers
+#[derive(Clone, Copy, Debug)]
+pub struct Irq {
+    /// Die Quelle im AIC (0 - 31)
+    pub source: usize,
+    /// 0 - 7, 7 ist die höchste (siehe exceptions::PRIO_LOWEST)
+    pub prio: u32,
+    pub src_type: SrcType,
+}
+
+/// Common interface of all drivers so that the registry can manage them
+pub trait Driver {
+    /// A short name for the debug console
+    fn name(&self) -> &'static str;
+
+    /// Initializes the device. Called once on registration
+    fn init(&mut self);
+
+    /// The interrupt line of the device, if it uses interrupts.
+    /// Several drivers may share one line (e.g. all system peripherals are on source 1)
+    fn irq(&self) -> Option<Irq> {
+        None
+    }
+
+    /// Handles an interrupt on the drivers line.
+    /// On a shared line every driver is asked, so it must return whether its device was the cause
+    fn handle_irq(&mut self, _threads: &mut ThreadList) -> bool {
+        false
+    }
+
+    /// The peripheral id of the device in the PMC, if it has its own peripheral clock.
+    /// The clock is enabled before `init` and disabled after `shutdown`
+    fn peripheral_id(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether the device uses the pin. Nobody else may then change its function
+    fn owns(&self, _pin: Pin) -> bool {
+        false
+    }
+
+    /// Stops the device. Called when the driver is unregistered
+    fn shutdown(&mut self) {}
+}
@@ -0,0 +1,368 @@
+//! Der Ethernet MAC
+//!
+//! Empfangen wird über einen Ring aus Deskriptoren, die der EMAC selbst mit ganzen Frames füllt.
+//! Senden kennt keinen Ring: Der EMAC nimmt einen Frame und hält höchstens einen weiteren bereit,
+//! deshalb gibt es zwei Sendepuffer.
+//! Beim Start wird die Autonegotiation des PHY nur angestoßen. Nach Link, Geschwindigkeit und Duplex
+//! wird er über MDIO regelmäßig aus dem Sys-Timer-Interrupt gefragt (siehe net::tick),
+//! so kommen ihr Ergebnis und spätere Änderungen am Link an.
+//! Empfangene Frames gehen an [crate::net].
+
+use super::{
+    exceptions::{SrcType, PRIO_NET},
+    gpio::{Peripheral, Pin, Port},
+    sys_timer::{is_due, SysTimer},
+    Driver, Irq,
+};
+use crate::{
+    consts::{NET_MAC, NET_RMII},
+    info, net, println,
+    thread::ThreadList,
+    util::without_interrupts,
+    warn,
+};
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use volatile_register::{RO, RW, WO};
+
+pub struct Emac {
+    // p. 580
+    pub ctrl: RW<u32>,
+    pub config: RW<u32>,
+    pub status: RO<u32>,
+    pub tx_address: RW<u32>,
+    pub tx_control: RW<u32>,
+    pub tx_status: RW<u32>,
+    pub rx_queue: RW<u32>,
+    _reserved0: u32,
+    pub rx_status: RW<u32>,
+    pub int_status: RO<u32>,
+    pub int_enable: WO<u32>,
+    pub int_disable: WO<u32>,
+    _int_mask: RO<u32>,
+    pub management: RW<u32>,
+    _reserved1: [u32; 2],
+    /// Frame counters from 0x40 on, we don't read them
+    _statistics: [u32; 18],
+    _reserved2: [u32; 2],
+    _hash_high: RW<u32>,
+    _hash_low: RW<u32>,
+    pub address_low: RW<u32>,
+    pub address_high: RW<u32>,
+}
+
+const EMAC_ADDR: u32 = 0xFFFB_C000;
+const EMAC_ID: usize = 24;
+// Control
+const RE: u32 = 1 << 2;
+const TE: u32 = 1 << 3;
+const MPE: u32 = 1 << 4;
+// Config
+const SPD: u32 = 1 << 0;
+const FD: u32 = 1 << 1;
+const BIG: u32 = 1 << 8;
+/// MDC = MCK / 32
+const CLK_32: u32 = 2 << 10;
+const RMII: u32 = 1 << 13;
+// Status
+const IDLE: u32 = 1 << 2;
+// Transmit Status
+const BNQ: u32 = 1 << 4;
+// Interrupts
+const RCOM: u32 = 1 << 1;
+const RBNA: u32 = 1 << 2;
+const TUND: u32 = 1 << 4;
+const RTRY: u32 = 1 << 5;
+const ROVR: u32 = 1 << 10;
+const ABT: u32 = 1 << 11;
+// Management (MDIO) frames
+const MAN_HIGH: u32 = 1 << 30;
+const MAN_CODE: u32 = 2 << 16;
+const MAN_READ: u32 = 2 << 28;
+const MAN_WRITE: u32 = 1 << 28;
+// PHY registers
+const PHY_BMCR: u32 = 0;
+const PHY_BMSR: u32 = 1;
+const PHY_ID1: u32 = 2;
+const PHY_ANAR: u32 = 4;
+const PHY_ANLPAR: u32 = 5;
+const BMSR_LINK: u32 = 1 << 2;
+const BMSR_AUTONEG_COMPLETE: u32 = 1 << 5;
+const BMCR_AUTONEG_ENABLE: u16 = 1 << 12;
+const BMCR_AUTONEG_RESTART: u16 = 1 << 9;
+/// How often the link is checked
+const LINK_POLL_MS: u32 = 1000;
+
+/// The pins of the RMII (and MDIO), PA7 - PA16 on peripheral A
+const PINS: [u8; 10] = [7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+/// What the MII needs in addition: ETX2, ETX3, ETXER, ERX2, ERX3, ERXDV, ECOL and ERXCK,
+/// PB12 - PB19 on peripheral B
+const MII_PINS: [u8; 8] = [12, 13, 14, 15, 16, 17, 18, 19];
+
+const RX_BUFFERS: usize = 32;
+/// Big enough for a whole frame with a VLAN tag
+pub const FRAME_SIZE: usize = 1536;
+/// Descriptor word 0: the buffer belongs to software (a frame is in it)
+const OWNERSHIP: u32 = 1 << 0;
+/// Descriptor word 0: the last descriptor
+const WRAP: u32 = 1 << 1;
+/// Descriptor word 1: the frame length
+const LENGTH_MASK: u32 = 0x7FF;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    address: u32,
+    status: u32,
+}
+
+#[repr(C, align(8))]
+struct RxRing {
+    descriptors: [Descriptor; RX_BUFFERS],
+    buffers: [[u8; FRAME_SIZE]; RX_BUFFERS],
+}
+
+static mut RX_RING: RxRing = RxRing {
+    descriptors: [Descriptor {
+        address: 0,
+        status: 0,
+    }; RX_BUFFERS],
+    buffers: [[0; FRAME_SIZE]; RX_BUFFERS],
+};
+/// The next descriptor we look at
+static mut RX_NEXT: usize = 0;
+
+#[repr(C, align(4))]
+struct TxBuffers([[u8; FRAME_SIZE]; 2]);
+
+static mut TX_BUFFERS: TxBuffers = TxBuffers([[0; FRAME_SIZE]; 2]);
+static mut TX_NEXT: usize = 0;
+/// The address of the PHY on the MDIO bus
+static mut PHY: Option<u32> = None;
+/// The last seen link: up, and SPD and FD as in the config
+static mut LINK: Option<(bool, u32)> = None;
+static mut NEXT_LINK_CHECK: u32 = 0;
+
+impl Emac {
+    #[inline(always)]
+    pub fn new() -> &'static mut Emac {
+        unsafe { &mut *(EMAC_ADDR as *mut Emac) }
+    }
+
+    /// Reads a PHY register over MDIO
+    fn phy_read(&mut self, phy: u32, register: u32) -> u16 {
+        unsafe {
+            self.management
+                .write(MAN_HIGH | MAN_CODE | MAN_READ | (phy << 23) | (register << 18))
+        };
+        while self.status.read() & IDLE == 0 {}
+        self.management.read() as u16
+    }
+
+    fn phy_write(&mut self, phy: u32, register: u32, value: u16) {
+        unsafe {
+            self.management.write(
+                MAN_HIGH | MAN_CODE | MAN_WRITE | (phy << 23) | (register << 18) | value as u32,
+            )
+        };
+        while self.status.read() & IDLE == 0 {}
+    }
+
+    /// The first address on the MDIO bus that answers
+    fn find_phy(&mut self) -> Option<u32> {
+        (0..32).find(|&phy| !matches!(self.phy_read(phy, PHY_ID1), 0 | 0xFFFF))
+    }
+
+    /// The status register of the PHY. The link bit latches a link loss, so it is read twice
+    fn phy_status(&mut self, phy: u32) -> u32 {
+        self.phy_read(phy, PHY_BMSR);
+        self.phy_read(phy, PHY_BMSR) as u32
+    }
+
+    /// Restarts the autonegotiation without waiting for it.
+    /// The link check applies speed and duplex once it is complete
+    fn autonegotiate(&mut self, phy: u32) {
+        let bmcr = self.phy_read(phy, PHY_BMCR);
+        self.phy_write(
+            phy,
+            PHY_BMCR,
+            bmcr | BMCR_AUTONEG_ENABLE | BMCR_AUTONEG_RESTART,
+        );
+    }
+
+    /// Reads the link from the PHY. If it changed, takes speed and duplex from the autonegotiation result
+    fn update_link(&mut self) {
+        let Some(phy) = (unsafe { PHY }) else { return };
+        let status = self.phy_status(phy);
+        let up = status & BMSR_LINK != 0 && status & BMSR_AUTONEG_COMPLETE != 0;
+        let mode = if up {
+            let common = self.phy_read(phy, PHY_ANAR) & self.phy_read(phy, PHY_ANLPAR);
+            match common {
+                c if c & (1 << 8) != 0 => SPD | FD,
+                c if c & (1 << 7) != 0 => SPD,
+                c if c & (1 << 6) != 0 => FD,
+                _ => 0,
+            }
+        } else {
+            0
+        };
+        if unsafe { LINK } == Some((up, mode)) {
+            return;
+        }
+        unsafe { LINK = Some((up, mode)) };
+        if !up {
+            info!("Ethernet link down");
+            return;
+        }
+        unsafe { self.config.modify(|config| (config & !(SPD | FD)) | mode) };
+        info!(
+            "Ethernet link up, {} Mbit/s, {} duplex",
+            if mode & SPD != 0 { 100 } else { 10 },
+            if mode & FD != 0 { "full" } else { "half" }
+        );
+    }
+
+    /// Sends the frame. Returns false if the EMAC has no room for it right now
+    pub fn send(&mut self, frame: &[u8]) -> bool {
+        without_interrupts(|| {
+            if self.tx_status.read() & BNQ == 0 || frame.len() > FRAME_SIZE {
+                return false;
+            }
+            unsafe {
+                let buffer = &mut TX_BUFFERS.0[TX_NEXT];
+                TX_NEXT = (TX_NEXT + 1) % 2;
+                buffer[..frame.len()].copy_from_slice(frame);
+                self.tx_address.write(buffer.as_ptr() as u32);
+                // Frames shorter than 60 bytes are padded by the EMAC
+                self.tx_control.write(frame.len() as u32);
+            }
+            true
+        })
+    }
+
+    /// Hands all received frames to the network stack
+    fn receive_all(&mut self) {
+        unsafe {
+            loop {
+                let descriptor = addr_of_mut!(RX_RING.descriptors[RX_NEXT]);
+                let address = read_volatile(addr_of!((*descriptor).address));
+                if address & OWNERSHIP == 0 {
+                    return;
+                }
+                let len = (read_volatile(addr_of!((*descriptor).status)) & LENGTH_MASK) as usize;
+                net::receive(&RX_RING.buffers[RX_NEXT][..len.min(FRAME_SIZE)]);
+                // Give the buffer back
+                write_volatile(addr_of_mut!((*descriptor).address), address & !OWNERSHIP);
+                RX_NEXT = (RX_NEXT + 1) % RX_BUFFERS;
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "emac: ctrl {:08x}, config {:08x}, tx status {:08x}, rx status {:08x}",
+            self.ctrl.read(),
+            self.config.read(),
+            self.tx_status.read(),
+            self.rx_status.read()
+        );
+    }
+}
+
+/// Checks the link regularly. Called by net::tick
+pub fn tick(now: u32) {
+    if unsafe { (*addr_of!(PHY)).is_some() && is_due(NEXT_LINK_CHECK, now) } {
+        Emac::new().update_link();
+        unsafe { NEXT_LINK_CHECK = SysTimer::new().deadline_in(LINK_POLL_MS) };
+    }
+}
+
+/// When the link has to be checked next, for the tickless mode
+pub fn next_link_check() -> Option<u32> {
+    unsafe { PHY.map(|_| NEXT_LINK_CHECK) }
+}
+
+impl Driver for Emac {
+    fn name(&self) -> &'static str {
+        "emac"
+    }
+
+    fn init(&mut self) {
+        for bit in PINS {
+            Pin::new(Port::A, bit).peripheral(Peripheral::A);
+        }
+        if !NET_RMII {
+            for bit in MII_PINS {
+                Pin::new(Port::B, bit).peripheral(Peripheral::B);
+            }
+        }
+        let rmii = if NET_RMII { RMII } else { 0 };
+        unsafe {
+            self.ctrl.write(0);
+            self.config.write(CLK_32 | BIG | rmii);
+            self.address_low.write(u32::from_le_bytes([
+                NET_MAC[0], NET_MAC[1], NET_MAC[2], NET_MAC[3],
+            ]));
+            self.address_high
+                .write(u16::from_le_bytes([NET_MAC[4], NET_MAC[5]]) as u32);
+            for (i, descriptor) in (*addr_of_mut!(RX_RING)).descriptors.iter_mut().enumerate() {
+                descriptor.address = RX_RING.buffers[i].as_ptr() as u32;
+                descriptor.status = 0;
+            }
+            RX_RING.descriptors[RX_BUFFERS - 1].address |= WRAP;
+            RX_NEXT = 0;
+            self.rx_queue.write(addr_of!(RX_RING.descriptors) as u32);
+            self.ctrl.write(MPE);
+        }
+        unsafe { LINK = None };
+        match self.find_phy() {
+            Some(phy) => {
+                unsafe { PHY = Some(phy) };
+                self.autonegotiate(phy);
+                unsafe { NEXT_LINK_CHECK = SysTimer::new().deadline_in(LINK_POLL_MS) };
+            }
+            None => warn!("No ethernet PHY found"),
+        }
+        unsafe {
+            self.ctrl.write(MPE | RE | TE);
+            self.int_status.read();
+            self.int_enable
+                .write(RCOM | RBNA | TUND | RTRY | ROVR | ABT);
+        }
+    }
+
+    fn irq(&self) -> Option<Irq> {
+        Some(Irq {
+            source: EMAC_ID,
+            prio: PRIO_NET,
+            src_type: SrcType::HighLevelSens,
+        })
+    }
+
+    fn handle_irq(&mut self, _threads: &mut ThreadList) -> bool {
+        // Reading clears it
+        let status = self.int_status.read();
+        if status & (RBNA | ROVR) != 0 {
+            warn!("emac: receive buffers full, frames were lost");
+            unsafe { self.rx_status.write(u32::MAX) };
+        }
+        if status & (TUND | RTRY | ABT) != 0 {
+            warn!("emac: transmit error {status:x}");
+            unsafe { self.tx_status.write(u32::MAX) };
+        }
+        if status & (RCOM | RBNA) != 0 {
+            self.receive_all();
+        }
+        status != 0
+    }
+
+    fn peripheral_id(&self) -> Option<usize> {
+        Some(EMAC_ID)
+    }
+
+    fn shutdown(&mut self) {
+        unsafe {
+            self.int_disable.write(u32::MAX);
+            self.ctrl.write(0);
+        }
+    }
+}
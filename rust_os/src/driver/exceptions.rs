@@ -1,17 +1,27 @@
 //! Diese Datei beschreibt die exception handler und deren Initialisierung auf der Hardware
 
 use crate::{
-    get_psr,
-    memory_controller::get_abort_adress,
-    println, serial,
-    serial::Serial,
+    clock::{self, TimeVal},
+    consts::SYS_STACK_TOP,
+    crash, error, fs, gdb, get_psr, gpio, log,
+    memory_controller::{get_abort_adress, get_abort_status},
+    net::socket::{self, NetCall},
+    power_management::PMC,
+    print, println,
+    serial::{self, Serial, TTY_NUMBER},
+    set_psr, spi,
     sys_timer::SysTimer,
     thread::{get_threads, State::*, ThreadList},
-    trampoline,
-    util::{demask_interrupts, mask_interrupts},
-    Registers, USR_MODE,
+    timer_counter, trampoline, twi,
+    util::{demask_fast_interrupts, demask_interrupts, mask_interrupts},
+    warn, watchdog, Registers, MODE_RESET, SYS_MODE, USR_MODE,
+};
+use core::{
+    arch::asm,
+    mem,
+    ptr::{addr_of, addr_of_mut, read, read_volatile, write, write_volatile},
+    slice,
 };
-use core::{arch::asm, mem, ptr::read};
 use volatile_register::{RO, RW, WO};
 
 const IVT_ADDR: u32 = 0;
@@ -29,7 +39,7 @@ pub struct IVT {
     fiq: WO<u32>,
     pub undef_handler: WO<extern "aapcs" fn()>,
     pub swi_handler: WO<extern "aapcs" fn()>,
-    prefetch_handler: WO<extern "aapcs" fn()>,
+    pub prefetch_handler: WO<extern "aapcs" fn()>,
     pub data_abort_handler: WO<extern "aapcs" fn()>,
 }
 
@@ -45,16 +55,19 @@ impl IVT {
             // AT91_interrupts.pdf p.2
             // https://armconverter.com/?code=ldr%20pc,%5Bpc,%23-0xF20%5D
             self.irq.write(0xE51FFF20);
+            // Dasselbe für FIQ, das liest dann das FVR (0xFFFFF104)
+            self.fiq.write(0xE51FFF20);
             // Hier müssen wir in die Register den assembly code reinschreiben,
             // der in den handler springt, der in den handler-Registern steht
             // https://armconverter.com/?code=ldr%20pc,%20%5Bpc,%20%230x14%5D
             const ASM_AS_BYTES: u32 = 0xE59FF014;
             self.undef.write(ASM_AS_BYTES);
             self.swi.write(ASM_AS_BYTES);
-            // self.prefetch.write(asm_as_bytes); Not needed
+            self.prefetch.write(ASM_AS_BYTES);
             self.data_abort.write(ASM_AS_BYTES);
         }
         unsafe {
+            self.prefetch_handler.write(_pab_handler);
             self.data_abort_handler.write(_dab_handler);
             self.undef_handler.write(_und_handler);
             self.swi_handler.write(_swi_handler);
@@ -63,7 +76,36 @@ impl IVT {
     }
 }
 
+impl IVT {
+    /// Prints the vectors and the handlers they jump to.
+    /// The registers are write-only for us, so we read the memory directly
+    pub fn print(&self) {
+        const NAMES: [&str; 8] = [
+            "reset",
+            "undef",
+            "swi",
+            "prefetch",
+            "data abort",
+            "reserved",
+            "irq",
+            "fiq",
+        ];
+        let base = self as *const Self as *const u32;
+        for (i, name) in NAMES.iter().enumerate() {
+            let vector = unsafe { read_volatile(base.add(i)) };
+            print!("{name:>10}: {vector:08x}");
+            // ldr pc, [pc, #0x14] jumps to the handler 8 words later
+            if vector == 0xE59FF014 {
+                let handler = unsafe { read_volatile(base.add(i + 8)) };
+                print!(" -> {handler:08x}");
+            }
+            println!();
+        }
+    }
+}
+
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SrcType {
     LowLevelSens,
     NegativeEdgeTriggered,
@@ -71,18 +113,57 @@ pub enum SrcType {
     PositiveEdgeTriggered,
 }
 
+/// Ein Handler für eine Interrupt-Quelle.
+/// Er läuft im System-Modus mit aktivierten Interrupts,
+/// kann also von Quellen mit höherer Priorität unterbrochen werden
+/// Als zweites Argument bekommt er die Nummer der Quelle
+pub type IrqHandler = extern "aapcs" fn(&mut ThreadList, usize);
+
+static mut IRQ_HANDLERS: [Option<IrqHandler>; 32] = [None; 32];
+
+// The priorities of the sources (0 - 7, 7 is the highest). A handler is only preempted by sources
+// with a higher priority (see run_nested). The system line is the lowest: The sys timer schedules and
+// runs the ticks, that can wait. The DBGU shares the line, so it gets the same priority.
+// Above it come the devices that lose data if they have to wait.
+pub const PRIO_LOWEST: u32 = 0;
+/// Pin changes and SPI transfers
+pub const PRIO_DEVICE: u32 = 2;
+/// Received frames, the EMAC only has a few receive buffers
+pub const PRIO_NET: u32 = 4;
+/// The USARTs, their receive register only holds one char
+pub const PRIO_SERIAL: u32 = 6;
+
+/// Der Handler für die schnellen Interrupts (FIQ).
+/// Er läuft im FIQ-Modus mit maskierten Interrupts und muss das Gerät selbst quittieren.
+/// Er sollte kurz sein und keine Threads anfassen
+pub type FiqHandler = extern "aapcs" fn();
+
+static mut FIQ_HANDLER: Option<FiqHandler> = None;
+
+/// FIQs without a handler. Only the FIQ handler writes it and nothing interrupts that.
+/// The ARMv4T has no atomics, but a word is read in one go, so the reporter keeps its own
+/// count instead of resetting this one
+static mut UNHANDLED_FIQS: u32 = 0;
+static mut REPORTED_FIQS: u32 = 0;
+
+/// The AIC source that is always routed to FIQ
+pub const FIQ_SOURCE: usize = 0;
+
 pub struct AIC {
     // p. 251
     pub src_modes: [RW<u32>; 32],
     pub src_vctrs: [RW<u32>; 32],
     pub ivr: RO<u32>,
     pub fvr: RO<u32>,
-    _isr: u32,
+    /// The source number of the interrupt that is currently handled
+    pub isr: RO<u32>,
     _ipr: u32,
-    _imr: u32,
+    /// The enabled sources
+    pub imr: RO<u32>,
     _unused0: [u32; 3],
     pub enable: WO<u32>,
-    _unused1: [u32; 3],
+    pub disable: WO<u32>,
+    _unused1: [u32; 2],
     pub eoicr: WO<u32>,
 }
 
@@ -92,47 +173,125 @@ impl AIC {
         unsafe { &mut *(AIC_ADDR as *mut AIC) }
     }
 
+    /// Startet mit allen Quellen deaktiviert. Die Driver-Registry aktiviert dann die benötigten
     #[inline(always)]
     pub fn init(&mut self) {
-        self.set_handler(1, _src1_handler as u32, 0, SrcType::LowLevelSens);
+        unsafe { self.disable.write(u32::MAX) }
     }
 
+    /// Interrupts stay masked in the cpu during boot, the first thread unmasks them (see ThreadList::start)
     #[inline(always)]
     pub fn enable_interrupt(&mut self, index: u8) {
         unsafe {
             self.enable.write(1 << index);
         }
-        crate::util::demask_interrupts();
+    }
+
+    /// Deaktiviert die Quelle und entfernt ihren Handler
+    #[inline(always)]
+    pub fn disable_source(&mut self, index: usize) {
+        unsafe {
+            self.disable.write(1 << index);
+            IRQ_HANDLERS[index] = None;
+        }
     }
 
     /// Setzt den handler an [index] mit Priorität [prio] und Source Typ [src_type]
     /// index muss zwischen 0 und 31 sein.
-    /// prio muss zwischen 0 und 7 sein
+    /// prio muss zwischen 0 und 7 sein. Ein Handler kann nur von Quellen mit höherer Priorität unterbrochen werden
     #[inline(always)]
     pub fn set_handler(
         &mut self,
         index: usize,
-        handler: u32,
+        handler: IrqHandler,
         prio: u32,
         src_type: SrcType,
     ) -> &mut Self {
         unsafe {
+            IRQ_HANDLERS[index] = Some(handler);
             self.src_modes[index].write(prio | ((src_type as u32) << 5));
-            self.src_vctrs[index].write(handler);
+            // Alle Quellen landen im selben Trampolin, welches dann den richtigen Handler aufruft
+            self.src_vctrs[index].write(_irq_handler as usize as u32);
         }
         self.enable_interrupt(index as u8);
         self
     }
 
-    /// Must be called after interrupt completion
+    /// Routes source 0 to the FIQ and installs the given handler.
+    /// FIQs don't go through the priority controller and need no end_of_interrupt
+    pub fn route_fiq(&mut self, handler: FiqHandler, src_type: SrcType) -> &mut Self {
+        unsafe {
+            FIQ_HANDLER = Some(handler);
+            self.src_modes[FIQ_SOURCE].write((src_type as u32) << 5);
+            self.src_vctrs[FIQ_SOURCE].write(_fiq_handler as usize as u32);
+            self.enable.write(1 << FIQ_SOURCE);
+        }
+        demask_fast_interrupts();
+        self
+    }
+
+    /// Prints all enabled sources with their mode and handler
+    pub fn print(&self) {
+        let enabled = self.imr.read();
+        for source in (0..32).filter(|s| enabled & (1 << s) != 0) {
+            let mode = self.src_modes[source].read();
+            println!(
+                "source {source:>2}: prio {}, type {}, vector {:08x}, handler {:08x}",
+                mode & 0b111,
+                (mode >> 5) & 0b11,
+                self.src_vctrs[source].read(),
+                unsafe { IRQ_HANDLERS[source] }.map_or(0, |h| h as usize as u32)
+            );
+        }
+    }
+
+    /// Must be called after interrupt completion.
+    /// Interrupts must stay masked until the handler returns, the return restores the cpsr
     #[inline(always)]
     pub fn end_of_interrupt(&mut self) {
         unsafe { self.eoicr.write(1) }
-        crate::util::demask_interrupts();
     }
 }
 
-trampoline! {_src1_handler=>src1_handler@4}
+trampoline! {_irq_handler=>irq_handler@4}
+
+/// Der FIQ-Einstieg. r8-r14 sind im FIQ-Modus gebankt,
+/// also sichern wir nur, was der Rust-Handler nach aapcs verändern darf
+#[naked]
+extern "aapcs" fn _fiq_handler() {
+    unsafe {
+        asm!(
+            "sub lr, #4",
+            "stmfd sp!, {{r0-r3, r12, lr}}",
+            "bl {handler}",
+            "ldmfd sp!, {{r0-r3, r12, pc}}^",
+            handler = sym fiq_handler,
+            options(noreturn)
+        )
+    }
+}
+
+extern "aapcs" fn fiq_handler() {
+    match unsafe { FIQ_HANDLER } {
+        Some(handler) => handler(),
+        // The log masks only IRQs, so it can't be used here
+        None => unsafe {
+            let count = read_volatile(addr_of!(UNHANDLED_FIQS));
+            write_volatile(addr_of_mut!(UNHANDLED_FIQS), count.wrapping_add(1));
+        },
+    }
+}
+
+/// Logs the FIQs without a handler since the last call. Called by the idle thread
+pub fn report_fiqs() {
+    let unhandled = unsafe { read_volatile(addr_of!(UNHANDLED_FIQS)) };
+    let reported = unsafe { REPORTED_FIQS };
+    if unhandled != reported {
+        warn!("{} FIQs without handler", unhandled.wrapping_sub(reported));
+        unsafe { REPORTED_FIQS = unhandled };
+    }
+}
+trampoline! {_pab_handler=>pab_handler@4}
 trampoline! {_dab_handler=>dab_handler@8}
 trampoline! {_und_handler=>und_handler@4}
 trampoline! {_swi_handler=>swi_handler@0}
@@ -143,59 +302,71 @@ fn end_handler(regs: &mut Registers) {
     demask_interrupts();
 }
 
-fn timer_handler(threads: &mut ThreadList) {
-    for thread in threads.array.iter_mut().filter_map(|x| x.as_mut()) {
-        match thread.state {
-            Sleeping(ms) => {
-                // if we overflow (we go below zero), we set to Ready otherwise we remain Sleeping
-                thread.state = match ms.checked_sub(ms) {
-                    Some(new_ms) => Sleeping(new_ms),
-                    None => Ready,
-                }
-            }
-            _ => (),
-        }
-    }
-    println!("!");
-}
+/// How many interrupt handlers are running. More than one means they are nested
+static mut IRQ_DEPTH: u32 = 0;
 
-fn dbgu_handler(threads: &mut ThreadList) {
-    let dbgu = Serial::new();
-    let char = dbgu.read() as u32;
-    if char == 4 {
-        // ctrl+d is debug print
-        println!("{threads:#?}");
-        return;
-    }
-    for thread in threads.iter_mut() {
-        match thread.state {
-            WaitingForChar => {
-                thread.regs.r0 = char;
-                thread.state = Ready;
-            }
-            _ => (),
-        }
+/// Runs the handler in sys mode with interrupts enabled, so that sources with a higher priority can preempt it.
+/// A nested handler keeps using the sys stack below the handler it interrupted,
+/// otherwise the sys stack starts at its top
+#[inline(always)]
+fn run_nested(handler: IrqHandler, threads: &mut ThreadList, source: usize, nested: bool) {
+    unsafe {
+        IRQ_DEPTH += 1;
+        asm!(
+            "mrs r4, cpsr",
+            "orr r5, r4, #{SYS}",
+            "bic r5, #(1<<7)",
+            "msr cpsr_c, r5",
+            // r3 is 0 if the sys stack is already in use
+            "cmp r3, #0",
+            "movne sp, r3",
+            "mov lr, pc",
+            "bx r2",
+            // Back into irq mode with interrupts masked
+            "msr cpsr_c, r4",
+            SYS = const SYS_MODE,
+            in("r0") threads,
+            in("r1") source,
+            in("r2") handler,
+            in("r3") if nested { 0 } else { SYS_STACK_TOP },
+            out("r4") _,
+            out("r5") _,
+            clobber_abi("aapcs"),
+        );
+        IRQ_DEPTH -= 1;
     }
 }
 
-extern "aapcs" fn src1_handler(regs: &mut Registers) {
+/// The common handler of all interrupt sources.
+/// Switching threads is only allowed if we interrupted a thread and not another handler.
+/// Interrupts are masked until the first thread runs, so outside of a handler there is always a thread
+extern "aapcs" fn irq_handler(regs: &mut Registers) {
+    let aic = AIC::new();
+    let source = (aic.isr.read() & 0x1f) as usize;
+    get_psr!(psr = spsr);
+    let nested = unsafe { IRQ_DEPTH } > 0;
+    let switch = !nested;
     let threads = get_threads();
-    threads.save_state(regs);
-    println!("Interrupt");
-    if SysTimer::new().status.read() == 1 {
-        timer_handler(threads);
-    } else if Serial::new().status.read() & serial::RXRDY != 0 {
-        dbgu_handler(threads);
+    if switch {
+        threads.save_state(regs);
+    }
+    match unsafe { IRQ_HANDLERS[source] } {
+        Some(handler) => run_nested(handler, threads, source, nested),
+        None => warn!("unknown interrupt source {source}"),
+    }
+    if switch {
+        threads.schedule_next();
+        threads.put_state(regs);
     } else {
-        println!("unknown interrupt")
+        // A nested interrupt has overwritten our spsr
+        set_psr!(spsr = psr);
     }
-    threads.schedule_next();
-    threads.put_state(regs);
-    AIC::new().end_of_interrupt();
+    aic.end_of_interrupt();
 }
 
 /// A function that is called when someone messed up
-/// It ends the current user thread
+/// It ends the current user thread and schedules the next one,
+/// since the thread before the ended one may not be ready
 #[inline(always)]
 fn exception_fault() {
     get_psr!(psr = spsr);
@@ -208,6 +379,7 @@ fn exception_fault() {
             threads.get_thread(id)
         );
         threads.end_thread(id);
+        threads.schedule_next();
     } else {
         panic!("Exception Fault while in mode {:?}", crate::show_mode(mode))
     }
@@ -216,22 +388,52 @@ fn exception_fault() {
 extern "aapcs" fn dab_handler(regs: &mut Registers) {
     mask_interrupts();
     println!(
-        "Data Abort at {:x} accessing {:x}",
+        "Data Abort at {:x} accessing {:x}: {}",
         regs.pc,
-        get_abort_adress()
+        get_abort_adress(),
+        get_abort_status()
     );
+    crash::report(regs);
+    exception_fault();
+    end_handler(regs);
+}
+
+extern "aapcs" fn pab_handler(regs: &mut Registers) {
+    mask_interrupts();
+    get_psr!(psr = spsr);
+    println!(
+        "Prefetch Abort at {:x} in {} mode (thread {})",
+        regs.pc,
+        crate::show_mode(psr & MODE_RESET),
+        get_threads().curr_thread
+    );
+    crash::report(regs);
     exception_fault();
     end_handler(regs);
 }
 
 extern "aapcs" fn und_handler(regs: &mut Registers) {
     mask_interrupts();
+    get_psr!(psr = spsr);
+    if psr & MODE_RESET == USR_MODE && unsafe { read(regs.pc as *const u32) } == gdb::BREAKPOINT {
+        // Maybe one of gdb's breakpoints
+        let threads = get_threads();
+        if !threads.save_state(regs) {
+            // The thread overflowed its stack and was ended, the one before it may not be ready
+            threads.schedule_next();
+            return end_handler(regs);
+        }
+        if gdb::on_breakpoint(threads) {
+            return end_handler(regs);
+        }
+    }
     println!("Undefined Instruction at {:x}", regs.pc);
+    crash::report(regs);
     exception_fault();
     end_handler(regs);
 }
 
-const SWI_CODE_NUM: usize = 5;
+const SWI_CODE_NUM: usize = 21;
 
 #[derive(Debug)]
 pub enum SWICode {
@@ -240,6 +442,22 @@ pub enum SWICode {
     Sleep,
     PutChar,
     ReadChar,
+    Dmesg,
+    Suspend,
+    Gpio,
+    Watchdog,
+    TtyRead,
+    TtyWrite,
+    Write,
+    Pwm,
+    Spi,
+    Twi,
+    Net,
+    File,
+    SetTime,
+    GetTimeOfDay,
+    Capture,
+    Receive,
 }
 
 impl From<u8> for SWICode {
@@ -251,13 +469,18 @@ impl From<u8> for SWICode {
 extern "aapcs" fn swi_handler(regs: &mut Registers) {
     mask_interrupts();
     let threads = get_threads();
-    threads.save_state(regs);
+    if !threads.save_state(regs) {
+        // The thread overflowed its stack and was ended, so the call is dropped
+        threads.schedule_next();
+        return end_handler(regs);
+    }
     // ARM Documentation advises us to read the swi code from the instruction (8 bit imm)
     let _code = unsafe { read((regs.pc - 4) as *const u8) };
     if _code >= SWI_CODE_NUM as u8 {
         return end_handler(regs);
     }
     let code = SWICode::from(_code);
+    let caller = threads.curr_thread;
     use SWICode::*;
     match code {
         Exit => _ = threads.end_thread(threads.curr_thread),
@@ -268,15 +491,164 @@ extern "aapcs" fn swi_handler(regs: &mut Registers) {
             regs.r0 = {
                 let regs = unsafe { read(regs.r0 as *const Registers) };
                 get_threads().create_thread(regs).unwrap_or_else(|err| {
-                    println!("Error in Fork handler: {err}");
+                    error!("Error in Fork handler: {err}");
                     0
                 }) as u32
             }
         }
-        Sleep => get_threads().curr_mut_thread().state = Sleeping(regs.r0),
+        Sleep => {
+            get_threads().curr_mut_thread().state = Sleeping(SysTimer::new().deadline_in(regs.r0))
+        }
         PutChar => Serial::new().write(regs.r0 as u8),
-        ReadChar => get_threads().curr_mut_thread().state = WaitingForChar,
+        ReadChar => read_tty(threads, regs, 0),
+        Dmesg => {
+            let buf = unsafe { slice::from_raw_parts_mut(regs.r0 as *mut u8, regs.r1 as usize) };
+            regs.r0 = log::read_into(buf) as u32;
+        }
+        // Interrupts are masked here, so the waking interrupt is taken after the return
+        Suspend => PMC::new().suspend(),
+        Gpio => {
+            match gpio::user_op(threads, regs.r0, regs.r1, regs.r2) {
+                Ok(Some(level)) => regs.r0 = level as u32,
+                // The pin interrupt answers
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("Error in Gpio handler: {err}");
+                    regs.r0 = u32::MAX;
+                }
+            }
+        }
+        TtyRead => read_tty(threads, regs, regs.r0 as usize),
+        TtyWrite => match Serial::tty(regs.r0 as usize) {
+            Some(serial) => serial.write(regs.r1 as u8),
+            None => warn!("Error in TtyWrite handler: Unknown tty {}", regs.r0),
+        },
+        Write => {
+            let buf = unsafe { slice::from_raw_parts(regs.r1 as *const u8, regs.r2 as usize) };
+            regs.r0 = match Serial::tty(regs.r0 as usize) {
+                Some(serial) => serial.write_buffered(buf) as u32,
+                None => {
+                    warn!("Error in Write handler: Unknown tty {}", regs.r0);
+                    u32::MAX
+                }
+            }
+        }
+        Pwm => {
+            regs.r0 = match timer_counter::user_pwm(regs.r0, regs.r1, regs.r2) {
+                Ok(()) => 1,
+                Err(err) => {
+                    warn!("Error in Pwm handler: {err}");
+                    0
+                }
+            }
+        }
+        Spi => {
+            let buf = unsafe { slice::from_raw_parts_mut(regs.r2 as *mut u8, regs.r3 as usize) };
+            match spi::user_transfer(threads, regs.r0, regs.r1, buf) {
+                Ok(Some(len)) => regs.r0 = len as u32,
+                // The interrupt answers when the transfer is done
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("Error in Spi handler: {err}");
+                    regs.r0 = u32::MAX;
+                }
+            }
+        }
+        Twi => {
+            let buf = unsafe { slice::from_raw_parts_mut(regs.r2 as *mut u8, regs.r3 as usize) };
+            regs.r0 = match twi::user_transfer(regs.r0, regs.r1, buf) {
+                Ok(()) => 1,
+                Err(err) => {
+                    warn!("Error in Twi handler: {err}");
+                    0
+                }
+            }
+        }
+        Net => {
+            let call = unsafe { &mut *(regs.r0 as *mut NetCall) };
+            regs.r0 = match socket::user_op(call, threads.curr_thread) {
+                Ok(value) => value,
+                Err(err) => {
+                    warn!("Error in Net handler: {err}");
+                    u32::MAX
+                }
+            }
+        }
+        File => {
+            let call = unsafe { read(regs.r0 as *const fs::FileCall) };
+            regs.r0 = match fs::user_op(&call, threads.curr_thread) {
+                Ok(value) => value,
+                Err(err) => {
+                    warn!("Error in File handler: {err}");
+                    u32::MAX
+                }
+            }
+        }
+        SetTime => {
+            let time = TimeVal {
+                secs: regs.r0,
+                usecs: regs.r1,
+            };
+            regs.r0 = match clock::set(time) {
+                Ok(()) => 1,
+                Err(err) => {
+                    warn!("Error in SetTime handler: {err}");
+                    0
+                }
+            }
+        }
+        GetTimeOfDay => {
+            regs.r0 = match clock::now() {
+                Some(time) => {
+                    unsafe { write(regs.r0 as *mut TimeVal, time) };
+                    1
+                }
+                None => 0,
+            }
+        }
+        Capture => {
+            // The interrupt answers when two edges were measured or there was no signal
+            if let Err(err) = timer_counter::user_capture(threads, regs.r0) {
+                warn!("Error in Capture handler: {err}");
+                regs.r0 = 0;
+            }
+        }
+        Receive => {
+            let buf = unsafe { slice::from_raw_parts_mut(regs.r1 as *mut u8, regs.r2 as usize) };
+            // The ENDRX interrupt answers when the buffer is full
+            if let Err(err) = serial::user_receive(threads, regs.r0 as usize, buf) {
+                warn!("Error in Receive handler: {err}");
+                regs.r0 = u32::MAX;
+            }
+        }
+        Watchdog => {
+            regs.r0 = match watchdog::user_op(regs.r0, threads.curr_thread) {
+                Ok(()) => 1,
+                Err(err) => {
+                    warn!("Error in Watchdog handler: {err}");
+                    0
+                }
+            }
+        }
+    }
+    // The calls answer in regs.r0, but end_handler restores the registers saved in the thread.
+    // After Exit the current thread is another one
+    if threads.curr_thread == caller {
+        threads.curr_mut_thread().regs.r0 = regs.r0;
     }
     threads.schedule_next();
     end_handler(regs);
 }
+
+/// Returns a buffered char right away, otherwise the thread waits for the next one
+fn read_tty(threads: &mut ThreadList, regs: &mut Registers, tty: usize) {
+    if tty >= TTY_NUMBER {
+        warn!("Error in TtyRead handler: Unknown tty {tty}");
+        regs.r0 = u32::MAX;
+        return;
+    }
+    match serial::buffered_char(tty) {
+        Some(char) => regs.r0 = char as u32,
+        None => threads.curr_mut_thread().state = WaitingForChar(tty),
+    }
+}
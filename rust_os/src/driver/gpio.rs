@@ -0,0 +1,345 @@
+//! Die Parallel I/O Controller (PIOA - PIOD)
+//!
+//! Jeder Controller steuert 32 Pins. Ein Pin wird entweder vom PIO selbst gesteuert
+//! (dann ist er ein GPIO) oder an eine der beiden Peripherien A und B weitergegeben.
+//! Pin-Änderungen lösen den Interrupt des Controllers aus, der dann die registrierten Handler aufruft.
+
+use super::{
+    exceptions::{SrcType, PRIO_DEVICE},
+    Driver, Irq,
+};
+use crate::{
+    println,
+    thread::{State::*, ThreadList},
+};
+use volatile_register::{RO, WO};
+
+pub struct Pio {
+    // p. 345
+    pub enable: WO<u32>,
+    pub disable: WO<u32>,
+    pub status: RO<u32>,
+    _reserved0: u32,
+    pub output_enable: WO<u32>,
+    pub output_disable: WO<u32>,
+    pub output_status: RO<u32>,
+    _reserved1: u32,
+    pub filter_enable: WO<u32>,
+    _filter_disable: WO<u32>,
+    _filter_status: RO<u32>,
+    _reserved2: u32,
+    pub set_output: WO<u32>,
+    pub clear_output: WO<u32>,
+    pub output_data: RO<u32>,
+    pub pin_data: RO<u32>,
+    pub int_enable: WO<u32>,
+    pub int_disable: WO<u32>,
+    pub int_mask: RO<u32>,
+    /// Reading clears it
+    pub int_status: RO<u32>,
+    pub multi_driver_enable: WO<u32>,
+    pub multi_driver_disable: WO<u32>,
+    _multi_driver_status: RO<u32>,
+    _reserved3: u32,
+    pub pull_up_disable: WO<u32>,
+    pub pull_up_enable: WO<u32>,
+    pub pull_up_status: RO<u32>,
+    _reserved4: u32,
+    pub select_a: WO<u32>,
+    pub select_b: WO<u32>,
+    _ab_status: RO<u32>,
+}
+
+const PIO_ADDR: u32 = 0xFFFF_F400;
+/// The controllers are 0x200 apart
+const PIO_STRIDE: u32 = 0x200;
+/// The peripheral id (and AIC source) of PIOA. The others follow
+const PIOA_ID: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+}
+
+pub const PORTS: [Port; 4] = [Port::A, Port::B, Port::C, Port::D];
+
+/// The peripherals a pin can be given to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Peripheral {
+    A,
+    B,
+}
+
+/// Called on a change of the pin with the new level
+pub type PinHandler = fn(&mut ThreadList, Pin, bool);
+
+static mut PIN_HANDLERS: [[Option<PinHandler>; 32]; 4] = [[None; 32]; 4];
+
+/// A single pin, e.g. `Pin::new(Port::B, 27)`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pin {
+    pub port: Port,
+    pub bit: u8,
+}
+
+impl Pin {
+    pub const fn new(port: Port, bit: u8) -> Self {
+        Pin { port, bit }
+    }
+
+    /// The pin with the given number, counted across all ports (PA0 = 0, PD31 = 127).
+    /// This is how user space names pins
+    pub fn from_number(number: u32) -> Option<Self> {
+        let port = *PORTS.get(number as usize / 32)?;
+        Some(Pin::new(port, (number % 32) as u8))
+    }
+
+    #[inline(always)]
+    fn mask(self) -> u32 {
+        1 << self.bit
+    }
+
+    #[inline(always)]
+    fn pio(self) -> &'static mut Pio {
+        Pio::new(self.port)
+    }
+
+    /// Makes the pin a GPIO output with the given initial level
+    pub fn output(self, level: bool) -> Self {
+        self.set(level);
+        let pio = self.pio();
+        unsafe {
+            pio.output_enable.write(self.mask());
+            pio.enable.write(self.mask());
+        }
+        self
+    }
+
+    /// Makes the pin a GPIO input
+    pub fn input(self, pull_up: bool) -> Self {
+        let pio = self.pio();
+        unsafe {
+            pio.output_disable.write(self.mask());
+            pio.enable.write(self.mask());
+        }
+        self.pull_up(pull_up)
+    }
+
+    pub fn pull_up(self, on: bool) -> Self {
+        let pio = self.pio();
+        unsafe {
+            match on {
+                true => pio.pull_up_enable.write(self.mask()),
+                false => pio.pull_up_disable.write(self.mask()),
+            }
+        }
+        self
+    }
+
+    /// Open drain. Several devices can drive the line, e.g. for the TWI
+    pub fn multi_driver(self, on: bool) -> Self {
+        let pio = self.pio();
+        unsafe {
+            match on {
+                true => pio.multi_driver_enable.write(self.mask()),
+                false => pio.multi_driver_disable.write(self.mask()),
+            }
+        }
+        self
+    }
+
+    /// Gives the pin to the peripheral. The PIO doesn't control it anymore
+    pub fn peripheral(self, peripheral: Peripheral) -> Self {
+        let pio = self.pio();
+        unsafe {
+            match peripheral {
+                Peripheral::A => pio.select_a.write(self.mask()),
+                Peripheral::B => pio.select_b.write(self.mask()),
+            }
+            pio.disable.write(self.mask());
+        }
+        self
+    }
+
+    #[inline(always)]
+    pub fn set(self, level: bool) {
+        let pio = self.pio();
+        unsafe {
+            match level {
+                true => pio.set_output.write(self.mask()),
+                false => pio.clear_output.write(self.mask()),
+            }
+        }
+    }
+
+    #[inline(always)]
+    pub fn toggle(self) {
+        self.set(self.pio().output_data.read() & self.mask() == 0)
+    }
+
+    /// The level on the pin, for inputs and outputs
+    #[inline(always)]
+    pub fn read(self) -> bool {
+        self.pio().pin_data.read() & self.mask() != 0
+    }
+
+    /// Calls the handler on every change of the pin.
+    /// The input filter is enabled too, so that glitches don't count
+    pub fn on_change(self, handler: PinHandler) -> Self {
+        let pio = self.pio();
+        unsafe {
+            PIN_HANDLERS[self.port as usize][self.bit as usize] = Some(handler);
+            pio.filter_enable.write(self.mask());
+            pio.int_enable.write(self.mask());
+        }
+        self
+    }
+
+    pub fn disable_change(self) {
+        unsafe {
+            self.pio().int_disable.write(self.mask());
+            PIN_HANDLERS[self.port as usize][self.bit as usize] = None;
+        }
+    }
+}
+
+/// What user space can do with a pin. See `syscalls::gpio`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpioOp {
+    Output,
+    Input,
+    Set,
+    Read,
+    /// Waits for the next edge to the given level
+    Wait,
+}
+
+impl TryFrom<u32> for GpioOp {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        use GpioOp::*;
+        Ok(match value {
+            0 => Output,
+            1 => Input,
+            2 => Set,
+            3 => Read,
+            4 => Wait,
+            _ => return Err("Unknown gpio operation"),
+        })
+    }
+}
+
+/// Wakes the threads that wait for this level of the pin.
+/// The interrupt of the pin stays on as long as someone waits for it
+fn wake_waiting(threads: &mut ThreadList, pin: Pin, level: bool) {
+    let mut waiting = false;
+    for thread in threads.iter_mut() {
+        match thread.state {
+            WaitingForPin(other, wanted) if other == pin && wanted == level => {
+                thread.regs.r0 = level as u32;
+                thread.state = Ready;
+            }
+            WaitingForPin(other, _) if other == pin => waiting = true,
+            _ => (),
+        }
+    }
+    if !waiting {
+        pin.disable_change();
+    }
+}
+
+/// Executes the operation for user space. Returns the level of the pin,
+/// or None if the thread waits for an edge. Then the interrupt answers
+pub fn user_op(
+    threads: &mut ThreadList,
+    op: u32,
+    pin: u32,
+    value: u32,
+) -> Result<Option<bool>, &'static str> {
+    let pin = Pin::from_number(pin).ok_or("Unknown pin")?;
+    match GpioOp::try_from(op)? {
+        GpioOp::Output => _ = pin.output(value != 0),
+        GpioOp::Input => _ = pin.input(value != 0),
+        GpioOp::Set => pin.set(value != 0),
+        GpioOp::Read => (),
+        GpioOp::Wait => {
+            pin.on_change(wake_waiting);
+            threads.curr_mut_thread().state = WaitingForPin(pin, value != 0);
+            return Ok(None);
+        }
+    }
+    Ok(Some(pin.read()))
+}
+
+impl Pio {
+    #[inline(always)]
+    pub fn new(port: Port) -> &'static mut Pio {
+        unsafe { &mut *((PIO_ADDR + port as u32 * PIO_STRIDE) as *mut Pio) }
+    }
+
+    /// The port of this controller, derived from its address
+    #[inline(always)]
+    fn port(&self) -> Port {
+        PORTS[((self as *const Pio as u32 - PIO_ADDR) / PIO_STRIDE) as usize]
+    }
+
+    pub fn print(&self) {
+        println!(
+            "PIO{:?}: pio {:08x}, output {:08x}, data {:08x}, pull-up {:08x}, interrupts {:08x}",
+            self.port(),
+            self.status.read(),
+            self.output_status.read(),
+            self.pin_data.read(),
+            !self.pull_up_status.read(),
+            self.int_mask.read()
+        );
+    }
+}
+
+impl Driver for Pio {
+    fn name(&self) -> &'static str {
+        match self.port() {
+            Port::A => "pioa",
+            Port::B => "piob",
+            Port::C => "pioc",
+            Port::D => "piod",
+        }
+    }
+
+    fn init(&mut self) {
+        unsafe { self.int_disable.write(u32::MAX) };
+        // clear old changes
+        self.int_status.read();
+    }
+
+    fn irq(&self) -> Option<Irq> {
+        Some(Irq {
+            source: PIOA_ID + self.port() as usize,
+            prio: PRIO_DEVICE,
+            src_type: SrcType::HighLevelSens,
+        })
+    }
+
+    fn handle_irq(&mut self, threads: &mut ThreadList) -> bool {
+        let port = self.port();
+        let changed = self.int_status.read() & self.int_mask.read();
+        let levels = self.pin_data.read();
+        for bit in (0..32).filter(|bit| changed & (1 << bit) != 0) {
+            if let Some(handler) = unsafe { PIN_HANDLERS[port as usize][bit] } {
+                handler(threads, Pin::new(port, bit as u8), levels & (1 << bit) != 0)
+            }
+        }
+        changed != 0
+    }
+
+    fn peripheral_id(&self) -> Option<usize> {
+        Some(PIOA_ID + self.port() as usize)
+    }
+
+    fn shutdown(&mut self) {
+        unsafe { self.int_disable.write(u32::MAX) };
+    }
+}
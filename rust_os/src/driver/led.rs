@@ -1,53 +1,111 @@
-use volatile_register::WO;
-
-const PIOB: u32 = 0xfffff600;
-const YELLOW_LED: u32 = 1 << 27;
-
-// Hier nutzen wir eine C-ähnliche Implementation mit Structs
-// Die Register werden als Write-Only abstrahiert, so wie es auch in der Dokumentation steht.
-#[repr(C)]
-struct LED {
-    pub per: WO<u32>,
-    unused0: [u32; 3],
-    pub oer: WO<u32>,
-    unused1: [u32; 7],
-    pub sodr: WO<u32>,
-    pub codr: WO<u32>,
-}
+//! Die LEDs des Boards
+//!
+//! Sie sind einfache GPIO-Ausgänge, siehe [super::gpio].
+//! Der Kernel zeigt mit ihnen seinen Zustand an, welche LED was anzeigt steht in consts.rs:
+//! - heartbeat: blinkt gleichmäßig, solange der Kernel lebt
+//! - idle: leuchtet, solange der idle-Thread läuft
+//! - panic: blinkt nach einem Panic dreimal kurz, dann eine Pause
 
-impl LED {
-    fn new() -> &'static mut LED {
-        unsafe { &mut *(PIOB as *mut LED) }
-    }
+use super::{
+    gpio::{Pin, Port},
+    sys_timer::{is_due, SysTimer},
+};
+use crate::{
+    consts::{HEARTBEAT_LED, HEARTBEAT_MS, IDLE_LED, PANIC_LED},
+    util::delay_ms,
+};
 
-    fn init(&mut self) -> &mut Self {
-        unsafe {
-            self.per.write(YELLOW_LED);
-            self.oer.write(YELLOW_LED);
-        }
+pub const YELLOW: Led = Led(Pin::new(Port::B, 27));
+
+#[derive(Clone, Copy, Debug)]
+pub struct Led(Pin);
+
+impl Led {
+    /// Makes the pin an output. The LED starts off
+    pub fn init(self) -> Self {
+        self.0.output(false);
         self
     }
 
-    fn on(&mut self) {
-        unsafe {
-            self.sodr.write(YELLOW_LED);
-        }
+    #[inline(always)]
+    pub fn on(self) {
+        self.0.set(true)
+    }
+
+    #[inline(always)]
+    pub fn off(self) {
+        self.0.set(false)
+    }
+
+    #[inline(always)]
+    pub fn set(self, on: bool) {
+        self.0.set(on)
+    }
+
+    #[inline(always)]
+    pub fn toggle(self) {
+        self.0.toggle()
     }
+}
+
+/// The tick of the next heartbeat toggle
+static mut NEXT_BEAT: u32 = 0;
+
+/// Initializes the status LEDs. The PIOs must be registered
+pub fn init_status() {
+    for led in [HEARTBEAT_LED, IDLE_LED, PANIC_LED].into_iter().flatten() {
+        led.init();
+    }
+    unsafe { NEXT_BEAT = SysTimer::new().deadline_in(HEARTBEAT_MS) };
+}
 
-    fn off(&mut self) {
-        unsafe {
-            self.codr.write(YELLOW_LED);
+/// Toggles the heartbeat LED if it is due. Called by the sys timer
+pub fn heartbeat(now: u32) {
+    let Some(led) = HEARTBEAT_LED else { return };
+    unsafe {
+        if is_due(NEXT_BEAT, now) {
+            led.toggle();
+            NEXT_BEAT = SysTimer::new().deadline_in(HEARTBEAT_MS);
         }
     }
 }
 
-#[no_mangle]
-extern "C" fn yellow_on() {
-    let led = LED::new().init();
+/// When the sys timer has to wake up for the heartbeat.
+/// The scheduler takes it into account, so that the heartbeat goes on in tickless mode
+pub fn next_heartbeat() -> Option<u32> {
+    HEARTBEAT_LED.map(|_| unsafe { NEXT_BEAT })
+}
+
+/// Shows whether the idle thread runs. Called by the scheduler
+#[inline(always)]
+pub fn show_idle(idle: bool) {
+    if let Some(led) = IDLE_LED {
+        led.set(idle)
+    }
+}
+
+/// Blinks the panic pattern until `done` returns true.
+/// Works without interrupts, so that a board without serial still shows it crashed
+pub fn panic_blink(done: impl Fn() -> bool) {
+    let Some(led) = PANIC_LED else {
+        while !done() {}
+        return;
+    };
+    // The other indicators might share the LED
+    led.init();
     loop {
-        led.on();
-        crate::util::wait(100_000);
-        led.off();
-        crate::util::wait(100_000);
+        for _ in 0..3 {
+            led.on();
+            delay_ms(150);
+            led.off();
+            delay_ms(150);
+            if done() {
+                return;
+            }
+        }
+        delay_ms(600);
+        if done() {
+            return;
+        }
     }
 }
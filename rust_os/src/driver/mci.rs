@@ -0,0 +1,420 @@
+//! Das Multimedia Card Interface
+//!
+//! Es spricht mit einer SD- oder MMC-Karte in Slot A. Beim Registrieren wird die Karte
+//! initialisiert (Identifikation, Adresse, Kapazität aus dem CSD), danach ist sie ein [BlockDevice].
+//! Sektoren werden über den PDC übertragen, gewartet wird gepollt, weil Dateisystemzugriffe
+//! aus Syscalls mit maskierten Interrupts kommen.
+//! Der PDC des AT91RM9200 vertauscht dabei die Bytes jedes Worts, das drehen wir zurück.
+
+use super::{
+    gpio::{Peripheral, Pin, Port},
+    pdc::Pdc,
+    power_management::PMC,
+    sys_timer::{is_due, SysTimer},
+    Driver,
+};
+use crate::{
+    block::{BlockDevice, BlockError, Sector, SECTOR_SIZE},
+    consts::{MCI_CLOCK_HZ, MCI_WIDE_BUS},
+    info, println, warn,
+};
+use core::ptr::{addr_of, addr_of_mut};
+use volatile_register::{RO, RW, WO};
+
+pub struct Mci {
+    // p. 463
+    pub ctrl: WO<u32>,
+    pub mode: RW<u32>,
+    pub data_timeout: RW<u32>,
+    pub sd_card: RW<u32>,
+    pub argument: RW<u32>,
+    pub command: WO<u32>,
+    _reserved0: [u32; 2],
+    pub response: [RO<u32>; 4],
+    _receive: RO<u32>,
+    _transmit: WO<u32>,
+    _reserved1: [u32; 2],
+    pub status: RO<u32>,
+    _int_enable: WO<u32>,
+    pub int_disable: WO<u32>,
+    _int_mask: RO<u32>,
+}
+
+const MCI_ADDR: u32 = 0xFFFB_4000;
+const MCI_ID: usize = 10;
+/// The clock during the identification
+const IDENTIFICATION_HZ: u32 = 400_000;
+/// How long a command or a read may take
+const TIMEOUT_MS: u32 = 100;
+/// Writing a sector and leaving the idle state may take much longer
+const BUSY_TIMEOUT_MS: u32 = 1000;
+// Control
+const MCIEN: u32 = 1 << 0;
+const MCIDIS: u32 = 1 << 1;
+const PWSDIS: u32 = 1 << 3;
+const SWRST: u32 = 1 << 7;
+// Mode
+const PWSDIV: u32 = 7 << 8;
+const PDCMODE: u32 = 1 << 15;
+const BLKLEN: u32 = 16;
+// Data Timeout: 15 * 1048576 cycles
+const DTOR_MAX: u32 = (7 << 4) | 15;
+// SD Card
+const SDCBUS: u32 = 1 << 7;
+// Command
+const RSP_48: u32 = 1 << 6;
+const RSP_136: u32 = 2 << 6;
+const SPCMD_INIT: u32 = 1 << 8;
+const OPDCMD: u32 = 1 << 11;
+const MAXLAT: u32 = 1 << 12;
+const TRCMD_START: u32 = 1 << 16;
+const TRDIR_READ: u32 = 1 << 18;
+// Status
+const CMDRDY: u32 = 1 << 0;
+const NOTBUSY: u32 = 1 << 5;
+const ENDRX: u32 = 1 << 6;
+const ENDTX: u32 = 1 << 7;
+const RINDE: u32 = 1 << 16;
+const RDIRE: u32 = 1 << 17;
+const RCRCE: u32 = 1 << 18;
+const RENDE: u32 = 1 << 19;
+const RTOE: u32 = 1 << 20;
+const DCRCE: u32 = 1 << 21;
+const DTOE: u32 = 1 << 22;
+const OVRE: u32 = 1 << 30;
+const UNRE: u32 = 1 << 31;
+const RESPONSE_ERRORS: u32 = RINDE | RDIRE | RCRCE | RENDE | RTOE;
+const DATA_ERRORS: u32 = DCRCE | DTOE | OVRE | UNRE;
+
+// Commands
+const GO_IDLE_STATE: u32 = 0;
+const SEND_OP_COND: u32 = 1 | RSP_48 | OPDCMD;
+const ALL_SEND_CID: u32 = 2 | RSP_136 | OPDCMD;
+const SEND_RELATIVE_ADDR: u32 = 3 | RSP_48 | OPDCMD;
+const SELECT_CARD: u32 = 7 | RSP_48 | MAXLAT;
+const SEND_IF_COND: u32 = 8 | RSP_48;
+const SEND_CSD: u32 = 9 | RSP_136 | MAXLAT;
+const SET_BLOCKLEN: u32 = 16 | RSP_48 | MAXLAT;
+const READ_SINGLE_BLOCK: u32 = 17 | RSP_48 | MAXLAT | TRCMD_START | TRDIR_READ;
+const WRITE_BLOCK: u32 = 24 | RSP_48 | MAXLAT | TRCMD_START;
+const APP_CMD: u32 = 55 | RSP_48 | MAXLAT;
+const SET_BUS_WIDTH: u32 = 6 | RSP_48 | MAXLAT;
+const SD_SEND_OP_COND: u32 = 41 | RSP_48;
+/// 2.7 - 3.6 V, and we can handle high capacity cards
+const OCR_VOLTAGES: u32 = 0x00FF_8000;
+const OCR_HIGH_CAPACITY: u32 = 1 << 30;
+const OCR_READY: u32 = 1 << 31;
+/// 2.7 - 3.6 V and the check pattern
+const IF_COND: u32 = 0x1AA;
+
+const MCCK: Pin = Pin::new(Port::A, 27);
+const MCCDA: Pin = Pin::new(Port::A, 28);
+const MCDA0: Pin = Pin::new(Port::A, 29);
+/// MCDA1 - MCDA3, only used with the 4-bit bus
+const MCDA_WIDE: [Pin; 3] = [
+    Pin::new(Port::B, 3),
+    Pin::new(Port::B, 4),
+    Pin::new(Port::B, 5),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CardType {
+    Mmc,
+    /// SD 1.x, addressed in bytes
+    Sd,
+    /// SDHC and SDXC, addressed in sectors
+    SdHighCapacity,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Card {
+    pub card_type: CardType,
+    /// The relative card address
+    rca: u32,
+    pub sectors: u32,
+}
+
+static mut CARD: Option<Card> = None;
+
+/// The PDC moves whole words
+#[repr(C, align(4))]
+struct Buffer([u32; SECTOR_SIZE / 4]);
+
+static mut BUFFER: Buffer = Buffer([0; SECTOR_SIZE / 4]);
+
+impl Mci {
+    #[inline(always)]
+    pub fn new() -> &'static mut Mci {
+        unsafe { &mut *(MCI_ADDR as *mut Mci) }
+    }
+
+    /// The initialized card, if there is one
+    pub fn card(&self) -> Option<Card> {
+        unsafe { CARD }
+    }
+
+    /// Sets the clock of the card: MCCK = MCK / (2 * (CLKDIV + 1))
+    fn set_clock(&mut self, hz: u32) {
+        let mck = PMC::new().master_clock_hz();
+        let div = (mck.div_ceil(2 * hz.max(1)).saturating_sub(1)).min(255);
+        unsafe { self.mode.modify(|mode| (mode & !0xFF) | div) };
+    }
+
+    /// Waits until one of the bits is set or an error occurs
+    fn wait_for(&self, bits: u32, errors: u32, ms: u32) -> Result<u32, &'static str> {
+        let timer = SysTimer::new();
+        let deadline = timer.deadline_in(ms);
+        loop {
+            let status = self.status.read();
+            if status & errors & RTOE != 0 {
+                return Err("The card didn't respond");
+            }
+            if status & errors & DTOE != 0 {
+                return Err("Data timeout");
+            }
+            if status & errors != 0 {
+                return Err("Transfer error");
+            }
+            if status & bits != 0 {
+                return Ok(status);
+            }
+            if is_due(deadline, timer.now()) {
+                return Err("Timeout on the MCI");
+            }
+        }
+    }
+
+    /// Sends a command and returns the first word of the response.
+    /// Some responses (R3) have no valid CRC, their CRC error is ignored
+    fn command(&mut self, command: u32, argument: u32) -> Result<u32, &'static str> {
+        unsafe {
+            self.argument.write(argument);
+            self.command.write(command);
+        }
+        let errors = match command & 0x3F {
+            1 | 41 => RESPONSE_ERRORS & !RCRCE,
+            _ => RESPONSE_ERRORS,
+        };
+        self.wait_for(CMDRDY, errors, TIMEOUT_MS)?;
+        Ok(self.response[0].read())
+    }
+
+    fn app_command(&mut self, command: u32, argument: u32) -> Result<u32, &'static str> {
+        let rca = self.card().map_or(0, |card| card.rca);
+        self.command(APP_CMD, rca << 16)?;
+        self.command(command, argument)
+    }
+
+    /// Repeats the op cond command until the card left its idle state. Returns the OCR
+    fn wait_ready(&mut self, card_type: CardType, argument: u32) -> Result<u32, &'static str> {
+        let timer = SysTimer::new();
+        let deadline = timer.deadline_in(BUSY_TIMEOUT_MS);
+        loop {
+            let ocr = match card_type {
+                CardType::Mmc => self.command(SEND_OP_COND, argument)?,
+                _ => self.app_command(SD_SEND_OP_COND, argument)?,
+            };
+            if ocr & OCR_READY != 0 {
+                return Ok(ocr);
+            }
+            if is_due(deadline, timer.now()) {
+                return Err("The card stays busy");
+            }
+        }
+    }
+
+    /// Identifies the card and selects it for transfers
+    pub fn init_card(&mut self) -> Result<Card, &'static str> {
+        unsafe { CARD = None };
+        self.set_clock(IDENTIFICATION_HZ);
+        unsafe { self.sd_card.write(0) };
+        // 74 clocks to wake the card up
+        self.command(SPCMD_INIT, 0)?;
+        self.command(GO_IDLE_STATE, 0)?;
+        // Only SD 2.0 cards know this command
+        let sd_v2 = self
+            .command(SEND_IF_COND, IF_COND)
+            .is_ok_and(|r| r & 0xFFF == IF_COND);
+        let argument = match sd_v2 {
+            true => OCR_VOLTAGES | OCR_HIGH_CAPACITY,
+            false => OCR_VOLTAGES,
+        };
+        let (card_type, ocr) = match self.wait_ready(CardType::Sd, argument) {
+            Ok(ocr) => (CardType::Sd, ocr),
+            // A MMC doesn't know the app commands
+            Err(_) => {
+                self.command(GO_IDLE_STATE, 0)?;
+                (CardType::Mmc, self.wait_ready(CardType::Mmc, argument)?)
+            }
+        };
+        let card_type = match ocr & OCR_HIGH_CAPACITY != 0 {
+            true if card_type == CardType::Sd => CardType::SdHighCapacity,
+            _ => card_type,
+        };
+        self.command(ALL_SEND_CID, 0)?;
+        let rca = match card_type {
+            CardType::Mmc => {
+                self.command(SEND_RELATIVE_ADDR, 1 << 16)?;
+                1
+            }
+            _ => self.command(SEND_RELATIVE_ADDR, 0)? >> 16,
+        };
+        self.command(SEND_CSD, rca << 16)?;
+        let csd = [
+            self.response[0].read(),
+            self.response[1].read(),
+            self.response[2].read(),
+            self.response[3].read(),
+        ];
+        let card = Card {
+            card_type,
+            rca,
+            sectors: capacity(&csd),
+        };
+        self.command(SELECT_CARD, rca << 16)?;
+        self.wait_for(NOTBUSY, DATA_ERRORS, BUSY_TIMEOUT_MS)?;
+        unsafe { CARD = Some(card) };
+        if card_type != CardType::Mmc && MCI_WIDE_BUS {
+            self.app_command(SET_BUS_WIDTH, 2)?;
+            unsafe { self.sd_card.write(SDCBUS) };
+        }
+        if card_type != CardType::SdHighCapacity {
+            self.command(SET_BLOCKLEN, SECTOR_SIZE as u32)?;
+        }
+        // 20 MHz for MMC, 25 MHz for SD
+        let max_hz = match card_type {
+            CardType::Mmc => 20_000_000,
+            _ => 25_000_000,
+        };
+        self.set_clock(MCI_CLOCK_HZ.min(max_hz));
+        Ok(card)
+    }
+
+    /// The address of the sector in the commands
+    fn address(card: &Card, sector: u32) -> u32 {
+        match card.card_type {
+            CardType::SdHighCapacity => sector,
+            _ => sector * SECTOR_SIZE as u32,
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "mci: mode {:08x}, status {:08x}, card {:?}",
+            self.mode.read(),
+            self.status.read(),
+            self.card()
+        );
+    }
+}
+
+/// The number of sectors from the card specific data
+fn capacity(csd: &[u32; 4]) -> u32 {
+    // bits msb..=lsb of the 128-bit register, csd[0] holds bits 127 - 96
+    let bits = |msb: u32, lsb: u32| -> u32 {
+        (lsb..=msb).fold(0, |value, bit| {
+            let word = csd[3 - (bit / 32) as usize];
+            value | (((word >> (bit % 32)) & 1) << (bit - lsb))
+        })
+    };
+    match bits(127, 126) {
+        // CSD 2.0: C_SIZE counts 512 kB
+        1 => (bits(69, 48) + 1) * 1024,
+        _ => {
+            let block_len = bits(83, 80);
+            let mult = bits(49, 47);
+            let bytes = (bits(73, 62) as u64 + 1) << (mult + 2 + block_len);
+            (bytes / SECTOR_SIZE as u64) as u32
+        }
+    }
+}
+
+impl BlockDevice for Mci {
+    fn sector_count(&self) -> u32 {
+        self.card().map_or(0, |card| card.sectors)
+    }
+
+    fn read(&mut self, sector: u32, buf: &mut Sector) -> Result<(), BlockError> {
+        let card = self.card().ok_or(BlockError::NoMedium)?;
+        if sector >= card.sectors {
+            return Err(BlockError::OutOfRange);
+        }
+        let pdc = Pdc::of(MCI_ADDR);
+        unsafe { pdc.receive_words(&mut (*addr_of_mut!(BUFFER)).0) };
+        let result = self
+            .command(READ_SINGLE_BLOCK, Mci::address(&card, sector))
+            .and_then(|_| self.wait_for(ENDRX, DATA_ERRORS, TIMEOUT_MS));
+        pdc.stop();
+        result.map_err(BlockError::Device)?;
+        for (i, word) in unsafe { (*addr_of!(BUFFER)).0.iter() }.enumerate() {
+            buf[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u32, buf: &Sector) -> Result<(), BlockError> {
+        let card = self.card().ok_or(BlockError::NoMedium)?;
+        if sector >= card.sectors {
+            return Err(BlockError::OutOfRange);
+        }
+        for (i, word) in unsafe { (*addr_of_mut!(BUFFER)).0.iter_mut() }.enumerate() {
+            *word = u32::from_be_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        self.command(WRITE_BLOCK, Mci::address(&card, sector))
+            .map_err(BlockError::Device)?;
+        let pdc = Pdc::of(MCI_ADDR);
+        unsafe { pdc.transmit_words(&(*addr_of!(BUFFER)).0) };
+        let result = self
+            .wait_for(ENDTX, DATA_ERRORS, TIMEOUT_MS)
+            .and_then(|_| self.wait_for(NOTBUSY, DATA_ERRORS, BUSY_TIMEOUT_MS));
+        pdc.stop();
+        result.map(|_| ()).map_err(BlockError::Device)
+    }
+}
+
+impl Driver for Mci {
+    fn name(&self) -> &'static str {
+        "mci"
+    }
+
+    fn init(&mut self) {
+        MCCK.peripheral(Peripheral::A);
+        for pin in [MCCDA, MCDA0] {
+            pin.pull_up(true).peripheral(Peripheral::A);
+        }
+        if MCI_WIDE_BUS {
+            for pin in MCDA_WIDE {
+                pin.pull_up(true).peripheral(Peripheral::B);
+            }
+        }
+        unsafe {
+            self.ctrl.write(SWRST);
+            self.ctrl.write(MCIDIS | PWSDIS);
+            self.int_disable.write(u32::MAX);
+            self.data_timeout.write(DTOR_MAX);
+            self.mode
+                .write(PWSDIV | PDCMODE | ((SECTOR_SIZE as u32) << BLKLEN));
+            self.ctrl.write(MCIEN);
+        }
+        match self.init_card() {
+            Ok(card) => info!(
+                "{:?} card with {} MB",
+                card.card_type,
+                card.sectors / (1024 * 1024 / SECTOR_SIZE as u32)
+            ),
+            Err(err) => warn!("No memory card: {err}"),
+        }
+    }
+
+    fn peripheral_id(&self) -> Option<usize> {
+        Some(MCI_ID)
+    }
+
+    fn shutdown(&mut self) {
+        Pdc::of(MCI_ADDR).stop();
+        unsafe {
+            CARD = None;
+            self.ctrl.write(MCIDIS);
+        }
+    }
+}
@@ -16,3 +16,68 @@ pub fn remap() {
 pub fn get_abort_adress() -> u32 {
     unsafe { read_volatile((MEMORY_CONTROLLER + 8) as *mut u32) }
 }
+
+/// Das Abort Status Register (MC_ASR) sagt uns, was bei einem Abort schief ging
+pub fn get_abort_status() -> AbortStatus {
+    AbortStatus(unsafe { read_volatile((MEMORY_CONTROLLER + 4) as *mut u32) })
+}
+
+/// The decoded MC_ASR (p. 134)
+#[derive(Clone, Copy)]
+pub struct AbortStatus(pub u32);
+
+impl AbortStatus {
+    /// The kind of the abort
+    pub fn kind(&self) -> &'static str {
+        if self.0 & (1 << 0) != 0 {
+            "undefined address"
+        } else if self.0 & (1 << 1) != 0 {
+            "misaligned address"
+        } else {
+            "unknown"
+        }
+    }
+
+    /// The size of the aborted access
+    pub fn size(&self) -> &'static str {
+        match (self.0 >> 8) & 0b11 {
+            0 => "byte",
+            1 => "half-word",
+            2 => "word",
+            _ => "reserved size",
+        }
+    }
+
+    /// The type of the aborted access
+    pub fn access(&self) -> &'static str {
+        match (self.0 >> 10) & 0b11 {
+            0 => "data read",
+            1 => "data write",
+            2 => "code fetch",
+            _ => "reserved access",
+        }
+    }
+
+    /// Who caused the abort
+    pub fn master(&self) -> &'static str {
+        match (self.0 >> 16) & 0b111 {
+            0b001 => "ARM920T",
+            0b010 => "PDC",
+            0b100 => "UHP",
+            _ => "unknown master",
+        }
+    }
+}
+
+impl core::fmt::Display for AbortStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{} ({} {} by {})",
+            self.kind(),
+            self.size(),
+            self.access(),
+            self.master()
+        )
+    }
+}
@@ -0,0 +1,89 @@
+//! Der Peripheral DMA Controller
+//!
+//! Die meisten Peripherien (DBGU, USARTs, SPI, ...) haben einen eigenen PDC-Kanal,
+//! dessen Register 0x100 hinter den Registern der Peripherie liegen.
+//! Er überträgt einen Puffer ohne die CPU. Das Ende meldet die Peripherie selbst
+//! mit ENDRX / ENDTX in ihrem Status-Register.
+
+use volatile_register::{RO, RW, WO};
+
+pub struct Pdc {
+    // p. 266
+    pub rx_pointer: RW<u32>,
+    pub rx_counter: RW<u32>,
+    pub tx_pointer: RW<u32>,
+    pub tx_counter: RW<u32>,
+    _rx_next_pointer: RW<u32>,
+    _rx_next_counter: RW<u32>,
+    _tx_next_pointer: RW<u32>,
+    _tx_next_counter: RW<u32>,
+    pub transfer_control: WO<u32>,
+    _transfer_status: RO<u32>,
+}
+
+const PDC_OFFSET: u32 = 0x100;
+const RXTEN: u32 = 1 << 0;
+const RXTDIS: u32 = 1 << 1;
+const TXTEN: u32 = 1 << 8;
+const TXTDIS: u32 = 1 << 9;
+
+impl Pdc {
+    /// The channel of the peripheral at the given address
+    #[inline(always)]
+    pub fn of(peripheral: u32) -> &'static mut Pdc {
+        unsafe { &mut *((peripheral + PDC_OFFSET) as *mut Pdc) }
+    }
+
+    /// Starts sending the buffer.
+    /// It must stay untouched until the transfer is done
+    pub fn transmit(&mut self, buf: &[u8]) {
+        unsafe {
+            self.transfer_control.write(TXTDIS);
+            self.tx_pointer.write(buf.as_ptr() as u32);
+            self.tx_counter.write(buf.len() as u32);
+            self.transfer_control.write(TXTEN);
+        }
+    }
+
+    /// Starts receiving into the buffer.
+    /// It must stay untouched until the transfer is done
+    pub fn receive(&mut self, buf: &mut [u8]) {
+        unsafe {
+            self.transfer_control.write(RXTDIS);
+            self.rx_pointer.write(buf.as_mut_ptr() as u32);
+            self.rx_counter.write(buf.len() as u32);
+            self.transfer_control.write(RXTEN);
+        }
+    }
+
+    /// Like transmit, for peripherals that move words (e.g. the MCI).
+    /// Their counters count words instead of bytes
+    pub fn transmit_words(&mut self, buf: &[u32]) {
+        unsafe {
+            self.transfer_control.write(TXTDIS);
+            self.tx_pointer.write(buf.as_ptr() as u32);
+            self.tx_counter.write(buf.len() as u32);
+            self.transfer_control.write(TXTEN);
+        }
+    }
+
+    /// Like receive, for peripherals that move words
+    pub fn receive_words(&mut self, buf: &mut [u32]) {
+        unsafe {
+            self.transfer_control.write(RXTDIS);
+            self.rx_pointer.write(buf.as_mut_ptr() as u32);
+            self.rx_counter.write(buf.len() as u32);
+            self.transfer_control.write(RXTEN);
+        }
+    }
+
+    /// Whether a transmit is still running
+    #[inline(always)]
+    pub fn transmitting(&self) -> bool {
+        self.tx_counter.read() != 0
+    }
+
+    pub fn stop(&mut self) {
+        unsafe { self.transfer_control.write(RXTDIS | TXTDIS) }
+    }
+}
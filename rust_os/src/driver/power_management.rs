@@ -1,22 +1,58 @@
-#![allow(dead_code)]
-use volatile_register::{RO, WO};
+//! Der Power Management Controller
+//!
+//! Steuert die Takte: den Prozessortakt (für idle), die Peripherie-Takte der Driver,
+//! den Master-Takt und den Suspend im Slow-Clock-Modus.
+use super::Driver;
+use crate::{consts::MASTER_CLOCK, println};
+use core::{
+    arch::global_asm,
+    mem,
+    ptr::{addr_of, copy_nonoverlapping},
+};
+use volatile_register::{RO, RW, WO};
 
 pub struct PMC {
     // p. 276
     pub scer: WO<u32>,
     pub scdr: WO<u32>,
-    pub scsr: RO<u32>,
+    _scsr: RO<u32>,
     _reserved1: u32,
-    _per_clock: [u32; 3],
+    pub pcer: WO<u32>,
+    pub pcdr: WO<u32>,
+    pub pcsr: RO<u32>,
     _reserved2: u32,
-    _unused: [u32; 16],
+    _main_oscillator: RW<u32>,
+    pub main_frequency: RO<u32>,
+    pub plla: RW<u32>,
+    pub pllb: RW<u32>,
+    pub master_clock: RW<u32>,
+    _reserved3: [u32; 3],
+    _programmable_clocks: [RW<u32>; 4],
+    _reserved4: [u32; 4],
     _int_enable: WO<u32>,
     _int_disable: WO<u32>,
-    _status: RO<u32>,
-    _mask: RO<u32>,
+    pub status: RO<u32>,
+    _int_mask: RO<u32>,
 }
 
 const PMC_ADDR: u32 = 0xFFFF_FC00;
+const SLOW_CLOCK_HZ: u32 = 32768;
+/// Master Clock Ready
+const MCKRDY: u32 = 1 << 3;
+/// Main Clock Ready (MCFR)
+const MAINRDY: u32 = 1 << 16;
+/// Where the slow clock routine is copied to. The internal SRAM is at 0 after the remap,
+/// behind the IVT
+const SRAM_SLOW_CLOCK: u32 = 0x200;
+
+/// The source of the master clock (MCKR.CSS)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockSource {
+    Slow,
+    Main,
+    PllA,
+    PllB,
+}
 
 impl PMC {
     #[inline(always)]
@@ -27,4 +63,189 @@ impl PMC {
     pub fn enable_sys_clock(&mut self) {
         unsafe { self.scer.write(1) };
     }
+
+    /// Disables the processor clock until the next interrupt.
+    /// This is the AT91RM9200's way to wait for an interrupt
+    #[inline(always)]
+    pub fn idle(&mut self) {
+        unsafe { self.scdr.write(1) };
+    }
+
+    /// Enables the clock of the peripheral with the given id (the same as its AIC source)
+    #[inline(always)]
+    pub fn enable_peripheral(&mut self, id: usize) {
+        unsafe { self.pcer.write(1 << id) };
+    }
+
+    #[inline(always)]
+    pub fn disable_peripheral(&mut self, id: usize) {
+        unsafe { self.pcdr.write(1 << id) };
+    }
+
+    /// The frequency of the main oscillator in Hz, measured against the slow clock
+    pub fn main_clock(&self) -> u32 {
+        let mcfr = self.main_frequency.read();
+        if mcfr & MAINRDY == 0 {
+            return 0;
+        }
+        // MAINF main clock cycles in 16 slow clock cycles
+        (mcfr & 0xFFFF) * (SLOW_CLOCK_HZ / 16)
+    }
+
+    /// The frequency of a PLL with the given register value
+    fn pll_clock(&self, pll: u32) -> u32 {
+        let div = pll & 0xFF;
+        let mul = (pll >> 16) & 0x7FF;
+        if div == 0 || mul == 0 {
+            return 0;
+        }
+        (self.main_clock() as u64 * (mul + 1) as u64 / div as u64) as u32
+    }
+
+    /// The current source of the master clock
+    pub fn clock_source(&self) -> ClockSource {
+        match self.master_clock.read() & 0b11 {
+            0 => ClockSource::Slow,
+            1 => ClockSource::Main,
+            2 => ClockSource::PllA,
+            _ => ClockSource::PllB,
+        }
+    }
+
+    /// The frequency of the processor clock in Hz
+    pub fn processor_clock(&self) -> u32 {
+        let source = match self.clock_source() {
+            ClockSource::Slow => SLOW_CLOCK_HZ,
+            ClockSource::Main => self.main_clock(),
+            ClockSource::PllA => self.pll_clock(self.plla.read()),
+            ClockSource::PllB => self.pll_clock(self.pllb.read()),
+        };
+        source >> ((self.master_clock.read() >> 2) & 0b111)
+    }
+
+    /// The frequency of the master clock in Hz, the clock of all peripherals
+    pub fn master_clock_hz(&self) -> u32 {
+        self.processor_clock() / (((self.master_clock.read() >> 8) & 0b11) + 1)
+    }
+
+    /// Configures the master clock.
+    /// The processor clock is the source divided by 2^prescaler (0 - 6),
+    /// the master clock is the processor clock divided by divider (1 - 4).
+    /// The PLLs must already be locked
+    pub fn set_master_clock(&mut self, source: ClockSource, prescaler: u32, divider: u32) {
+        let mckr = source as u32 | (prescaler.min(6) << 2) | ((divider.clamp(1, 4) - 1) << 8);
+        unsafe { self.master_clock.write(mckr) };
+        while self.status.read() & MCKRDY == 0 {}
+    }
+
+    /// Drops into the slow clock mode until the next interrupt:
+    /// The SDRAM goes into self-refresh, the master clock to the slow clock and
+    /// the PLLs and the main oscillator are stopped. Then the processor clock is disabled.
+    /// Everything is restored before returning. Must be called with masked interrupts,
+    /// the waking interrupt is taken after they are unmasked again.
+    /// Wake-ups come from the sys timer (it runs on the slow clock). The dbgu can wake us too,
+    /// but the character that does it is lost, since its baud rate depends on the master clock
+    pub fn suspend(&mut self) {
+        // While the SDRAM is in self-refresh we can't run from it, so the routine is copied into the SRAM
+        let start = at91_slow_clock as *const () as usize;
+        let len = addr_of!(at91_slow_clock_end) as usize - start;
+        unsafe {
+            copy_nonoverlapping(start as *const u8, SRAM_SLOW_CLOCK as *mut u8, len);
+            let slow_clock: extern "aapcs" fn() = mem::transmute(SRAM_SLOW_CLOCK);
+            slow_clock();
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "main clock {} Hz, processor clock {} Hz ({:?}), master clock {} Hz, peripherals {:08x}",
+            self.main_clock(),
+            self.processor_clock(),
+            self.clock_source(),
+            self.master_clock_hz(),
+            self.pcsr.read()
+        );
+    }
+}
+
+extern "aapcs" {
+    fn at91_slow_clock();
+    static at91_slow_clock_end: u8;
+}
+
+// Position independent, so it can run from the SRAM. Only uses registers while the SDRAM sleeps.
+// r4: PMC, r5: SDRAMC_SRR, r6: MCKR, r7: PLLAR, r2: PLLBR, r3: MOR
+global_asm!(
+    ".global at91_slow_clock",
+    ".global at91_slow_clock_end",
+    "at91_slow_clock:",
+    "stmfd sp!, {{r4-r7, lr}}",
+    "ldr r4, =0xFFFFFC00",
+    "ldr r5, =0xFFFFFF9C",
+    "ldr r6, [r4, #0x30]",
+    "ldr r7, [r4, #0x28]",
+    "ldr r2, [r4, #0x2C]",
+    "ldr r3, [r4, #0x20]",
+    // SDRAM self-refresh
+    "mov r0, #1",
+    "str r0, [r5]",
+    // master clock = slow clock
+    "mov r0, #0",
+    "str r0, [r4, #0x30]",
+    "1: ldr r0, [r4, #0x68]",
+    "tst r0, #(1 << 3)",
+    "beq 1b",
+    // stop the PLLs (bit 29 must always be written as 1) and the main oscillator
+    "mov r0, #(1 << 29)",
+    "str r0, [r4, #0x28]",
+    "str r0, [r4, #0x2C]",
+    "mov r0, #0",
+    "str r0, [r4, #0x20]",
+    // wait for an interrupt
+    "mov r0, #1",
+    "str r0, [r4, #0x04]",
+    // restore the main oscillator, if it was on
+    "str r3, [r4, #0x20]",
+    "tst r3, #1",
+    "beq 3f",
+    "2: ldr r0, [r4, #0x68]",
+    "tst r0, #(1 << 0)",
+    "beq 2b",
+    // restore the PLLs, if they were on (MUL != 0)
+    "3: str r7, [r4, #0x28]",
+    "mov r0, r7, lsr #16",
+    "bics r0, r0, #0xF800",
+    "beq 5f",
+    "4: ldr r0, [r4, #0x68]",
+    "tst r0, #(1 << 1)",
+    "beq 4b",
+    "5: str r2, [r4, #0x2C]",
+    "mov r0, r2, lsr #16",
+    "bics r0, r0, #0xF800",
+    "beq 7f",
+    "6: ldr r0, [r4, #0x68]",
+    "tst r0, #(1 << 2)",
+    "beq 6b",
+    // restore the master clock. The SDRAM leaves self-refresh on the next access
+    "7: str r6, [r4, #0x30]",
+    "8: ldr r0, [r4, #0x68]",
+    "tst r0, #(1 << 3)",
+    "beq 8b",
+    "ldmfd sp!, {{r4-r7, pc}}",
+    // the literal pool has to be copied too
+    ".ltorg",
+    "at91_slow_clock_end:",
+);
+
+impl Driver for PMC {
+    fn name(&self) -> &'static str {
+        "pmc"
+    }
+
+    fn init(&mut self) {
+        self.enable_sys_clock();
+        if let Some((source, prescaler, divider)) = MASTER_CLOCK {
+            self.set_master_clock(source, prescaler, divider);
+        }
+    }
 }
@@ -0,0 +1,141 @@
+//! Die Driver-Registry
+//!
+//! Sie initialisiert die registrierten Driver, vergibt die Vektoren im AIC
+//! und verteilt Interrupts auf geteilten Leitungen an alle Driver der Leitung.
+
+use super::{exceptions::AIC, gpio::Pin, power_management::PMC, Driver, Irq};
+use crate::{debug, println, thread::ThreadList, trace, warn};
+use core::ptr::addr_of_mut;
+
+const MAX_DRIVERS: usize = 32;
+
+struct Entry {
+    driver: &'static mut dyn Driver,
+    /// How many interrupts the driver has handled
+    irqs: u32,
+}
+
+const NO_ENTRY: Option<Entry> = None;
+
+pub static mut REGISTRY: Registry = Registry {
+    entries: [NO_ENTRY; MAX_DRIVERS],
+};
+
+/// Gets the global Registry
+#[inline(always)]
+pub fn get_registry() -> &'static mut Registry {
+    unsafe { &mut *addr_of_mut!(REGISTRY) }
+}
+
+pub struct Registry {
+    entries: [Option<Entry>; MAX_DRIVERS],
+}
+
+impl Registry {
+    /// Initializes the driver and claims its interrupt line.
+    /// The first driver on a line configures it, later ones must agree on the source type and priority.
+    /// The line is only enabled once the driver is initialized and can handle its interrupts
+    pub fn register(&mut self, driver: &'static mut dyn Driver) -> Result<(), &'static str> {
+        let slot = self
+            .entries
+            .iter()
+            .position(|e| e.is_none())
+            .ok_or("Couldn't register driver. Registry is full")?;
+        let irq = driver.irq();
+        let first_on_line = match irq.map(|irq| (irq, self.claimed(irq.source))) {
+            Some((irq, Some(claimed))) if claimed.src_type != irq.src_type => {
+                return Err("Couldn't register driver. Line is shared with another source type")
+            }
+            Some((irq, Some(claimed))) if claimed.prio != irq.prio => {
+                return Err("Couldn't register driver. Line is shared with another priority")
+            }
+            Some((_, claimed)) => claimed.is_none(),
+            None => false,
+        };
+        if let Some(id) = driver.peripheral_id() {
+            PMC::new().enable_peripheral(id);
+        }
+        driver.init();
+        debug!("Registered {}", driver.name());
+        self.entries[slot] = Some(Entry { driver, irqs: 0 });
+        if let (Some(irq), true) = (irq, first_on_line) {
+            AIC::new().set_handler(irq.source, dispatch, irq.prio, irq.src_type);
+        }
+        Ok(())
+    }
+
+    /// Shuts the driver down and removes it.
+    /// Its peripheral clock and interrupt line are turned off, if no other driver uses them
+    pub fn unregister(&mut self, name: &str) -> Result<(), &'static str> {
+        let slot = self
+            .entries
+            .iter()
+            .position(|e| e.as_ref().is_some_and(|e| e.driver.name() == name))
+            .ok_or("Couldn't unregister driver. No driver with that name")?;
+        let entry = self.entries[slot].take().unwrap();
+        entry.driver.shutdown();
+        debug!("Unregistered {name}");
+        if let Some(id) = entry.driver.peripheral_id() {
+            if !self.iter().any(|e| e.driver.peripheral_id() == Some(id)) {
+                PMC::new().disable_peripheral(id);
+            }
+        }
+        if let Some(irq) = entry.driver.irq() {
+            if self.claimed(irq.source).is_none() {
+                AIC::new().disable_source(irq.source);
+            }
+        }
+        Ok(())
+    }
+
+    /// How the given line is configured if it is already claimed by a driver
+    fn claimed(&self, source: usize) -> Option<Irq> {
+        self.iter()
+            .filter_map(|e| e.driver.irq())
+            .find(|irq| irq.source == source)
+    }
+
+    /// The name of the registered driver that uses the pin
+    pub fn owner(&self, pin: Pin) -> Option<&'static str> {
+        self.iter()
+            .find(|e| e.driver.owns(pin))
+            .map(|e| e.driver.name())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter_map(|e| e.as_ref())
+    }
+
+    /// Prints all registered devices with their interrupt lines and counts
+    pub fn print(&self) {
+        for entry in self.iter() {
+            match entry.driver.irq() {
+                Some(irq) => println!(
+                    "{}: source {} (prio {}), {} interrupts",
+                    entry.driver.name(),
+                    irq.source,
+                    irq.prio,
+                    entry.irqs
+                ),
+                None => println!("{}: no interrupts", entry.driver.name()),
+            }
+        }
+    }
+}
+
+/// The AIC handler of every claimed line. Asks every driver on the line
+extern "aapcs" fn dispatch(threads: &mut ThreadList, source: usize) {
+    trace!("Interrupt on source {source}");
+    let mut handled = false;
+    for entry in get_registry().entries.iter_mut().filter_map(|e| e.as_mut()) {
+        if entry.driver.irq().map(|irq| irq.source) == Some(source)
+            && entry.driver.handle_irq(threads)
+        {
+            entry.irqs += 1;
+            handled = true;
+        }
+    }
+    if !handled {
+        warn!("unknown interrupt on source {source}")
+    }
+}
@@ -1,42 +1,197 @@
 //! Beschreibt die grundsätzliche Struktur einer seriellen Schnittstelle
-//! Ist aber auf die DBGU (Debug-Unit) zugeschnitten
-//! Input ist interrupt getrieben aufgebaut
+//! Die DBGU (Debug-Unit) und die vier USARTs haben dieselben Register am Anfang,
+//! deshalb funktioniert [Serial] für alle. Jede ist ein TTY für den User-Space:
+//! 0 ist die DBGU, 1 - 4 sind USART0 - USART3.
+//! Input ist interrupt getrieben aufgebaut. Lange Ausgaben (kernel log, Write-Syscall)
+//! gehen über eine Warteschlange und den PDC, ohne die CPU zu beschäftigen.
 
-use core::fmt::Write;
+use super::{
+    emac::Emac,
+    exceptions::{SrcType, PRIO_LOWEST, PRIO_SERIAL},
+    gpio::{Peripheral, Pin, Pio, Port, PORTS},
+    mci::Mci,
+    pdc::Pdc,
+    power_management::PMC,
+    registry::get_registry,
+    spi::Spi,
+    timer_counter::{TimerCounter, CHANNEL_NUMBER},
+    twi::Twi,
+    Driver, Irq,
+};
+use crate::{
+    consts::{GDB_STUB, USARTS},
+    gdb, net, println,
+    thread::{State::*, ThreadList},
+    util::without_interrupts,
+    warn,
+};
+use core::{fmt::Write, ptr::addr_of_mut};
 use volatile_register::{RO, RW, WO};
 
 // consts
 pub const DBGU_ADDR: u32 = 0xFFFFF200;
+pub const USART_ADDR: [u32; 4] = [0xFFFC_0000, 0xFFFC_4000, 0xFFFC_8000, 0xFFFC_C000];
+/// The peripheral id (and AIC source) of USART0. The others follow
+const USART0_ID: usize = 6;
+/// The DBGU and the four USARTs
+pub const TTY_NUMBER: usize = 5;
 pub const RXRDY: u32 = 1 << 0;
 pub const TXRDY: u32 = 1 << 1;
+/// The PDC has received everything
+pub const ENDRX: u32 = 1 << 3;
+/// The PDC has sent everything
+pub const ENDTX: u32 = 1 << 4;
+pub const RSTRX: u32 = 1 << 2;
+pub const RSTTX: u32 = 1 << 3;
 pub const RXEN: u32 = 1 << 4;
+pub const RXDIS: u32 = 1 << 5;
 pub const TXEN: u32 = 1 << 6;
+pub const TXDIS: u32 = 1 << 7;
+/// Reset Status Bits (the errors below)
+pub const RSTSTA: u32 = 1 << 8;
+pub const OVRE: u32 = 1 << 5;
+pub const FRAME: u32 = 1 << 6;
+pub const PARE: u32 = 1 << 7;
+// USART mode register
+const MODE_NORMAL: u32 = 0;
+const MODE_HW_HANDSHAKING: u32 = 2;
+const CHRL_8: u32 = 3 << 6;
+const PAR_NONE: u32 = 4 << 9;
 
 #[repr(C)]
 pub struct Serial {
-    // p. 330
+    // p. 330 (DBGU), p. 401 (USART)
     pub control: WO<u32>,
-    _mode: RW<u32>,
+    pub mode: RW<u32>,
     pub int_enable: WO<u32>,
     pub int_disable: WO<u32>,
     pub int_mask: RO<u32>,
     pub status: RO<u32>,
     pub receive: RO<u32>,
     pub transmit: WO<u32>,
+    pub baud_rate: RW<u32>,
 }
 
+/// How a USART is configured on registration, see consts.rs
+#[derive(Clone, Copy, Debug)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    /// Hardware handshaking with RTS and CTS
+    pub flow_control: bool,
+}
+
+/// The pins of a USART: TXD, RXD, RTS, CTS.
+/// RTS2 and CTS2 are the same pins as the DBGU, so USART2 can't use flow control
+const USART_PINS: [[(Pin, Peripheral); 4]; 4] = [
+    [
+        (Pin::new(Port::A, 17), Peripheral::A),
+        (Pin::new(Port::A, 18), Peripheral::A),
+        (Pin::new(Port::A, 21), Peripheral::A),
+        (Pin::new(Port::A, 20), Peripheral::A),
+    ],
+    [
+        (Pin::new(Port::B, 20), Peripheral::A),
+        (Pin::new(Port::B, 21), Peripheral::A),
+        (Pin::new(Port::B, 26), Peripheral::A),
+        (Pin::new(Port::B, 24), Peripheral::A),
+    ],
+    [
+        (Pin::new(Port::A, 23), Peripheral::A),
+        (Pin::new(Port::A, 22), Peripheral::A),
+        (Pin::new(Port::A, 30), Peripheral::B),
+        (Pin::new(Port::A, 31), Peripheral::B),
+    ],
+    [
+        (Pin::new(Port::A, 5), Peripheral::B),
+        (Pin::new(Port::A, 6), Peripheral::B),
+        (Pin::new(Port::B, 0), Peripheral::B),
+        (Pin::new(Port::B, 1), Peripheral::B),
+    ],
+];
+
+/// Received chars that no thread waited for
+struct RxBuffer {
+    buffer: [u8; 64],
+    head: usize,
+    tail: usize,
+}
+
+impl RxBuffer {
+    fn push(&mut self, char: u8) {
+        if self.head - self.tail == self.buffer.len() {
+            // full, the oldest char is dropped
+            self.tail += 1;
+        }
+        self.buffer[self.head % self.buffer.len()] = char;
+        self.head += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.head == self.tail {
+            return None;
+        }
+        let char = self.buffer[self.tail % self.buffer.len()];
+        self.tail += 1;
+        Some(char)
+    }
+}
+
+const EMPTY_BUFFER: RxBuffer = RxBuffer {
+    buffer: [0; 64],
+    head: 0,
+    tail: 0,
+};
+
+static mut RX_BUFFERS: [RxBuffer; TTY_NUMBER] = [EMPTY_BUFFER; TTY_NUMBER];
+
+const TX_QUEUE_SIZE: usize = 1024;
+
+/// Bytes that wait for the PDC. Positions count all bytes ever queued
+struct TxQueue {
+    buffer: [u8; TX_QUEUE_SIZE],
+    /// Where the next byte is queued
+    head: usize,
+    /// The first byte that wasn't sent yet
+    tail: usize,
+    /// How many bytes from tail on the PDC currently sends
+    sending: usize,
+}
+
+const EMPTY_QUEUE: TxQueue = TxQueue {
+    buffer: [0; TX_QUEUE_SIZE],
+    head: 0,
+    tail: 0,
+    sending: 0,
+};
+
+static mut TX_QUEUES: [TxQueue; TTY_NUMBER] = [EMPTY_QUEUE; TTY_NUMBER];
+
+/// The length of the running DMA receive, 0 if there is none. See Serial::receive_dma
+static mut RECEIVING: [usize; TTY_NUMBER] = [0; TTY_NUMBER];
+
 impl Serial {
+    /// The DBGU, our main serial interface
     #[inline(always)]
     pub fn new() -> &'static mut Serial {
         unsafe { &mut *(DBGU_ADDR as *mut Serial) }
     }
 
-    #[inline(always)]
-    pub fn init(&mut self) -> &mut Self {
-        unsafe {
-            self.control.write(RXEN | TXEN);
+    /// The serial interface of the given TTY
+    pub fn tty(tty: usize) -> Option<&'static mut Serial> {
+        match tty {
+            0 => Some(Serial::new()),
+            1..TTY_NUMBER => Some(unsafe { &mut *(USART_ADDR[tty - 1] as *mut Serial) }),
+            _ => None,
         }
-        self
+    }
+
+    /// The TTY number of this interface, derived from its address
+    fn tty_number(&self) -> usize {
+        let addr = self as *const Serial as u32;
+        USART_ADDR
+            .iter()
+            .position(|&usart| usart == addr)
+            .map_or(0, |usart| usart + 1)
     }
 
     #[inline(always)]
@@ -65,14 +220,288 @@ impl Serial {
         self.receive.read() as u8
     }
 
-    /// Schreibt einen char
+    /// Schreibt einen char.
+    /// Vorher wird die Warteschlange geleert, damit die Reihenfolge stimmt
     #[inline(always)]
     pub fn write(&self, char: u8) {
+        self.drain();
         while !self.tx_ready() {}
         unsafe {
             self.transmit.write(char.into());
         }
     }
+
+    /// The PDC channel of this interface
+    #[inline(always)]
+    fn pdc(&self) -> &'static mut Pdc {
+        Pdc::of(self as *const Serial as u32)
+    }
+
+    #[inline(always)]
+    fn tx_queue(&self) -> &'static mut TxQueue {
+        unsafe { &mut (*addr_of_mut!(TX_QUEUES))[self.tty_number()] }
+    }
+
+    /// Queues as many bytes as fit and lets the PDC send them.
+    /// Returns how many bytes were queued
+    pub fn write_buffered(&mut self, bytes: &[u8]) -> usize {
+        without_interrupts(|| {
+            let queue = self.tx_queue();
+            let free = TX_QUEUE_SIZE - (queue.head - queue.tail);
+            let count = bytes.len().min(free);
+            for &byte in &bytes[..count] {
+                queue.buffer[queue.head % TX_QUEUE_SIZE] = byte;
+                queue.head += 1;
+            }
+            if queue.sending == 0 {
+                self.start_transmit();
+            }
+            count
+        })
+    }
+
+    /// Lets the PDC send the next contiguous part of the queue.
+    /// Interrupts must be masked
+    fn start_transmit(&self) {
+        let queue = self.tx_queue();
+        let start = queue.tail % TX_QUEUE_SIZE;
+        queue.sending = (queue.head - queue.tail).min(TX_QUEUE_SIZE - start);
+        if queue.sending == 0 {
+            unsafe { self.int_disable.write(ENDTX) };
+            return;
+        }
+        self.pdc()
+            .transmit(&queue.buffer[start..start + queue.sending]);
+        unsafe { self.int_enable.write(ENDTX) };
+    }
+
+    /// The PDC is done with the current part. Interrupts must be masked
+    fn transmit_done(&self) {
+        let queue = self.tx_queue();
+        queue.tail += queue.sending;
+        queue.sending = 0;
+        self.start_transmit();
+    }
+
+    /// Waits until the queue is sent, without relying on interrupts
+    pub fn drain(&self) {
+        let queue = self.tx_queue();
+        if queue.head == queue.tail {
+            return;
+        }
+        without_interrupts(|| {
+            // transmit_done changes the queue, so it is fetched again every time
+            while self.tx_queue().sending != 0 {
+                while self.pdc().transmitting() {}
+                self.transmit_done();
+            }
+        })
+    }
+
+    /// Receives exactly buf.len() bytes with the PDC instead of the per char interrupt.
+    /// Until buf is filled, the chars don't reach the threads (or the ctrl keys of the dbgu).
+    /// The ENDRX interrupt wakes the threads that wait for this driver
+    pub fn receive_dma(&mut self, buf: &mut [u8]) -> Result<(), &'static str> {
+        let tty = self.tty_number();
+        without_interrupts(|| unsafe {
+            if RECEIVING[tty] != 0 {
+                return Err("A receive is already running");
+            }
+            RECEIVING[tty] = buf.len();
+            self.int_disable.write(RXRDY);
+            self.pdc().receive(buf);
+            self.int_enable.write(ENDRX);
+            Ok(())
+        })
+    }
+
+    /// Sets 8N1 with the given baud rate, which is derived from the master clock.
+    /// Flow control is only possible on USARTs
+    pub fn configure(&mut self, config: SerialConfig) {
+        let clock = PMC::new().master_clock_hz();
+        let divisor = (clock + 8 * config.baud_rate) / (16 * config.baud_rate);
+        let mode = match config.flow_control {
+            true => MODE_HW_HANDSHAKING,
+            false => MODE_NORMAL,
+        };
+        unsafe {
+            self.control.write(RSTRX | RSTTX | RXDIS | TXDIS);
+            self.mode.write(mode | CHRL_8 | PAR_NONE);
+            self.baud_rate.write(divisor);
+            self.control.write(RSTSTA | RXEN | TXEN);
+        }
+    }
+
+    /// The pins of the USART, RTS and CTS only with flow control. The DBGU pins are set by the boot loader
+    fn pins(&self) -> &'static [(Pin, Peripheral)] {
+        match self.tty_number() {
+            0 => &[],
+            tty if USARTS[tty - 1].flow_control && tty != 3 => &USART_PINS[tty - 1],
+            tty => &USART_PINS[tty - 1][..2],
+        }
+    }
+
+    /// Gives the received char to a thread waiting for this TTY or buffers it
+    fn receive_char(&self, threads: &mut ThreadList, char: u8) {
+        let tty = self.tty_number();
+        match threads
+            .iter_mut()
+            .find(|thread| thread.state == WaitingForChar(tty))
+        {
+            Some(thread) => {
+                thread.regs.r0 = char as u32;
+                thread.state = Ready;
+            }
+            None => unsafe { RX_BUFFERS[tty].push(char) },
+        }
+    }
+}
+
+/// Executes the Receive syscall: Fills the buffer from the TTY with the PDC,
+/// the thread waits until it is full
+pub fn user_receive(
+    threads: &mut ThreadList,
+    tty: usize,
+    buf: &mut [u8],
+) -> Result<(), &'static str> {
+    let serial = Serial::tty(tty).ok_or("Unknown tty")?;
+    if buf.is_empty() {
+        return Err("Empty buffer");
+    }
+    serial.receive_dma(buf)?;
+    threads.curr_mut_thread().state = WaitingForDevice(serial.name());
+    Ok(())
+}
+
+/// A char that was received on the TTY before anyone asked for it
+pub fn buffered_char(tty: usize) -> Option<u8> {
+    unsafe { (*addr_of_mut!(RX_BUFFERS)).get_mut(tty)?.pop() }
+}
+
+impl Driver for Serial {
+    fn name(&self) -> &'static str {
+        ["dbgu", "usart0", "usart1", "usart2", "usart3"][self.tty_number()]
+    }
+
+    fn init(&mut self) {
+        let tty = self.tty_number();
+        if tty != 0 {
+            let usart = tty - 1;
+            let config = USARTS[usart];
+            for &(pin, peripheral) in self.pins() {
+                pin.peripheral(peripheral);
+            }
+            self.configure(SerialConfig {
+                flow_control: config.flow_control && usart != 2,
+                ..config
+            });
+        } else {
+            unsafe {
+                self.control.write(RXEN | TXEN);
+            }
+        }
+        self.enable_interrupts();
+    }
+
+    fn irq(&self) -> Option<Irq> {
+        match self.peripheral_id() {
+            Some(id) => Some(Irq {
+                source: id,
+                prio: PRIO_SERIAL,
+                src_type: SrcType::HighLevelSens,
+            }),
+            // Die DBGU teilt sich die Leitung mit den anderen System-Peripherien
+            None => Some(Irq {
+                source: 1,
+                prio: PRIO_LOWEST,
+                src_type: SrcType::LowLevelSens,
+            }),
+        }
+    }
+
+    fn handle_irq(&mut self, threads: &mut ThreadList) -> bool {
+        let status = self.status.read();
+        let pending = status & self.int_mask.read();
+        let mut handled = false;
+        if status & (OVRE | FRAME | PARE) != 0 {
+            warn!("{}: receive error {:x}", self.name(), status);
+            unsafe { self.control.write(RSTSTA) };
+            handled = true;
+        }
+        if pending & ENDTX != 0 {
+            self.transmit_done();
+            handled = true;
+        }
+        if pending & ENDRX != 0 {
+            let tty = self.tty_number();
+            let len = unsafe { RECEIVING[tty] };
+            unsafe {
+                RECEIVING[tty] = 0;
+                self.int_disable.write(ENDRX);
+            }
+            self.enable_interrupts();
+            for thread in threads.iter_mut() {
+                if thread.state == WaitingForDevice(self.name()) {
+                    thread.regs.r0 = len as u32;
+                    thread.state = Ready;
+                }
+            }
+            handled = true;
+        }
+        if pending & RXRDY == 0 {
+            return handled;
+        }
+        let char = self.read();
+        if self.tty_number() == 0 {
+            if GDB_STUB && (char == 3 || char == b'$') {
+                // gdb wants to talk to us
+                gdb::enter(threads, char);
+                return true;
+            }
+            if char == 4 {
+                // ctrl+d is debug print
+                println!("{threads:#?}");
+                threads.print_stats();
+                crate::kernel_stack::report();
+                get_registry().print();
+                PMC::new().print();
+                for port in PORTS {
+                    Pio::new(port).print();
+                }
+                for channel in 0..CHANNEL_NUMBER {
+                    TimerCounter::new(channel).print();
+                }
+                Spi::new().print();
+                Twi::new().print();
+                Emac::new().print();
+                Mci::new().print();
+                net::arp::print();
+                return true;
+            }
+        }
+        self.receive_char(threads, char);
+        true
+    }
+
+    fn peripheral_id(&self) -> Option<usize> {
+        match self.tty_number() {
+            0 => None,
+            tty => Some(USART0_ID + tty - 1),
+        }
+    }
+
+    fn owns(&self, pin: Pin) -> bool {
+        self.pins().iter().any(|&(own, _)| own == pin)
+    }
+
+    fn shutdown(&mut self) {
+        self.drain();
+        self.pdc().stop();
+        unsafe {
+            self.int_disable.write(u32::MAX);
+            self.control.write(RXDIS | TXDIS);
+        }
+    }
 }
 
 impl Write for Serial {
@@ -0,0 +1,321 @@
+//! Der SPI-Master
+//!
+//! Bis zu vier Geräte hängen an MISO, MOSI und SPCK, jedes mit eigenem Chip-Select (NPCS0 - NPCS3).
+//! Die Chip-Selects steuern wir selbst als GPIOs, damit sie über eine ganze Übertragung aktiv bleiben.
+//! Übertragungen sind full duplex: Jedes gesendete Byte wird im Puffer durch das empfangene ersetzt.
+//! Sie laufen entweder gepollt, byteweise im Interrupt oder über den PDC, je nach Konfiguration des Geräts.
+//! NPCS2 und NPCS3 teilen sich die Pins mit USART3 und lassen sich nur nutzen, wenn dieser nicht registriert ist.
+
+use super::{
+    exceptions::{SrcType, PRIO_DEVICE},
+    gpio::{Peripheral, Pin, Port},
+    pdc::Pdc,
+    power_management::PMC,
+    registry::get_registry,
+    Driver, Irq,
+};
+use crate::{
+    consts::SPI_LOOPBACK,
+    println,
+    thread::{State::*, ThreadList},
+    util::without_interrupts,
+};
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    slice,
+};
+use volatile_register::{RO, RW, WO};
+
+pub struct Spi {
+    // p. 357
+    pub ctrl: WO<u32>,
+    pub mode: RW<u32>,
+    pub receive: RO<u32>,
+    pub transmit: WO<u32>,
+    pub status: RO<u32>,
+    pub int_enable: WO<u32>,
+    pub int_disable: WO<u32>,
+    pub int_mask: RO<u32>,
+    _reserved: [u32; 4],
+    pub chip_select: [RW<u32>; 4],
+}
+
+const SPI_ADDR: u32 = 0xFFFE_0000;
+const SPI_ID: usize = 13;
+// Control
+const SPIEN: u32 = 1 << 0;
+const SPIDIS: u32 = 1 << 1;
+const SWRST: u32 = 1 << 7;
+// Mode
+const MSTR: u32 = 1 << 0;
+const MODFDIS: u32 = 1 << 4;
+const LLB: u32 = 1 << 7;
+const PCS: u32 = 16;
+// Status
+const RDRF: u32 = 1 << 0;
+const TDRE: u32 = 1 << 1;
+const ENDRX: u32 = 1 << 4;
+// Chip Select Registers
+const CPOL: u32 = 1 << 0;
+const NCPHA: u32 = 1 << 1;
+const SCBR: u32 = 8;
+
+const MISO: Pin = Pin::new(Port::A, 0);
+const MOSI: Pin = Pin::new(Port::A, 1);
+const SPCK: Pin = Pin::new(Port::A, 2);
+const NPCS: [Pin; 4] = [
+    Pin::new(Port::A, 3),
+    Pin::new(Port::A, 4),
+    Pin::new(Port::A, 5),
+    Pin::new(Port::A, 6),
+];
+
+/// A device on the bus
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpiDevice {
+    /// The chip select 0 - 3
+    pub cs: u8,
+    /// The SPI mode 0 - 3 (bit 1: clock polarity, bit 0: clock phase)
+    pub mode: u8,
+    pub clock_hz: u32,
+    /// How transfers with the device are carried out
+    pub transfer: TransferMode,
+}
+
+/// How a transfer is carried out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferMode {
+    /// The PDC moves the bytes, the end is an interrupt
+    Dma = 0,
+    /// Every byte is an interrupt
+    Interrupt = 1,
+    /// The cpu waits for every byte. Only worth it for a few bytes
+    Polled = 2,
+}
+
+/// The running interrupt or DMA transfer
+struct Transfer {
+    buf: *mut u8,
+    len: usize,
+    /// The next byte that is received
+    pos: usize,
+    mode: TransferMode,
+    cs: u8,
+}
+
+static mut TRANSFER: Option<Transfer> = None;
+
+impl Spi {
+    #[inline(always)]
+    pub fn new() -> &'static mut Spi {
+        unsafe { &mut *(SPI_ADDR as *mut Spi) }
+    }
+
+    /// Sets mode and clock of the device in its chip select register
+    fn configure(&mut self, device: &SpiDevice) {
+        let mck = PMC::new().master_clock_hz();
+        // SPCK = MCK / (2 * SCBR), rounded so that the device isn't clocked too fast
+        let scbr = mck.div_ceil(2 * device.clock_hz.max(1)).clamp(1, 255);
+        let mut csr = scbr << SCBR;
+        if device.mode & 0b10 != 0 {
+            csr |= CPOL;
+        }
+        if device.mode & 0b01 == 0 {
+            csr |= NCPHA;
+        }
+        unsafe {
+            self.chip_select[device.cs as usize].write(csr);
+            // The PCS field decides which chip select register is used: the device's bit is 0
+            self.mode
+                .modify(|mode| (mode & !(0xF << PCS)) | ((!(1 << device.cs) & 0xF) << PCS));
+        }
+        // The chip select only becomes a GPIO when it is used, NPCS2 and NPCS3 might belong to USART3
+        // (transfer checks that)
+        NPCS[device.cs as usize].output(false);
+    }
+
+    #[inline(always)]
+    fn deselect(cs: u8) {
+        NPCS[cs as usize].set(true);
+    }
+
+    /// Transfers the buffer to the device and replaces it with the answer, in the transfer mode of the device.
+    /// Polled returns when the transfer is done, the other modes only start it.
+    /// Then the buffer must stay valid until `busy` is false
+    pub fn transfer(&mut self, device: &SpiDevice, buf: &mut [u8]) -> Result<(), &'static str> {
+        if device.cs > 3 {
+            return Err("Unknown chip select");
+        }
+        if get_registry().owner(NPCS[device.cs as usize]).is_some() {
+            return Err("NPCS2/3 belong to USART3");
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+        without_interrupts(|| {
+            if self.busy() {
+                return Err("SPI is busy");
+            }
+            self.configure(device);
+            match device.transfer {
+                TransferMode::Polled => {
+                    for byte in buf.iter_mut() {
+                        while self.status.read() & TDRE == 0 {}
+                        unsafe { self.transmit.write(*byte as u32) };
+                        while self.status.read() & RDRF == 0 {}
+                        *byte = self.receive.read() as u8;
+                    }
+                    Spi::deselect(device.cs);
+                    return Ok(());
+                }
+                TransferMode::Interrupt => {
+                    // clear an old byte
+                    self.receive.read();
+                    unsafe {
+                        self.transmit.write(buf[0] as u32);
+                        self.int_enable.write(RDRF);
+                    }
+                }
+                TransferMode::Dma => {
+                    let pdc = Pdc::of(SPI_ADDR);
+                    // Both directions use the buffer. A byte is received only after it was sent
+                    unsafe {
+                        pdc.receive(slice::from_raw_parts_mut(buf.as_mut_ptr(), buf.len()));
+                        pdc.transmit(slice::from_raw_parts(buf.as_ptr(), buf.len()));
+                        self.int_enable.write(ENDRX);
+                    }
+                }
+            }
+            unsafe {
+                TRANSFER = Some(Transfer {
+                    buf: buf.as_mut_ptr(),
+                    len: buf.len(),
+                    pos: 0,
+                    mode: device.transfer,
+                    cs: device.cs,
+                })
+            };
+            Ok(())
+        })
+    }
+
+    /// Whether an interrupt or DMA transfer is running
+    #[inline(always)]
+    pub fn busy(&self) -> bool {
+        unsafe { (*addr_of!(TRANSFER)).is_some() }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "spi: mode {:08x}, status {:08x}, busy {}",
+            self.mode.read(),
+            self.status.read(),
+            self.busy()
+        );
+    }
+}
+
+/// Executes the Spi syscall. Returns the number of transferred bytes if the transfer is already done (Polled),
+/// otherwise the thread waits for the end of the transfer
+pub fn user_transfer(
+    threads: &mut ThreadList,
+    device: u32,
+    clock_hz: u32,
+    buf: &mut [u8],
+) -> Result<Option<usize>, &'static str> {
+    if buf.is_empty() {
+        return Err("Empty buffer");
+    }
+    let transfer = match (device >> 4) & 0b11 {
+        0 => TransferMode::Dma,
+        1 => TransferMode::Interrupt,
+        2 => TransferMode::Polled,
+        _ => return Err("Unknown transfer mode"),
+    };
+    let device = SpiDevice {
+        cs: (device & 0b11) as u8,
+        mode: ((device >> 2) & 0b11) as u8,
+        clock_hz,
+        transfer,
+    };
+    Spi::new().transfer(&device, buf)?;
+    if transfer == TransferMode::Polled {
+        return Ok(Some(buf.len()));
+    }
+    threads.curr_mut_thread().state = WaitingForDevice("spi");
+    Ok(None)
+}
+
+impl Driver for Spi {
+    fn name(&self) -> &'static str {
+        "spi"
+    }
+
+    fn init(&mut self) {
+        for pin in [MISO, MOSI, SPCK] {
+            pin.peripheral(Peripheral::A);
+        }
+        let loopback = match SPI_LOOPBACK {
+            true => LLB,
+            false => 0,
+        };
+        unsafe {
+            self.ctrl.write(SWRST);
+            self.mode.write(MSTR | MODFDIS | loopback | (0xF << PCS));
+            self.int_disable.write(u32::MAX);
+            self.ctrl.write(SPIEN);
+        }
+    }
+
+    fn irq(&self) -> Option<Irq> {
+        Some(Irq {
+            source: SPI_ID,
+            prio: PRIO_DEVICE,
+            src_type: SrcType::HighLevelSens,
+        })
+    }
+
+    fn handle_irq(&mut self, threads: &mut ThreadList) -> bool {
+        let pending = self.status.read() & self.int_mask.read();
+        let Some(transfer) = (unsafe { (*addr_of_mut!(TRANSFER)).as_mut() }) else {
+            return false;
+        };
+        match transfer.mode {
+            TransferMode::Interrupt if pending & RDRF != 0 => {
+                let buf = unsafe { slice::from_raw_parts_mut(transfer.buf, transfer.len) };
+                buf[transfer.pos] = self.receive.read() as u8;
+                transfer.pos += 1;
+                if transfer.pos < transfer.len {
+                    unsafe { self.transmit.write(buf[transfer.pos] as u32) };
+                    return true;
+                }
+            }
+            TransferMode::Dma if pending & ENDRX != 0 => transfer.pos = transfer.len,
+            _ => return false,
+        }
+        // done
+        unsafe { self.int_disable.write(RDRF | ENDRX) };
+        Spi::deselect(transfer.cs);
+        let len = transfer.len;
+        unsafe { TRANSFER = None };
+        for thread in threads.iter_mut() {
+            if thread.state == WaitingForDevice("spi") {
+                thread.regs.r0 = len as u32;
+                thread.state = Ready;
+            }
+        }
+        true
+    }
+
+    fn peripheral_id(&self) -> Option<usize> {
+        Some(SPI_ID)
+    }
+
+    fn shutdown(&mut self) {
+        Pdc::of(SPI_ADDR).stop();
+        unsafe {
+            self.int_disable.write(u32::MAX);
+            self.ctrl.write(SPIDIS);
+        }
+    }
+}
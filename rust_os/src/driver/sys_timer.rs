@@ -1,18 +1,76 @@
 //! Der System-Timer-Driver
+//!
+//! Neben dem periodischen Timer für die Zeitscheiben nutzen wir den Real-time Timer als Uhr
+//! (siehe clock.rs) und seinen Alarm für die Weckzeiten der schlafenden Threads.
+//! Im tickless mode läuft der periodische Timer nur, wenn ein anderer Thread als idle läuft.
 
+use super::{
+    exceptions::{SrcType, PRIO_LOWEST},
+    led, watchdog, Driver, Irq,
+};
+use crate::{
+    clock,
+    consts::TIME_SLICE,
+    net,
+    thread::{State::*, ThreadList},
+    trace,
+};
+use core::cmp::min;
 use volatile_register::{RO, RW, WO};
 
 const ST_ADDR: u32 = 0xFFFF_FD00;
+/// Period Interval Timer Status
+const PITS: u32 = 1 << 0;
+/// Alarm Status
+const ALMS: u32 = 1 << 3;
+/// The real-time timer counts every RTPRES slow clock cycles
+const RTPRES: u32 = 32;
+/// Ticks of the real-time timer per second
+pub const RTT_HZ: u32 = 32768 / RTPRES;
+/// The real-time timer has only 20 bits
+const RTT_MASK: u32 = (1 << 20) - 1;
+/// Watchdog Timer Restart
+const WDRST: u32 = 1 << 0;
+/// Watchdog Reset Enable
+const RSTEN: u32 = 1 << 16;
+/// Watchdog External Signal Enable
+const EXTEN: u32 = 1 << 17;
 
 pub struct SysTimer {
     // p. 296
-    pub ctrl: u32,
+    pub ctrl: WO<u32>,
     pub interval_mode: RW<u32>,
-    _unused: [u32; 2],
+    pub watchdog_mode: RW<u32>,
+    pub rt_mode: RW<u32>,
     pub status: RO<u32>,
     pub int_enable: WO<u32>,
     pub int_disable: WO<u32>,
     pub int_mask: RO<u32>,
+    pub rt_alarm: RW<u32>,
+    pub rt_current: RO<u32>,
+}
+
+/// The number of real-time timer ticks from `from` to `to`, respecting the overflow
+#[inline(always)]
+pub fn ticks_between(from: u32, to: u32) -> u32 {
+    to.wrapping_sub(from) & RTT_MASK
+}
+
+/// Whether the deadline is reached at `now`.
+/// Deadlines can be at most half the range of the real-time timer (about 8 minutes) away
+#[inline(always)]
+pub fn is_due(deadline: u32, now: u32) -> bool {
+    ticks_between(deadline, now) < RTT_MASK / 2
+}
+
+#[inline(always)]
+pub fn ms_to_ticks(ms: u32) -> u32 {
+    min(ms as u64 * RTT_HZ as u64 / 1000, (RTT_MASK / 2) as u64) as u32
+}
+
+#[inline(always)]
+pub fn ticks_to_ms(ticks: u32) -> u32 {
+    (ticks as u64 * 1000 / RTT_HZ as u64) as u32
 }
 
 impl SysTimer {
@@ -21,14 +79,6 @@ impl SysTimer {
         unsafe { &mut *(ST_ADDR as *mut SysTimer) }
     }
 
-    #[inline(always)]
-    pub fn init(&mut self) -> &mut Self {
-        unsafe {
-            self.int_enable.write(1);
-        }
-        self
-    }
-
     /// Sets the interval of the period clock
     #[inline(always)]
     pub fn set_interval(&mut self, interval: u16) {
@@ -36,4 +86,125 @@ impl SysTimer {
         // not affected by power management and slow clock mode
         unsafe { self.interval_mode.write(interval as u32) }
     }
+
+    /// The current value of the real-time timer.
+    /// It runs asynchronously, so we read until two reads agree
+    #[inline(always)]
+    pub fn now(&self) -> u32 {
+        loop {
+            let value = self.rt_current.read();
+            if value == self.rt_current.read() {
+                return value & RTT_MASK;
+            }
+        }
+    }
+
+    /// The tick at which something that should happen in ms milliseconds is due
+    #[inline(always)]
+    pub fn deadline_in(&self, ms: u32) -> u32 {
+        (self.now() + ms_to_ticks(ms)) & RTT_MASK
+    }
+
+    /// Programs the timer interrupts for the next scheduling decision.
+    /// The alarm fires at the given deadline. The periodic interrupt is only needed
+    /// if a thread (and not the idle thread) runs
+    pub fn program(&mut self, deadline: Option<u32>, idle: bool) {
+        unsafe {
+            match deadline {
+                Some(deadline) => {
+                    // The alarm only fires on equality, a deadline in the past would be missed
+                    let now = self.now();
+                    let deadline = if is_due(deadline, now) {
+                        (now + 2) & RTT_MASK
+                    } else {
+                        deadline
+                    };
+                    self.rt_alarm.write(deadline);
+                    self.int_enable.write(ALMS);
+                }
+                None => self.int_disable.write(ALMS),
+            }
+            if idle {
+                self.int_disable.write(PITS);
+            } else {
+                self.int_enable.write(PITS);
+            }
+        }
+    }
+
+    /// Resets the board after the given number of watchdog ticks (256 Hz).
+    /// The AT91RM9200 has no own reset controller, a reset is done by the watchdog of the sys timer
+    pub fn reset_after(&mut self, ticks: u16) -> ! {
+        self.arm_reset(ticks);
+        crate::util::idle()
+    }
+
+    /// Like reset_after, but returns, so that something can be done until the reset
+    pub fn arm_reset(&mut self, ticks: u16) {
+        unsafe {
+            self.watchdog_mode
+                .write(RSTEN | EXTEN | ticks.max(1) as u32);
+            self.ctrl.write(WDRST);
+        }
+    }
+
+    /// Resets the board immediately
+    pub fn reset(&mut self) -> ! {
+        self.reset_after(1)
+    }
+
+    /// Restarts the watchdog counter
+    #[inline(always)]
+    pub fn pet(&mut self) {
+        unsafe { self.ctrl.write(WDRST) }
+    }
+
+    /// Lets the watchdog run without resetting the board
+    pub fn disarm_reset(&mut self) {
+        unsafe { self.watchdog_mode.write(u16::MAX as u32) }
+    }
+}
+
+impl Driver for SysTimer {
+    fn name(&self) -> &'static str {
+        "sys_timer"
+    }
+
+    fn init(&mut self) {
+        unsafe {
+            // This also restarts the real-time timer at 0
+            self.rt_mode.write(RTPRES);
+            self.int_enable.write(PITS);
+        }
+        clock::restart();
+        self.set_interval(TIME_SLICE as u16);
+    }
+
+    fn irq(&self) -> Option<Irq> {
+        Some(Irq {
+            source: 1,
+            prio: PRIO_LOWEST,
+            src_type: SrcType::LowLevelSens,
+        })
+    }
+
+    fn handle_irq(&mut self, threads: &mut ThreadList) -> bool {
+        // Reading the status also clears it
+        if self.status.read() & (PITS | ALMS) == 0 {
+            return false;
+        }
+        let now = self.now();
+        clock::tick(now);
+        led::heartbeat(now);
+        watchdog::tick(now);
+        net::tick(now);
+        for thread in threads.array.iter_mut().filter_map(|x| x.as_mut()) {
+            match thread.state {
+                Sleeping(deadline) if is_due(deadline, now) => thread.state = Ready,
+                _ => (),
+            }
+        }
+        trace!("tick");
+        true
+    }
 }
@@ -0,0 +1,387 @@
+//! Die Timer/Counter (TC0 - TC5)
+//!
+//! Jeder Kanal ist ein 16-Bit-Zähler, der entweder Flanken an TIOA/TIOB misst (capture mode)
+//! oder dort Signale erzeugt (waveform mode, z.B. PWM).
+//! TC0 und TC1 bilden zusammen einen freilaufenden 32-Bit-Zähler mit MCK/8:
+//! TC0 zählt, TC1 zählt die Überläufe von TC0. Er ist die Uhr für `util::delay_us` und fürs Profiling.
+//! TC2 - TC5 sind frei für PWM und Messungen.
+
+use super::{
+    exceptions::{SrcType, PRIO_DEVICE},
+    gpio::{Peripheral, Pin, Port},
+    power_management::PMC,
+    registry::get_registry,
+    Driver, Irq,
+};
+use crate::{
+    println,
+    thread::{State::*, ThreadList},
+};
+use volatile_register::{RO, RW, WO};
+
+pub struct TimerCounter {
+    // p. 437
+    pub ctrl: WO<u32>,
+    pub mode: RW<u32>,
+    _reserved0: [u32; 2],
+    pub counter: RO<u32>,
+    pub ra: RW<u32>,
+    pub rb: RW<u32>,
+    pub rc: RW<u32>,
+    pub status: RO<u32>,
+    pub int_enable: WO<u32>,
+    pub int_disable: WO<u32>,
+    pub int_mask: RO<u32>,
+}
+
+const TC_ADDR: [u32; 2] = [0xFFFA_0000, 0xFFFA_4000];
+/// The channels of a block are 0x40 apart
+const CHANNEL_STRIDE: u32 = 0x40;
+/// The Block Mode Register, behind the three channels
+const BMR_OFFSET: u32 = 0xC4;
+/// The peripheral id of TC0. The others follow
+const TC0_ID: usize = 17;
+pub const CHANNEL_NUMBER: usize = 6;
+
+// Channel Control Register
+const CLKEN: u32 = 1 << 0;
+const CLKDIS: u32 = 1 << 1;
+const SWTRG: u32 = 1 << 2;
+// Channel Mode Register
+const WAVE: u32 = 1 << 15;
+/// Waveform: count up to RC, then start again
+const WAVSEL_UP_RC: u32 = 2 << 13;
+/// Waveform: TIOB is an output (the external event is XC0)
+const EEVT_XC0: u32 = 1 << 10;
+/// The clock of TC1 is XC1
+const TCCLKS_XC1: u32 = 6;
+/// Block Mode: XC1 is TIOA0
+const TC1XC1S_TIOA0: u32 = 2 << 2;
+// Actions on TIOA (ACPA, ACPC) and TIOB (BCPB, BCPC)
+const SET: u32 = 1;
+const CLEAR: u32 = 2;
+const ACPA: u32 = 16;
+const ACPC: u32 = 18;
+const BCPB: u32 = 24;
+const BCPC: u32 = 26;
+/// Capture: load RA on a rising TIOA edge
+const LDRA_RISING: u32 = 1 << 16;
+/// Capture: load RB on a rising TIOA edge
+const LDRB_RISING: u32 = 1 << 18;
+// Status
+const COVFS: u32 = 1 << 0;
+const LDRBS: u32 = 1 << 6;
+
+/// The clock of the free running counter is TIMER_CLOCK2
+const COUNTER_CLOCK: Clock = Clock::Mck8;
+/// The clock of the Capture syscall. A period must fit into 16 bits of it, so about 30 Hz are the minimum
+const CAPTURE_CLOCK: Clock = Clock::Mck32;
+/// How many rounds of the counter (65536 ticks of CAPTURE_CLOCK, about 35 ms) the Capture syscall waits for two edges
+const CAPTURE_ROUNDS: u32 = 3;
+
+/// The internal clocks of the channels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    Mck2,
+    Mck8,
+    Mck32,
+    Mck128,
+    Slow,
+}
+
+impl Clock {
+    pub fn hz(self) -> u32 {
+        let mck = PMC::new().master_clock_hz();
+        match self {
+            Clock::Mck2 => mck / 2,
+            Clock::Mck8 => mck / 8,
+            Clock::Mck32 => mck / 32,
+            Clock::Mck128 => mck / 128,
+            Clock::Slow => 32768,
+        }
+    }
+}
+
+/// The two outputs of a channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tio {
+    A,
+    B,
+}
+
+/// TIOA and TIOB of every channel. TC0 - TC2 share their pins with USART0 and USART2
+const TIO_PINS: [[Pin; 2]; CHANNEL_NUMBER] = [
+    [Pin::new(Port::A, 17), Pin::new(Port::A, 18)],
+    [Pin::new(Port::A, 19), Pin::new(Port::A, 20)],
+    [Pin::new(Port::A, 21), Pin::new(Port::A, 22)],
+    [Pin::new(Port::B, 6), Pin::new(Port::B, 7)],
+    [Pin::new(Port::B, 8), Pin::new(Port::B, 9)],
+    [Pin::new(Port::B, 10), Pin::new(Port::B, 11)],
+];
+
+impl TimerCounter {
+    /// The channel 0 - 5
+    #[inline(always)]
+    pub fn new(channel: usize) -> &'static mut TimerCounter {
+        let addr = TC_ADDR[channel / 3] + (channel % 3) as u32 * CHANNEL_STRIDE;
+        unsafe { &mut *(addr as *mut TimerCounter) }
+    }
+
+    /// The channel number, derived from the address
+    fn channel(&self) -> usize {
+        let addr = self as *const TimerCounter as u32;
+        let block = TC_ADDR.iter().rposition(|&block| addr >= block).unwrap();
+        block * 3 + ((addr - TC_ADDR[block]) / CHANNEL_STRIDE) as usize
+    }
+
+    /// Enables the clock and starts counting at 0
+    #[inline(always)]
+    pub fn start(&mut self) {
+        unsafe { self.ctrl.write(CLKEN | SWTRG) }
+    }
+
+    #[inline(always)]
+    pub fn stop(&mut self) {
+        unsafe { self.ctrl.write(CLKDIS) }
+    }
+
+    /// Measures TIOA: RA is loaded on a rising edge, then RB on the next one.
+    /// RB - RA is the period of the signal
+    pub fn capture(&mut self, clock: Clock) {
+        TIO_PINS[self.channel()][0].peripheral(Peripheral::B);
+        self.stop();
+        unsafe { self.mode.write(clock as u32 | LDRA_RISING | LDRB_RISING) };
+        // clear old loads
+        self.status.read();
+        self.start();
+    }
+
+    /// Generates a PWM signal on the output, freq_hz = 0 stops it.
+    /// duty is in percent. The other output of the channel keeps its duty cycle,
+    /// but both share the frequency
+    pub fn pwm(&mut self, tio: Tio, freq_hz: u32, duty: u32) -> Result<(), &'static str> {
+        if freq_hz == 0 {
+            self.stop();
+            return Ok(());
+        }
+        // The fastest clock that can still count a whole period in 16 bits
+        let clock = [
+            Clock::Mck2,
+            Clock::Mck8,
+            Clock::Mck32,
+            Clock::Mck128,
+            Clock::Slow,
+        ]
+        .into_iter()
+        .find(|clock| clock.hz() / freq_hz <= u16::MAX as u32)
+        .ok_or("Frequency too low")?;
+        let period = clock.hz() / freq_hz;
+        if period < 2 {
+            return Err("Frequency too high");
+        }
+        let high = period * duty.min(100) / 100;
+        let old_mode = self.mode.read();
+        // The actions of the other output stay
+        let other = match old_mode & (WAVE | 0b111) == WAVE | clock as u32 {
+            true => old_mode & ((0b11 << ACPA) | (0b11 << ACPC) | (0b11 << BCPB) | (0b11 << BCPC)),
+            false => 0,
+        };
+        // The output goes up at the period start (RC) and down at the compare
+        let (actions, own_mask) = match tio {
+            Tio::A => (
+                (CLEAR << ACPA) | (SET << ACPC),
+                (0b11 << ACPA) | (0b11 << ACPC),
+            ),
+            Tio::B => (
+                (CLEAR << BCPB) | (SET << BCPC),
+                (0b11 << BCPB) | (0b11 << BCPC),
+            ),
+        };
+        self.stop();
+        unsafe {
+            self.mode.write(
+                clock as u32 | WAVE | WAVSEL_UP_RC | EEVT_XC0 | (other & !own_mask) | actions,
+            );
+            self.rc.write(period);
+            // RA/RB = 0 would never be reached, the output would always stay high
+            match tio {
+                Tio::A => self.ra.write(high.max(1)),
+                Tio::B => self.rb.write(high.max(1)),
+            }
+        }
+        TIO_PINS[self.channel()][tio as usize].peripheral(Peripheral::B);
+        self.start();
+        Ok(())
+    }
+
+    pub fn print(&self) {
+        println!(
+            "tc{}: mode {:08x}, counter {}, ra {}, rb {}, rc {}",
+            self.channel(),
+            self.mode.read(),
+            self.counter.read(),
+            self.ra.read(),
+            self.rb.read(),
+            self.rc.read()
+        );
+    }
+}
+
+static mut COUNTING: bool = false;
+/// The counter rounds every channel has waited for the edges of the Capture syscall
+static mut ROUNDS: [u32; CHANNEL_NUMBER] = [0; CHANNEL_NUMBER];
+
+/// Starts the free running counter on TC0 and TC1. They must be registered
+pub fn init_counter() {
+    let low = TimerCounter::new(0);
+    let high = TimerCounter::new(1);
+    unsafe {
+        // TC0 counts 0 - 0xFFFF and wraps. TIOA0 goes up in the middle and down at the end,
+        // so it has one rising edge per round, which TC1 counts
+        low.mode
+            .write(COUNTER_CLOCK as u32 | WAVE | (SET << ACPA) | (CLEAR << ACPC));
+        low.ra.write(0x8000);
+        low.rc.write(0xFFFF);
+        ((TC_ADDR[0] + BMR_OFFSET) as *mut u32).write_volatile(TC1XC1S_TIOA0);
+        high.mode.write(TCCLKS_XC1);
+    }
+    high.start();
+    low.start();
+    unsafe { COUNTING = true };
+}
+
+/// Whether the free running counter was started
+#[inline(always)]
+pub fn counting() -> bool {
+    unsafe { COUNTING }
+}
+
+/// The free running counter. It counts with MCK/8 and wraps after about 9 minutes
+pub fn now() -> u32 {
+    let low = TimerCounter::new(0);
+    let high = TimerCounter::new(1);
+    loop {
+        let upper = high.counter.read();
+        let lower = low.counter.read();
+        // TC1 counts when TC0 passes 0x8000, not when it wraps
+        let rounds = match lower < 0x8000 {
+            true => upper,
+            false => upper.wrapping_sub(1),
+        };
+        if high.counter.read() == upper {
+            return ((rounds & 0xFFFF) << 16) | lower;
+        }
+    }
+}
+
+/// Converts counter ticks into µs, e.g. to profile a piece of code:
+/// `let start = now(); ...; ticks_to_us(now().wrapping_sub(start))`
+#[inline(always)]
+pub fn ticks_to_us(ticks: u32) -> u32 {
+    (ticks as u64 * 1_000_000 / COUNTER_CLOCK.hz() as u64) as u32
+}
+
+/// Converts µs into counter ticks
+#[inline(always)]
+pub fn us_to_ticks(us: u32) -> u32 {
+    (us as u64 * COUNTER_CLOCK.hz() as u64 / 1_000_000) as u32
+}
+
+/// Fails if a registered driver uses the pin, e.g. TIOA2 is the RXD of USART2
+fn check_pin(pin: Pin) -> Result<(), &'static str> {
+    match get_registry().owner(pin) {
+        Some(_) => Err("The pin belongs to another driver"),
+        None => Ok(()),
+    }
+}
+
+/// Executes the PWM syscall. Outputs are numbered channel * 2 + (0 for TIOA, 1 for TIOB).
+/// TC0 and TC1 are the kernel counter, so only the outputs 4 - 11 are allowed
+pub fn user_pwm(output: u32, freq_hz: u32, duty: u32) -> Result<(), &'static str> {
+    let channel = output as usize / 2;
+    if !(2..CHANNEL_NUMBER).contains(&channel) {
+        return Err("Unknown pwm output");
+    }
+    let tio = match output % 2 {
+        0 => Tio::A,
+        _ => Tio::B,
+    };
+    check_pin(TIO_PINS[channel][tio as usize])?;
+    TimerCounter::new(channel).pwm(tio, freq_hz, duty)
+}
+
+/// Executes the Capture syscall: Starts measuring the frequency of the signal on TIOA of the channel.
+/// The thread waits until the interrupt has the result in Hz.
+/// TC0 and TC1 are the kernel counter, so only the channels 2 - 5 are allowed
+pub fn user_capture(threads: &mut ThreadList, channel: u32) -> Result<(), &'static str> {
+    let channel = channel as usize;
+    if !(2..CHANNEL_NUMBER).contains(&channel) {
+        return Err("Unknown capture channel");
+    }
+    check_pin(TIO_PINS[channel][0])?;
+    let tc = TimerCounter::new(channel);
+    unsafe { ROUNDS[channel] = 0 };
+    tc.capture(CAPTURE_CLOCK);
+    unsafe { tc.int_enable.write(LDRBS | COVFS) };
+    threads.curr_mut_thread().state = WaitingForDevice(tc.name());
+    Ok(())
+}
+
+impl Driver for TimerCounter {
+    fn name(&self) -> &'static str {
+        ["tc0", "tc1", "tc2", "tc3", "tc4", "tc5"][self.channel()]
+    }
+
+    fn init(&mut self) {
+        self.stop();
+        unsafe { self.int_disable.write(u32::MAX) };
+    }
+
+    fn irq(&self) -> Option<Irq> {
+        Some(Irq {
+            source: TC0_ID + self.channel(),
+            prio: PRIO_DEVICE,
+            src_type: SrcType::HighLevelSens,
+        })
+    }
+
+    /// Only the Capture syscall enables interrupts
+    fn handle_irq(&mut self, threads: &mut ThreadList) -> bool {
+        let pending = self.status.read() & self.int_mask.read();
+        let channel = self.channel();
+        let hz = if pending & LDRBS != 0 {
+            // The counter wraps, one period fits into 16 bits
+            match (self.rb.read() as u16).wrapping_sub(self.ra.read() as u16) {
+                0 => 0,
+                period => CAPTURE_CLOCK.hz() / period as u32,
+            }
+        } else if pending & COVFS != 0 {
+            unsafe { ROUNDS[channel] += 1 };
+            if unsafe { ROUNDS[channel] } < CAPTURE_ROUNDS {
+                return true;
+            }
+            // no signal
+            0
+        } else {
+            return false;
+        };
+        unsafe { self.int_disable.write(LDRBS | COVFS) };
+        self.stop();
+        for thread in threads.iter_mut() {
+            if thread.state == WaitingForDevice(self.name()) {
+                thread.regs.r0 = hz;
+                thread.state = Ready;
+            }
+        }
+        true
+    }
+
+    fn peripheral_id(&self) -> Option<usize> {
+        Some(TC0_ID + self.channel())
+    }
+
+    fn shutdown(&mut self) {
+        unsafe { self.int_disable.write(u32::MAX) };
+        self.stop();
+    }
+}
@@ -0,0 +1,222 @@
+//! Der TWI-Master (I2C)
+//!
+//! Übertragungen sind gepollt: Der TWI des AT91RM9200 verträgt keine Pausen zwischen den Bytes,
+//! die ein Interrupt verursachen könnte. Jedes Warten hat einen Timeout,
+//! ein NACK des Geräts wird zu einem Fehler.
+
+use super::{
+    gpio::{Peripheral, Pin, Port},
+    power_management::PMC,
+    sys_timer::{is_due, SysTimer},
+    Driver,
+};
+use crate::{consts::TWI_CLOCK_HZ, println, util::without_interrupts};
+use volatile_register::{RO, RW, WO};
+
+pub struct Twi {
+    // p. 385
+    pub ctrl: WO<u32>,
+    pub master_mode: RW<u32>,
+    _reserved0: u32,
+    pub internal_address: RW<u32>,
+    pub clock_waveform: RW<u32>,
+    _reserved1: [u32; 3],
+    pub status: RO<u32>,
+    _int_enable: WO<u32>,
+    pub int_disable: WO<u32>,
+    _int_mask: RO<u32>,
+    pub receive: RO<u32>,
+    pub transmit: WO<u32>,
+}
+
+const TWI_ADDR: u32 = 0xFFFB_8000;
+const TWI_ID: usize = 12;
+/// How long we wait for the device
+const TIMEOUT_MS: u32 = 10;
+// Control
+const START: u32 = 1 << 0;
+const STOP: u32 = 1 << 1;
+const MSEN: u32 = 1 << 2;
+const MSDIS: u32 = 1 << 3;
+const SWRST: u32 = 1 << 7;
+// Master Mode
+const IADRSZ: u32 = 8;
+const MREAD: u32 = 1 << 12;
+const DADR: u32 = 16;
+// Status
+const TXCOMP: u32 = 1 << 0;
+const RXRDY: u32 = 1 << 1;
+const TXRDY: u32 = 1 << 2;
+const NACK: u32 = 1 << 8;
+
+const TWD: Pin = Pin::new(Port::A, 25);
+const TWCK: Pin = Pin::new(Port::A, 26);
+
+impl Twi {
+    #[inline(always)]
+    pub fn new() -> &'static mut Twi {
+        unsafe { &mut *(TWI_ADDR as *mut Twi) }
+    }
+
+    /// Sets the bus clock. Low and high phase are equally long
+    pub fn set_clock(&mut self, hz: u32) {
+        // A phase lasts (DIV * 2^CKDIV + 3) master clock cycles
+        let cycles = (PMC::new().master_clock_hz() / (2 * hz.max(1))).saturating_sub(3);
+        let ckdiv = (0..8).find(|ckdiv| cycles >> ckdiv <= 255).unwrap_or(7);
+        let div = (cycles >> ckdiv).min(255);
+        unsafe { self.clock_waveform.write((ckdiv << 16) | (div << 8) | div) };
+    }
+
+    /// Waits until one of the status bits is set. A NACK ends the transfer
+    fn wait_for(&self, bits: u32) -> Result<u32, &'static str> {
+        let timer = SysTimer::new();
+        let deadline = timer.deadline_in(TIMEOUT_MS);
+        loop {
+            let status = self.status.read();
+            if status & NACK != 0 {
+                return Err("The device didn't acknowledge");
+            }
+            if status & bits != 0 {
+                return Ok(status);
+            }
+            if is_due(deadline, timer.now()) {
+                return Err("Timeout on the TWI bus");
+            }
+        }
+    }
+
+    /// Addresses the device and, if given, its internal register
+    fn address(&mut self, device: u8, register: Option<u8>, read: bool) {
+        let mut mode = ((device as u32 & 0x7F) << DADR) | if read { MREAD } else { 0 };
+        if let Some(register) = register {
+            mode |= 1 << IADRSZ;
+            unsafe { self.internal_address.write(register as u32) };
+        }
+        unsafe { self.master_mode.write(mode) };
+    }
+
+    /// Writes data into the register of the device, e.g. an EEPROM cell or an RTC register
+    pub fn write_register(
+        &mut self,
+        device: u8,
+        register: u8,
+        data: &[u8],
+    ) -> Result<(), &'static str> {
+        self.write_transfer(device, Some(register), data)
+    }
+
+    /// Reads from the register of the device (with a repeated start)
+    pub fn read_register(
+        &mut self,
+        device: u8,
+        register: u8,
+        buf: &mut [u8],
+    ) -> Result<(), &'static str> {
+        self.read_transfer(device, Some(register), buf)
+    }
+
+    fn write_transfer(
+        &mut self,
+        device: u8,
+        register: Option<u8>,
+        data: &[u8],
+    ) -> Result<(), &'static str> {
+        without_interrupts(|| {
+            self.address(device, register, false);
+            // clear an old NACK
+            self.status.read();
+            if data.is_empty() {
+                // Only the address, e.g. to probe the device
+                unsafe { self.ctrl.write(START | STOP) };
+            }
+            // Writing the first byte starts the transfer, the stop follows when the data runs out
+            for &byte in data {
+                unsafe { self.transmit.write(byte as u32) };
+                self.wait_for(TXRDY)?;
+            }
+            self.wait_for(TXCOMP).map(|_| ())
+        })
+    }
+
+    fn read_transfer(
+        &mut self,
+        device: u8,
+        register: Option<u8>,
+        buf: &mut [u8],
+    ) -> Result<(), &'static str> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        without_interrupts(|| {
+            self.address(device, register, true);
+            self.status.read();
+            let len = buf.len();
+            // The stop must be requested while the last byte is received
+            unsafe {
+                match len {
+                    1 => self.ctrl.write(START | STOP),
+                    _ => self.ctrl.write(START),
+                }
+            }
+            for (i, byte) in buf.iter_mut().enumerate() {
+                if i == len - 1 && len > 1 {
+                    unsafe { self.ctrl.write(STOP) };
+                }
+                self.wait_for(RXRDY)?;
+                *byte = self.receive.read() as u8;
+            }
+            self.wait_for(TXCOMP).map(|_| ())
+        })
+    }
+
+    pub fn print(&self) {
+        println!(
+            "twi: mode {:08x}, clock {:08x}, status {:08x}",
+            self.master_mode.read(),
+            self.clock_waveform.read(),
+            self.status.read()
+        );
+    }
+}
+
+/// Executes the Twi syscall.
+/// device holds the 7-bit address (bits 0-6), whether to write (bit 7) and whether register is used (bit 8)
+pub fn user_transfer(device: u32, register: u32, buf: &mut [u8]) -> Result<(), &'static str> {
+    let twi = Twi::new();
+    let address = (device & 0x7F) as u8;
+    let register = match device & (1 << 8) != 0 {
+        true => Some(register as u8),
+        false => None,
+    };
+    match device & (1 << 7) != 0 {
+        true => twi.write_transfer(address, register, buf),
+        false => twi.read_transfer(address, register, buf),
+    }
+}
+
+impl Driver for Twi {
+    fn name(&self) -> &'static str {
+        "twi"
+    }
+
+    fn init(&mut self) {
+        // Open drain, the pull-ups are on the board
+        for pin in [TWD, TWCK] {
+            pin.multi_driver(true).peripheral(Peripheral::A);
+        }
+        unsafe {
+            self.ctrl.write(SWRST);
+            self.int_disable.write(u32::MAX);
+        }
+        self.set_clock(TWI_CLOCK_HZ);
+        unsafe { self.ctrl.write(MSEN) };
+    }
+
+    fn peripheral_id(&self) -> Option<usize> {
+        Some(TWI_ID)
+    }
+
+    fn shutdown(&mut self) {
+        unsafe { self.ctrl.write(MSDIS) }
+    }
+}
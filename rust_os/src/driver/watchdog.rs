@@ -0,0 +1,115 @@
+//! Der Watchdog des System-Timers
+//!
+//! Er setzt das Board zurück, wenn er nicht rechtzeitig gestreichelt wird.
+//! Normalerweise macht das der Kernel im Timer-Interrupt. Ein Supervisor-Thread kann ihn aber
+//! mit dem Watchdog-Syscall übernehmen, dann führt ein hängender Supervisor zum Reset.
+//! Nach einem Panic wird er nicht mehr gestreichelt. Nur wenn PANIC_RESET_TICKS einen Neustart durch den
+//! Watchdog will, bleibt er dabei scharf, sonst wird er angehalten.
+
+use super::sys_timer::{is_due, SysTimer};
+use crate::{consts::WATCHDOG_MS, info, thread::ID};
+
+/// Who has to pet the watchdog
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Owner {
+    Kernel,
+    Thread(ID),
+}
+
+static mut OWNER: Owner = Owner::Kernel;
+/// The tick at which the kernel pets next
+static mut NEXT_PET: u32 = 0;
+static mut STOPPED: bool = false;
+
+/// The watchdog counts at 256 Hz with 16 bits
+#[inline(always)]
+fn timeout_ticks(ms: u32) -> u16 {
+    (ms as u64 * 256 / 1000).clamp(1, u16::MAX as u64) as u16
+}
+
+/// Starts the watchdog if WATCHDOG_MS is set. The sys timer must be registered
+pub fn init() {
+    let Some(ms) = WATCHDOG_MS else { return };
+    let timer = SysTimer::new();
+    timer.arm_reset(timeout_ticks(ms));
+    unsafe { NEXT_PET = timer.deadline_in(ms / 2) };
+    info!("Watchdog resets after {ms} ms");
+}
+
+/// Pets the watchdog if the kernel owns it and it is due. Called by the sys timer
+pub fn tick(now: u32) {
+    let Some(ms) = WATCHDOG_MS else { return };
+    unsafe {
+        if OWNER == Owner::Kernel && !STOPPED && is_due(NEXT_PET, now) {
+            let timer = SysTimer::new();
+            timer.pet();
+            NEXT_PET = timer.deadline_in(ms / 2);
+        }
+    }
+}
+
+/// When the kernel has to pet next.
+/// The scheduler takes it into account, so that the watchdog is petted in tickless mode too
+pub fn next_pet() -> Option<u32> {
+    match (WATCHDOG_MS, unsafe { OWNER }) {
+        (Some(_), Owner::Kernel) => Some(unsafe { NEXT_PET }),
+        _ => None,
+    }
+}
+
+/// Hands the watchdog to the thread. From now on only it pets the watchdog,
+/// if it stops or ends the board is reset
+pub fn claim(id: ID) -> Result<(), &'static str> {
+    if WATCHDOG_MS.is_none() {
+        return Err("The watchdog is disabled");
+    }
+    unsafe {
+        match OWNER {
+            Owner::Thread(owner) if owner != id => Err("The watchdog belongs to another thread"),
+            _ => {
+                OWNER = Owner::Thread(id);
+                SysTimer::new().pet();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Pets the watchdog for the thread that owns it
+pub fn pet(id: ID) -> Result<(), &'static str> {
+    if unsafe { OWNER } != Owner::Thread(id) {
+        return Err("The watchdog doesn't belong to this thread");
+    }
+    if unsafe { !STOPPED } {
+        SysTimer::new().pet();
+    }
+    Ok(())
+}
+
+/// Nobody pets the watchdog anymore. Called on a panic with PANIC_RESET_TICKS set, so that a watchdog reset follows
+pub fn stop() {
+    unsafe { STOPPED = true }
+}
+
+/// Keeps the watchdog from resetting the board, e.g. while gdb halts the system
+pub fn pause() {
+    if WATCHDOG_MS.is_some() {
+        SysTimer::new().disarm_reset();
+    }
+}
+
+/// Undoes pause. The timeout starts again
+pub fn resume() {
+    if let Some(ms) = WATCHDOG_MS {
+        SysTimer::new().arm_reset(timeout_ticks(ms));
+    }
+}
+
+/// What user space can do with the watchdog. See `syscalls::watchdog_claim`
+pub fn user_op(op: u32, id: ID) -> Result<(), &'static str> {
+    match op {
+        0 => claim(id),
+        1 => pet(id),
+        _ => Err("Unknown watchdog operation"),
+    }
+}
@@ -0,0 +1,304 @@
+//! Der Datei-Namensraum des Kernels
+//!
+//! Dateisysteme werden unter einem Pfad eingehängt, z.B. die SD-Karte unter /sd und die RAM-Disk
+//! unter /ram. Vor der Karte liegt ein Block-Cache, geschrieben wird sie beim Schließen einer Datei.
+//! Ein Pfad geht an das Dateisystem mit dem längsten passenden Mount-Pfad.
+//! Geöffnete Dateien gehören dem Thread, der sie geöffnet hat, und werden über ihren Index
+//! angesprochen, siehe den File-Syscall.
+//! - fat: FAT16 und FAT32 auf einem [BlockDevice]
+
+pub mod fat;
+
+use crate::{
+    block::{cache::BlockCache, ram_disk::RamDisk, Sector, SECTOR_SIZE},
+    consts::{BLOCK_CACHE_SECTORS, RAM_DISK_SECTORS, RAM_MOUNT, SD_MOUNT},
+    info,
+    mci::Mci,
+    registry::get_registry,
+    thread::ID,
+    warn,
+};
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    slice, str,
+};
+use fat::Fat;
+
+const MOUNT_NUMBER: usize = 4;
+pub const FILE_NUMBER: usize = 16;
+// Flags of open
+/// Creates the file if it doesn't exist
+pub const CREATE: u32 = 1 << 0;
+/// Starts at the end of the file
+pub const APPEND: u32 = 1 << 1;
+
+/// A file or directory
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    pub size: u32,
+    pub is_dir: bool,
+    /// Where the file system finds the data and the entry of the node. Their meaning is up to it
+    pub start: u32,
+    pub entry: u32,
+}
+
+/// Common interface of all file systems. Paths are relative to the mount point
+pub trait FileSystem {
+    fn lookup(&mut self, path: &str) -> Result<Node, &'static str>;
+
+    /// Creates an empty file
+    fn create(&mut self, path: &str) -> Result<Node, &'static str>;
+
+    /// Reads from offset on. Returns the number of bytes, 0 at the end of the file
+    fn read(&mut self, node: &Node, offset: u32, buf: &mut [u8]) -> Result<usize, &'static str>;
+
+    /// Writes at offset, which may be at most the end of the file. The file grows as needed
+    fn write(&mut self, node: &mut Node, offset: u32, data: &[u8]) -> Result<usize, &'static str>;
+
+    /// Brings everything written so far onto the device
+    fn flush(&mut self) -> Result<(), &'static str>;
+}
+
+struct Mount {
+    path: &'static str,
+    fs: &'static mut dyn FileSystem,
+}
+
+struct OpenFile {
+    owner: ID,
+    mount: usize,
+    node: Node,
+    position: u32,
+}
+
+const NO_MOUNT: Option<Mount> = None;
+static mut MOUNTS: [Option<Mount>; MOUNT_NUMBER] = [NO_MOUNT; MOUNT_NUMBER];
+const NO_FILE: Option<OpenFile> = None;
+static mut FILES: [Option<OpenFile>; FILE_NUMBER] = [NO_FILE; FILE_NUMBER];
+
+/// Formats and mounts the RAM disk and mounts the FAT file system of the memory card, if there is one
+pub fn init() {
+    static mut RAM: [Sector; RAM_DISK_SECTORS] = [[0; SECTOR_SIZE]; RAM_DISK_SECTORS];
+    static mut RAM_DISK: Option<RamDisk> = None;
+    static mut RAM_FS: Option<Fat> = None;
+    static mut CARD_CACHE: Option<BlockCache<BLOCK_CACHE_SECTORS>> = None;
+    static mut CARD_FS: Option<Fat> = None;
+    unsafe {
+        let disk = (*addr_of_mut!(RAM_DISK)).insert(RamDisk::new(&mut *addr_of_mut!(RAM)));
+        match fat::format(disk, "ramdisk")
+            .and_then(|_| Fat::new((*addr_of_mut!(RAM_DISK)).as_mut().unwrap()))
+        {
+            Ok(fat) => {
+                mount(RAM_MOUNT, (*addr_of_mut!(RAM_FS)).insert(fat)).unwrap();
+                info!(
+                    "Mounted a RAM disk with {} kB at {RAM_MOUNT}",
+                    RAM_DISK_SECTORS / 2
+                );
+            }
+            Err(err) => warn!("Couldn't create the RAM disk: {err}"),
+        }
+    }
+
+    let mci = Mci::new();
+    if mci.card().is_none() {
+        // Without a card the MCI only costs power
+        get_registry().unregister("mci").unwrap();
+        return;
+    }
+    unsafe {
+        let cache = (*addr_of_mut!(CARD_CACHE)).insert(BlockCache::new(mci));
+        match Fat::new(cache) {
+            Ok(fat) => {
+                info!(
+                    "Mounted the {:?} file system of the card at {SD_MOUNT}",
+                    fat.fat_type
+                );
+                mount(SD_MOUNT, (*addr_of_mut!(CARD_FS)).insert(fat)).unwrap();
+            }
+            Err(err) => warn!("Couldn't mount the card: {err}"),
+        }
+    }
+}
+
+/// Makes the file system available under path, e.g. "/sd"
+pub fn mount(path: &'static str, fs: &'static mut dyn FileSystem) -> Result<(), &'static str> {
+    let mounts = unsafe { &mut *addr_of_mut!(MOUNTS) };
+    if mounts.iter().flatten().any(|mount| mount.path == path) {
+        return Err("Something is already mounted there");
+    }
+    let slot = mounts
+        .iter()
+        .position(|mount| mount.is_none())
+        .ok_or("Too many mounts")?;
+    mounts[slot] = Some(Mount { path, fs });
+    Ok(())
+}
+
+/// The mount of the path and the path inside it
+fn resolve(path: &str) -> Result<(usize, &str), &'static str> {
+    unsafe { (*addr_of!(MOUNTS)).iter() }
+        .enumerate()
+        .filter_map(|(i, mount)| {
+            let mount = mount.as_ref()?;
+            let prefix = mount.path.trim_end_matches('/');
+            let rest = path.strip_prefix(prefix)?;
+            match rest.is_empty() || rest.starts_with('/') {
+                true => Some((i, prefix.len(), rest.trim_start_matches('/'))),
+                false => None,
+            }
+        })
+        .max_by_key(|&(_, len, _)| len)
+        .map(|(i, _, rest)| (i, rest))
+        .ok_or("No file system mounted there")
+}
+
+#[inline(always)]
+fn file_system(mount: usize) -> &'static mut dyn FileSystem {
+    unsafe { MOUNTS[mount].as_mut().unwrap().fs }
+}
+
+/// The open file with the handle, if it belongs to the thread
+fn file(handle: u32, owner: ID) -> Result<&'static mut OpenFile, &'static str> {
+    match unsafe { (*addr_of_mut!(FILES)).get_mut(handle as usize) } {
+        Some(Some(file)) if file.owner == owner => Ok(file),
+        _ => Err("Unknown file"),
+    }
+}
+
+/// Opens the file for the thread and returns its handle
+pub fn open(path: &str, flags: u32, owner: ID) -> Result<u32, &'static str> {
+    let (mount, path) = resolve(path)?;
+    let fs = file_system(mount);
+    let node = match fs.lookup(path) {
+        Ok(node) => node,
+        Err(_) if flags & CREATE != 0 => fs.create(path)?,
+        Err(err) => return Err(err),
+    };
+    let files = unsafe { &mut *addr_of_mut!(FILES) };
+    let handle = files
+        .iter()
+        .position(|file| file.is_none())
+        .ok_or("Too many open files")?;
+    files[handle] = Some(OpenFile {
+        owner,
+        mount,
+        node,
+        position: if flags & APPEND != 0 { node.size } else { 0 },
+    });
+    Ok(handle as u32)
+}
+
+pub fn read(handle: u32, buf: &mut [u8], owner: ID) -> Result<usize, &'static str> {
+    let file = file(handle, owner)?;
+    let count = file_system(file.mount).read(&file.node, file.position, buf)?;
+    file.position += count as u32;
+    Ok(count)
+}
+
+pub fn write(handle: u32, data: &[u8], owner: ID) -> Result<usize, &'static str> {
+    let (count, mount, node) = {
+        let file = file(handle, owner)?;
+        let count = file_system(file.mount).write(&mut file.node, file.position, data)?;
+        file.position += count as u32;
+        (count, file.mount, file.node)
+    };
+    // Other handles of the same file see the new size
+    let others = unsafe { (*addr_of_mut!(FILES)).iter_mut() }
+        .enumerate()
+        .filter(|&(i, _)| i != handle as usize);
+    for other in others.filter_map(|(_, other)| other.as_mut()) {
+        if other.mount == mount && other.node.entry == node.entry {
+            other.node = node;
+        }
+    }
+    Ok(count)
+}
+
+/// Moves to the position, at most to the end of the file
+pub fn seek(handle: u32, position: u32, owner: ID) -> Result<u32, &'static str> {
+    let file = file(handle, owner)?;
+    file.position = position.min(file.node.size);
+    Ok(file.position)
+}
+
+/// Closes the file and writes its file system back to the device
+pub fn close(handle: u32, owner: ID) -> Result<(), &'static str> {
+    let mount = file(handle, owner)?.mount;
+    unsafe { FILES[handle as usize] = None };
+    file_system(mount).flush()
+}
+
+/// Closes all files of a thread that ended and writes their file systems back, like `close`
+pub fn release(owner: ID) {
+    let mut used = [false; MOUNT_NUMBER];
+    for file in unsafe { (*addr_of_mut!(FILES)).iter_mut() } {
+        if let Some(open) = file.as_ref().filter(|file| file.owner == owner) {
+            used[open.mount] = true;
+            *file = None;
+        }
+    }
+    let mounts = unsafe { (*addr_of_mut!(MOUNTS)).iter_mut() };
+    for (mount, _) in mounts.zip(used).filter(|&(_, used)| used) {
+        let mount = mount.as_mut().unwrap();
+        if let Err(err) = mount.fs.flush() {
+            warn!("Couldn't write back {}: {err}", mount.path);
+        }
+    }
+}
+
+/// What user space can do with files. See `syscalls::file`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileOp {
+    /// Opens the path in buf with the flags in arg
+    Open,
+    Read,
+    Write,
+    /// Moves to the position in arg
+    Seek,
+    /// The size of the file
+    Size,
+    Close,
+}
+
+impl TryFrom<u32> for FileOp {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        use FileOp::*;
+        Ok(match value {
+            0 => Open,
+            1 => Read,
+            2 => Write,
+            3 => Seek,
+            4 => Size,
+            5 => Close,
+            _ => return Err("Unknown file operation"),
+        })
+    }
+}
+
+/// The argument of the File syscall
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FileCall {
+    pub op: u32,
+    pub handle: u32,
+    pub buf: u32,
+    pub len: usize,
+    pub arg: u32,
+}
+
+/// Executes the File syscall for the thread
+pub fn user_op(call: &FileCall, owner: ID) -> Result<u32, &'static str> {
+    let buf = unsafe { slice::from_raw_parts_mut(call.buf as *mut u8, call.len) };
+    Ok(match FileOp::try_from(call.op)? {
+        FileOp::Open => {
+            let path = str::from_utf8(buf).map_err(|_| "The path isn't UTF-8")?;
+            open(path, call.arg, owner)?
+        }
+        FileOp::Read => read(call.handle, buf, owner)? as u32,
+        FileOp::Write => write(call.handle, buf, owner)? as u32,
+        FileOp::Seek => seek(call.handle, call.arg, owner)?,
+        FileOp::Size => file(call.handle, owner)?.node.size,
+        FileOp::Close => close(call.handle, owner).map(|_| 0)?,
+    })
+}
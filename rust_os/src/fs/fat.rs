@@ -0,0 +1,573 @@
+//! FAT16 und FAT32
+//!
+//! Das Dateisystem liegt entweder direkt auf dem Gerät oder in der ersten Partition.
+//! Es werden nur kurze 8.3-Namen verstanden, lange Namen werden beim Suchen übersprungen.
+//! Geschrieben wird sofort an das Blockgerät und in alle Kopien der FAT. Liegt davor ein
+//! [BlockCache](crate::block::cache::BlockCache), erreicht es die Hardware erst bei flush.
+//! Die Anzahl freier Cluster im FSInfo-Sektor von FAT32 aktualisieren wir nicht, sie ist nur ein Hinweis.
+//! format legt ein leeres FAT16 an, z.B. auf der RAM-Disk.
+
+use super::{FileSystem, Node};
+use crate::block::{BlockDevice, Sector, SECTOR_SIZE};
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+// Attributes
+const VOLUME_ID: u8 = 0x08;
+const DIRECTORY: u8 = 0x10;
+const ARCHIVE: u8 = 0x20;
+const LONG_NAME: u8 = 0x0F;
+/// The first byte of a deleted entry
+const DELETED: u8 = 0xE5;
+/// Clusters from here on mark the end of a chain (FAT32 values, FAT16 is extended)
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const FAT32_MASK: u32 = 0x0FFF_FFFF;
+/// The entry of the root directory, which has none
+const ROOT_ENTRY: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Where the sectors of a directory are
+#[derive(Clone, Copy, Debug)]
+enum Dir {
+    /// The fixed root directory of FAT16
+    Root,
+    Chain(u32),
+}
+
+pub struct Fat {
+    device: &'static mut dyn BlockDevice,
+    pub fat_type: FatType,
+    sectors_per_cluster: u32,
+    fat_start: u32,
+    fat_sectors: u32,
+    fats: u32,
+    /// The fixed root directory of FAT16
+    root_start: u32,
+    root_sectors: u32,
+    /// The first cluster of the root directory of FAT32
+    root_cluster: u32,
+    data_start: u32,
+    clusters: u32,
+    /// Where the search for a free cluster starts
+    next_free: u32,
+    /// The last step through a cluster chain: start, index and cluster
+    last_step: (u32, u32, u32),
+    buf: Sector,
+    /// The sector in buf
+    buffered: Option<u32>,
+}
+
+#[inline(always)]
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[inline(always)]
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+/// Whether the sector looks like a FAT boot sector
+fn is_boot_sector(buf: &Sector) -> bool {
+    buf[510..512] == [0x55, 0xAA]
+        && matches!(buf[0], 0xEB | 0xE9)
+        && read_u16(buf, 11) as usize == SECTOR_SIZE
+}
+
+/// Converts a name into the padded 8.3 form of the directory entries, e.g. "a.txt" into "A       TXT"
+fn short_name(name: &str) -> Result<[u8; 11], &'static str> {
+    let mut short = [b' '; 11];
+    if name == "." || name == ".." {
+        short[..name.len()].copy_from_slice(name.as_bytes());
+        return Ok(short);
+    }
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return Err("Only 8.3 names are supported");
+    }
+    for (i, byte) in base.bytes().chain(extension.bytes()).enumerate() {
+        if !byte.is_ascii_graphic() || b"\"*+,./:;<=>?[\\]|".contains(&byte) {
+            return Err("Invalid character in the name");
+        }
+        let position = if i < base.len() {
+            i
+        } else {
+            8 + i - base.len()
+        };
+        short[position] = byte.to_ascii_uppercase();
+    }
+    Ok(short)
+}
+
+/// Splits a path into its parent and its last component
+fn split(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+        None => ("", path),
+    }
+}
+
+/// Creates an empty FAT16 over the whole device: one reserved sector, two FATs and 512 root entries
+pub fn format(device: &mut dyn BlockDevice, label: &str) -> Result<(), &'static str> {
+    const RESERVED: u32 = 1;
+    const FATS: u32 = 2;
+    const ROOT_ENTRIES: u32 = 512;
+    let total = device.sector_count();
+    let root_sectors = ROOT_ENTRIES * ENTRY_SIZE as u32 / SECTOR_SIZE as u32;
+    // The smallest clusters that keep the number of clusters in the range of FAT16
+    let mut sectors_per_cluster = 1;
+    while (total / sectors_per_cluster) > 65524 && sectors_per_cluster < 128 {
+        sectors_per_cluster *= 2;
+    }
+    let data = total.saturating_sub(RESERVED + root_sectors);
+    let fat_sectors = ((data / sectors_per_cluster + 2) * 2).div_ceil(SECTOR_SIZE as u32);
+    let clusters = data.saturating_sub(FATS * fat_sectors) / sectors_per_cluster;
+    if !(4085..=65524).contains(&clusters) {
+        return Err("The device has the wrong size for FAT16");
+    }
+
+    let mut buf = [0; SECTOR_SIZE];
+    buf[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    buf[3..11].copy_from_slice(b"RUST_OS ");
+    buf[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    buf[13] = sectors_per_cluster as u8;
+    buf[14..16].copy_from_slice(&(RESERVED as u16).to_le_bytes());
+    buf[16] = FATS as u8;
+    buf[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+    match u16::try_from(total) {
+        Ok(total) => buf[19..21].copy_from_slice(&total.to_le_bytes()),
+        Err(_) => buf[32..36].copy_from_slice(&total.to_le_bytes()),
+    }
+    // Fixed disk
+    buf[21] = 0xF8;
+    buf[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+    // Extended boot signature, serial number, label and type
+    buf[38] = 0x29;
+    buf[39..43].copy_from_slice(&total.to_le_bytes());
+    buf[43..54].fill(b' ');
+    for (byte, char) in buf[43..54].iter_mut().zip(label.bytes()) {
+        *byte = char.to_ascii_uppercase();
+    }
+    buf[54..62].copy_from_slice(b"FAT16   ");
+    buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+    device.write(0, &buf)?;
+
+    // The first two entries of the FAT are reserved: the media byte and end of chain
+    for fat in 0..FATS {
+        let start = RESERVED + fat * fat_sectors;
+        for sector in 0..fat_sectors {
+            buf = [0; SECTOR_SIZE];
+            if sector == 0 {
+                buf[0..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+            }
+            device.write(start + sector, &buf)?;
+        }
+    }
+    buf = [0; SECTOR_SIZE];
+    for sector in 0..root_sectors {
+        device.write(RESERVED + FATS * fat_sectors + sector, &buf)?;
+    }
+    Ok(device.flush()?)
+}
+
+impl Fat {
+    /// Reads the boot sector, also from the first partition
+    pub fn new(device: &'static mut dyn BlockDevice) -> Result<Fat, &'static str> {
+        if device.sector_size() != SECTOR_SIZE {
+            return Err("Unsupported sector size");
+        }
+        let mut buf = [0; SECTOR_SIZE];
+        device.read(0, &mut buf)?;
+        let mut start = 0;
+        if !is_boot_sector(&buf) {
+            if buf[510..512] != [0x55, 0xAA] {
+                return Err("No boot sector or partition table");
+            }
+            // The first partition of the MBR
+            start = read_u32(&buf, 446 + 8);
+            device.read(start, &mut buf)?;
+            if !is_boot_sector(&buf) {
+                return Err("The first partition has no FAT file system");
+            }
+        }
+        let sectors_per_cluster = buf[13] as u32;
+        let reserved = read_u16(&buf, 14) as u32;
+        let fats = buf[16] as u32;
+        let root_entries = read_u16(&buf, 17) as u32;
+        let total = match read_u16(&buf, 19) {
+            0 => read_u32(&buf, 32),
+            total => total as u32,
+        };
+        let fat_sectors = match read_u16(&buf, 22) {
+            0 => read_u32(&buf, 36),
+            sectors => sectors as u32,
+        };
+        if sectors_per_cluster == 0 || fats == 0 || fat_sectors == 0 {
+            return Err("Broken boot sector");
+        }
+        let fat_start = start + reserved;
+        let root_start = fat_start + fats * fat_sectors;
+        let root_sectors = (root_entries * ENTRY_SIZE as u32).div_ceil(SECTOR_SIZE as u32);
+        let data_start = root_start + root_sectors;
+        let clusters = total.saturating_sub(data_start - start) / sectors_per_cluster;
+        let fat_type = match clusters {
+            0..=4084 => return Err("FAT12 isn't supported"),
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        Ok(Fat {
+            device,
+            fat_type,
+            sectors_per_cluster,
+            fat_start,
+            fat_sectors,
+            fats,
+            root_start,
+            root_sectors,
+            root_cluster: read_u32(&buf, 44),
+            data_start,
+            clusters,
+            next_free: 2,
+            last_step: (0, 0, 0),
+            buf: [0; SECTOR_SIZE],
+            buffered: None,
+        })
+    }
+
+    /// Brings the sector into buf
+    fn load(&mut self, sector: u32) -> Result<(), &'static str> {
+        if self.buffered != Some(sector) {
+            self.buffered = None;
+            self.device.read(sector, &mut self.buf)?;
+            self.buffered = Some(sector);
+        }
+        Ok(())
+    }
+
+    /// Writes buf back to its sector
+    fn store(&mut self) -> Result<(), &'static str> {
+        match self.buffered {
+            Some(sector) => Ok(self.device.write(sector, &self.buf)?),
+            None => Ok(()),
+        }
+    }
+
+    #[inline(always)]
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    #[inline(always)]
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    /// The sector of the first FAT and the offset in it, where the entry of the cluster is
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (
+            self.fat_start + offset / SECTOR_SIZE as u32,
+            (offset % SECTOR_SIZE as u32) as usize,
+        )
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> Result<u32, &'static str> {
+        let (sector, offset) = self.fat_position(cluster);
+        self.load(sector)?;
+        Ok(match self.fat_type {
+            FatType::Fat16 => match read_u16(&self.buf, offset) as u32 {
+                value if value >= 0xFFF8 => END_OF_CHAIN,
+                value => value,
+            },
+            FatType::Fat32 => read_u32(&self.buf, offset) & FAT32_MASK,
+        })
+    }
+
+    /// Sets the entry in every copy of the FAT
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), &'static str> {
+        let (sector, offset) = self.fat_position(cluster);
+        for fat in 0..self.fats {
+            self.load(sector + fat * self.fat_sectors)?;
+            match self.fat_type {
+                FatType::Fat16 => {
+                    self.buf[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes())
+                }
+                FatType::Fat32 => {
+                    // The upper 4 bits are reserved
+                    let old = read_u32(&self.buf, offset) & !FAT32_MASK;
+                    self.buf[offset..offset + 4].copy_from_slice(&(old | value).to_le_bytes());
+                }
+            }
+            self.store()?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after this one, None at the end of the chain
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, &'static str> {
+        match self.fat_entry(cluster)? {
+            next if (2..END_OF_CHAIN).contains(&next) && next < self.clusters + 2 => Ok(Some(next)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The index-th cluster of the chain starting at start
+    fn cluster_at(&mut self, start: u32, index: u32) -> Result<Option<u32>, &'static str> {
+        if start < 2 {
+            return Ok(None);
+        }
+        // Reading or writing a file steps through its chain in order, so we continue the last walk
+        let (mut cluster, mut i) = match self.last_step {
+            (last_start, last_index, last) if last_start == start && last_index <= index => {
+                (last, last_index)
+            }
+            _ => (start, 0),
+        };
+        while i < index {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(None),
+            }
+            i += 1;
+        }
+        self.last_step = (start, index, cluster);
+        Ok(Some(cluster))
+    }
+
+    /// Takes a free cluster and appends it to the chain ending in previous
+    fn allocate(&mut self, previous: Option<u32>) -> Result<u32, &'static str> {
+        let (first, clusters) = (self.next_free - 2, self.clusters);
+        let mut free = None;
+        for cluster in (0..clusters).map(|i| 2 + (first + i) % clusters) {
+            if self.fat_entry(cluster)? == 0 {
+                free = Some(cluster);
+                break;
+            }
+        }
+        let cluster = free.ok_or("The file system is full")?;
+        self.set_fat_entry(cluster, END_OF_CHAIN & FAT32_MASK)?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    fn root(&self) -> Node {
+        Node {
+            size: 0,
+            is_dir: true,
+            start: match self.fat_type {
+                FatType::Fat16 => 0,
+                FatType::Fat32 => self.root_cluster,
+            },
+            entry: ROOT_ENTRY,
+        }
+    }
+
+    fn dir(&self, node: &Node) -> Dir {
+        match node.start {
+            // ".." entries point to the root with cluster 0
+            0 if self.fat_type == FatType::Fat16 => Dir::Root,
+            0 => Dir::Chain(self.root_cluster),
+            start => Dir::Chain(start),
+        }
+    }
+
+    /// The index-th sector of the directory
+    fn dir_sector(&mut self, dir: Dir, index: u32) -> Result<Option<u32>, &'static str> {
+        match dir {
+            Dir::Root => Ok((index < self.root_sectors).then_some(self.root_start + index)),
+            Dir::Chain(start) => Ok(self
+                .cluster_at(start, index / self.sectors_per_cluster)?
+                .map(|cluster| self.cluster_sector(cluster) + index % self.sectors_per_cluster)),
+        }
+    }
+
+    /// Finds the entry with the name in the directory
+    fn find(&mut self, dir: Dir, name: &[u8; 11]) -> Result<Option<Node>, &'static str> {
+        for index in 0.. {
+            let Some(sector) = self.dir_sector(dir, index)? else {
+                break;
+            };
+            self.load(sector)?;
+            for slot in 0..ENTRIES_PER_SECTOR {
+                let entry = &self.buf[slot as usize * ENTRY_SIZE..][..ENTRY_SIZE];
+                match entry[0] {
+                    0 => return Ok(None),
+                    DELETED => continue,
+                    _ => (),
+                }
+                if entry[11] & LONG_NAME == LONG_NAME || entry[11] & VOLUME_ID != 0 {
+                    continue;
+                }
+                if entry[..11] == name[..] {
+                    return Ok(Some(Node {
+                        size: read_u32(entry, 28),
+                        is_dir: entry[11] & DIRECTORY != 0,
+                        start: ((read_u16(entry, 20) as u32) << 16) | read_u16(entry, 26) as u32,
+                        entry: sector * ENTRIES_PER_SECTOR + slot,
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds a free entry in the directory and grows it if there is none
+    fn free_entry(&mut self, dir: Dir) -> Result<u32, &'static str> {
+        let mut index = 0;
+        while let Some(sector) = self.dir_sector(dir, index)? {
+            self.load(sector)?;
+            for slot in 0..ENTRIES_PER_SECTOR {
+                if matches!(self.buf[slot as usize * ENTRY_SIZE], 0 | DELETED) {
+                    return Ok(sector * ENTRIES_PER_SECTOR + slot);
+                }
+            }
+            index += 1;
+        }
+        let Dir::Chain(start) = dir else {
+            return Err("The root directory is full");
+        };
+        let last = self.cluster_at(start, index / self.sectors_per_cluster - 1)?;
+        let cluster = self.allocate(last)?;
+        // A new directory cluster must be empty
+        self.buf = [0; SECTOR_SIZE];
+        for i in 0..self.sectors_per_cluster {
+            self.buffered = Some(self.cluster_sector(cluster) + i);
+            self.store()?;
+        }
+        Ok(self.cluster_sector(cluster) * ENTRIES_PER_SECTOR)
+    }
+
+    /// Writes size and first cluster of the node into its directory entry
+    fn update_entry(&mut self, node: &Node) -> Result<(), &'static str> {
+        if node.entry == ROOT_ENTRY {
+            return Ok(());
+        }
+        self.load(node.entry / ENTRIES_PER_SECTOR)?;
+        let offset = (node.entry % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE;
+        let entry = &mut self.buf[offset..offset + ENTRY_SIZE];
+        entry[20..22].copy_from_slice(&((node.start >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(node.start as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&node.size.to_le_bytes());
+        self.store()
+    }
+}
+
+impl FileSystem for Fat {
+    fn lookup(&mut self, path: &str) -> Result<Node, &'static str> {
+        let mut node = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if !node.is_dir {
+                return Err("Not a directory");
+            }
+            let dir = self.dir(&node);
+            node = self.find(dir, &short_name(name)?)?.ok_or("No such file")?;
+        }
+        Ok(node)
+    }
+
+    fn create(&mut self, path: &str) -> Result<Node, &'static str> {
+        let (parent, name) = split(path);
+        let name = short_name(name)?;
+        let parent = self.lookup(parent)?;
+        if !parent.is_dir {
+            return Err("Not a directory");
+        }
+        let dir = self.dir(&parent);
+        if self.find(dir, &name)?.is_some() {
+            return Err("The file exists");
+        }
+        let entry = self.free_entry(dir)?;
+        self.load(entry / ENTRIES_PER_SECTOR)?;
+        let offset = (entry % ENTRIES_PER_SECTOR) as usize * ENTRY_SIZE;
+        self.buf[offset..offset + ENTRY_SIZE].fill(0);
+        self.buf[offset..offset + 11].copy_from_slice(&name);
+        self.buf[offset + 11] = ARCHIVE;
+        self.store()?;
+        Ok(Node {
+            size: 0,
+            is_dir: false,
+            start: 0,
+            entry,
+        })
+    }
+
+    fn read(&mut self, node: &Node, offset: u32, buf: &mut [u8]) -> Result<usize, &'static str> {
+        if node.is_dir {
+            return Err("Is a directory");
+        }
+        let len = buf.len().min(node.size.saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u32;
+            let cluster = self
+                .cluster_at(node.start, position / self.cluster_bytes())?
+                .ok_or("Broken cluster chain")?;
+            let in_cluster = position % self.cluster_bytes();
+            self.load(self.cluster_sector(cluster) + in_cluster / SECTOR_SIZE as u32)?;
+            let in_sector = (in_cluster % SECTOR_SIZE as u32) as usize;
+            let count = (len - done).min(SECTOR_SIZE - in_sector);
+            buf[done..done + count].copy_from_slice(&self.buf[in_sector..in_sector + count]);
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, node: &mut Node, offset: u32, data: &[u8]) -> Result<usize, &'static str> {
+        if node.is_dir {
+            return Err("Is a directory");
+        }
+        if offset > node.size {
+            return Err("Write beyond the end of the file");
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u32;
+            let index = position / self.cluster_bytes();
+            let cluster = match self.cluster_at(node.start, index)? {
+                Some(cluster) => cluster,
+                None if index == 0 => {
+                    node.start = self.allocate(None)?;
+                    node.start
+                }
+                None => {
+                    let last = self.cluster_at(node.start, index - 1)?;
+                    self.allocate(last)?
+                }
+            };
+            let in_cluster = position % self.cluster_bytes();
+            self.load(self.cluster_sector(cluster) + in_cluster / SECTOR_SIZE as u32)?;
+            let in_sector = (in_cluster % SECTOR_SIZE as u32) as usize;
+            let count = (data.len() - done).min(SECTOR_SIZE - in_sector);
+            self.buf[in_sector..in_sector + count].copy_from_slice(&data[done..done + count]);
+            self.store()?;
+            done += count;
+        }
+        node.size = node.size.max(offset + done as u32);
+        self.update_entry(node)?;
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> Result<(), &'static str> {
+        Ok(self.device.flush()?)
+    }
+}
@@ -0,0 +1,486 @@
+//! A GDB remote stub over the dbgu
+//!
+//! With GDB_STUB in consts.rs, the stub is entered when gdb sends a packet or ctrl+c over the dbgu,
+//! or when a thread hits a breakpoint.
+//! While it runs, the whole system is halted (interrupts are masked and the dbgu is polled).
+//! It works on the saved registers in the ThreadList, so every user thread can be inspected.
+//! GDB thread ids are our ids + 1, because gdb doesn't like the id 0.
+//!
+//! Breakpoints replace the instruction with an undefined one, which lands in the und_handler.
+//! The ARM920T can't single-step, so a step sets temporary breakpoints on the possible next instructions.
+//! Only ARM code is supported (no Thumb).
+//!
+//! Use: `arm-none-eabi-gdb kernel`, `set remotebaud 115200`, `target remote /dev/ttyS0`
+
+use crate::{
+    serial::Serial,
+    thread::{ThreadList, ID},
+    util::without_interrupts,
+    watchdog, KERNEL_MEM,
+};
+use core::{
+    fmt::{self, Write},
+    ptr::{addr_of, addr_of_mut, read_volatile, write_volatile},
+};
+
+/// The instruction gdb itself uses for ARM breakpoints, it is permanently undefined
+pub const BREAKPOINT: u32 = 0xE7FF_DEFE;
+const MAX_BREAKPOINTS: usize = 16;
+const PACKET_SIZE: usize = 1024;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u32,
+    original: u32,
+}
+
+static mut BREAKPOINTS: [Option<Breakpoint>; MAX_BREAKPOINTS] = [None; MAX_BREAKPOINTS];
+/// The temporary breakpoints of a single step
+static mut STEP: [Option<Breakpoint>; 2] = [None; 2];
+/// The thread that g, G, p and P work on
+static mut SELECTED: Option<ID> = None;
+
+/// Called by the dbgu for ctrl+c (3) and '$'
+pub fn enter(threads: &mut ThreadList, char: u8) {
+    without_interrupts(|| {
+        if char == 3 {
+            stop_reply(threads, SIGINT);
+            session(threads, false);
+        } else {
+            session(threads, true);
+        }
+    })
+}
+
+/// Called by the und_handler. The registers of the current thread must already be saved.
+/// Returns false if there is no breakpoint of ours at pc
+pub fn on_breakpoint(threads: &mut ThreadList) -> bool {
+    let pc = threads.curr_thread().regs.pc;
+    let (breakpoints, step) = unsafe { (&*addr_of!(BREAKPOINTS), &*addr_of!(STEP)) };
+    let known = breakpoints
+        .iter()
+        .chain(step.iter())
+        .flatten()
+        .any(|bp| bp.addr == pc);
+    if !known {
+        return false;
+    }
+    clear_step();
+    unsafe { SELECTED = None };
+    stop_reply(threads, SIGTRAP);
+    session(threads, false);
+    true
+}
+
+/// Handles packets until gdb lets the system continue
+fn session(threads: &mut ThreadList, mut started: bool) {
+    let mut packet = [0; PACKET_SIZE];
+    // The system is halted, so nobody pets the watchdog
+    watchdog::pause();
+    loop {
+        let len = receive(&mut packet, started);
+        started = false;
+        if !handle(threads, &packet[..len]) {
+            break;
+        }
+    }
+    watchdog::resume();
+}
+
+/// Handles one packet. Returns false if the system should continue
+fn handle(threads: &mut ThreadList, packet: &[u8]) -> bool {
+    let mut out = Packet::new();
+    let (&cmd, args) = match packet.split_first() {
+        Some(split) => split,
+        None => {
+            send(&out);
+            return true;
+        }
+    };
+    let ok = match cmd {
+        b'?' => {
+            stop_reply(threads, SIGTRAP);
+            return true;
+        }
+        b'g' => read_registers(threads, &mut out),
+        b'G' => write_registers(threads, args),
+        b'p' => parse_hex(args).and_then(|n| read_register(threads, n as usize, &mut out)),
+        b'P' => write_register(threads, args),
+        b'm' => read_memory(args, &mut out),
+        b'M' => write_memory(args),
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                threads.curr_mut_thread().regs.pc = addr;
+            }
+            if cmd == b's' {
+                set_step(threads);
+            }
+            return false;
+        }
+        b'D' => {
+            out.push_str("OK");
+            send(&out);
+            return false;
+        }
+        b'k' => return false,
+        b'Z' | b'z' => breakpoint(cmd == b'Z', args),
+        b'H' => select_thread(threads, args),
+        b'T' => parse_hex(args)
+            .and_then(|tid| threads.get_thread(tid.checked_sub(1)? as ID))
+            .map(|_| ()),
+        b'q' => query(threads, args, &mut out),
+        // unsupported, answered with an empty packet
+        _ => Some(()),
+    };
+    match ok {
+        // These commands have no answer on success
+        Some(())
+            if out.len == 0 && matches!(cmd, b'G' | b'P' | b'M' | b'Z' | b'z' | b'H' | b'T') =>
+        {
+            out.push_str("OK")
+        }
+        Some(()) => (),
+        None => {
+            out.len = 0;
+            out.push_str("E01")
+        }
+    }
+    send(&out);
+    true
+}
+
+fn stop_reply(threads: &ThreadList, signal: u8) {
+    let mut out = Packet::new();
+    _ = write!(out, "T{signal:02x}thread:{:x};", threads.curr_thread + 1);
+    send(&out);
+}
+
+// Registers
+
+/// The thread selected with Hg, otherwise the current one
+fn selected(threads: &mut ThreadList) -> Option<&mut crate::thread::Thread> {
+    let id = unsafe { SELECTED }.unwrap_or(threads.curr_thread);
+    threads.array.get_mut(id)?.as_mut()
+}
+
+/// gdb's arm layout: r0-r15, f0-f7 (12 bytes each), fps, cpsr
+fn read_registers(threads: &mut ThreadList, out: &mut Packet) -> Option<()> {
+    let thread = selected(threads)?;
+    for &reg in thread.regs.as_array() {
+        out.push_word(reg);
+    }
+    for _ in 0..8 * 3 + 1 {
+        out.push_word(0);
+    }
+    out.push_word(thread.psr);
+    Some(())
+}
+
+fn write_registers(threads: &mut ThreadList, args: &[u8]) -> Option<()> {
+    let thread = selected(threads)?;
+    for (i, reg) in thread.regs.as_array_mut().iter_mut().enumerate() {
+        *reg = parse_word(args.get(i * 8..i * 8 + 8)?)?;
+    }
+    // we don't let gdb change the mode of a thread, so the cpsr is ignored
+    Some(())
+}
+
+fn read_register(threads: &mut ThreadList, n: usize, out: &mut Packet) -> Option<()> {
+    let thread = selected(threads)?;
+    match n {
+        0..=15 => out.push_word(thread.regs.as_array()[n]),
+        16..=23 => out.push_str("000000000000000000000000"),
+        24 => out.push_word(0),
+        25 => out.push_word(thread.psr),
+        _ => return None,
+    }
+    Some(())
+}
+
+fn write_register(threads: &mut ThreadList, args: &[u8]) -> Option<()> {
+    let (n, value) = split(args, b'=')?;
+    let (n, value) = (parse_hex(n)? as usize, parse_word(value)?);
+    let thread = selected(threads)?;
+    match n {
+        0..=15 => thread.regs.as_array_mut()[n] = value,
+        // flags only, the mode stays
+        25 => thread.psr = (thread.psr & 0x0FFF_FFFF) | (value & 0xF000_0000),
+        _ => (),
+    }
+    Some(())
+}
+
+fn select_thread(threads: &ThreadList, args: &[u8]) -> Option<()> {
+    let (&op, tid) = args.split_first()?;
+    if op != b'g' {
+        // Hc, we can only continue everything
+        return Some(());
+    }
+    unsafe {
+        SELECTED = match tid {
+            b"0" | b"-1" => None,
+            _ => {
+                let id = (parse_hex(tid)? as ID).checked_sub(1)?;
+                threads.get_thread(id)?;
+                Some(id)
+            }
+        }
+    }
+    Some(())
+}
+
+fn query(threads: &ThreadList, args: &[u8], out: &mut Packet) -> Option<()> {
+    if args.starts_with(b"Supported") {
+        _ = write!(out, "PacketSize={:x}", PACKET_SIZE - 4);
+    } else if args == b"fThreadInfo" {
+        out.push_str("m");
+        for (i, thread) in threads.array.iter().flatten().enumerate() {
+            _ = write!(out, "{}{:x}", if i == 0 { "" } else { "," }, thread.id + 1);
+        }
+    } else if args == b"sThreadInfo" {
+        out.push_str("l");
+    } else if args == b"C" {
+        _ = write!(out, "QC{:x}", threads.curr_thread + 1);
+    } else if args == b"Attached" {
+        out.push_str("1");
+    }
+    Some(())
+}
+
+// Memory
+
+/// Whether gdb may access the memory: RAM and the internal SRAM at 0 (after the remap)
+fn accessible(addr: u32, len: u32) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    (addr >= 0x2000_0000 && end <= KERNEL_MEM as u32) || end <= 0x4000
+}
+
+fn read_memory(args: &[u8], out: &mut Packet) -> Option<()> {
+    let (addr, len) = split(args, b',')?;
+    let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+    if !accessible(addr, len) || len as usize * 2 > PACKET_SIZE - 4 {
+        return None;
+    }
+    for i in 0..len {
+        out.push_byte(unsafe { read_volatile((addr + i) as *const u8) });
+    }
+    Some(())
+}
+
+fn write_memory(args: &[u8]) -> Option<()> {
+    let (header, data) = split(args, b':')?;
+    let (addr, len) = split(header, b',')?;
+    let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+    if !accessible(addr, len) || data.len() < len as usize * 2 {
+        return None;
+    }
+    for i in 0..len as usize {
+        let byte = parse_hex(&data[i * 2..i * 2 + 2])? as u8;
+        unsafe { write_volatile((addr + i as u32) as *mut u8, byte) }
+    }
+    Some(())
+}
+
+// Breakpoints
+
+fn insert(addr: u32) -> Option<Breakpoint> {
+    if !accessible(addr, 4) || addr % 4 != 0 {
+        return None;
+    }
+    let original = unsafe { read_volatile(addr as *const u32) };
+    unsafe { write_volatile(addr as *mut u32, BREAKPOINT) }
+    Some(Breakpoint { addr, original })
+}
+
+fn remove(bp: Breakpoint) {
+    unsafe { write_volatile(bp.addr as *mut u32, bp.original) }
+}
+
+/// Z0,addr,kind and z0,addr,kind. Only software breakpoints
+fn breakpoint(set: bool, args: &[u8]) -> Option<()> {
+    let (kind, rest) = split(args, b',')?;
+    if kind != b"0" {
+        return None;
+    }
+    let addr = parse_hex(split(rest, b',').map_or(rest, |(addr, _)| addr))?;
+    let breakpoints = unsafe { &mut *addr_of_mut!(BREAKPOINTS) };
+    let existing = breakpoints
+        .iter()
+        .position(|bp| matches!(bp, Some(bp) if bp.addr == addr));
+    match (set, existing) {
+        (true, Some(_)) => (),
+        (true, None) => {
+            let slot = breakpoints.iter().position(|bp| bp.is_none())?;
+            breakpoints[slot] = Some(insert(addr)?);
+        }
+        (false, Some(i)) => remove(breakpoints[i].take()?),
+        (false, None) => return None,
+    }
+    Some(())
+}
+
+/// Sets temporary breakpoints on every instruction that can follow the one at pc
+fn set_step(threads: &mut ThreadList) {
+    let thread = threads.curr_thread();
+    let regs = thread.regs.as_array();
+    let pc = thread.regs.pc;
+    // pc reads 8 bytes ahead
+    let reg = |n: u32| if n == 15 { pc + 8 } else { regs[n as usize] };
+    let instr = unsafe { read_volatile(pc as *const u32) };
+    let target = if instr & 0x0E00_0000 == 0x0A00_0000 {
+        // b, bl: signed 24 bit word offset
+        let offset = ((instr << 8) as i32 >> 6) as u32;
+        Some(pc.wrapping_add(8).wrapping_add(offset))
+    } else if instr & 0x0FFF_FFF0 == 0x012F_FF10 {
+        // bx rm
+        Some(reg(instr & 0xF) & !1)
+    } else if instr & 0x0FEF_FFF0 == 0x01A0_F000 {
+        // mov pc, rm
+        Some(reg(instr & 0xF))
+    } else {
+        // loads into pc are not decoded, the step then only stops at the next instruction
+        None
+    };
+    let always = instr >> 28 == 0xE;
+    let next = match target {
+        Some(target) if always => [Some(target), None],
+        target => [Some(pc + 4), target],
+    };
+    for (slot, addr) in unsafe { (*addr_of_mut!(STEP)).iter_mut() }.zip(next) {
+        *slot = addr.and_then(insert);
+    }
+}
+
+fn clear_step() {
+    // in reverse, in case both are at the same address
+    for slot in unsafe { (*addr_of_mut!(STEP)).iter_mut() }.rev() {
+        if let Some(bp) = slot.take() {
+            remove(bp);
+        }
+    }
+}
+
+// The protocol
+
+/// Receives a packet into buf and acknowledges it. Returns its length.
+/// If started is true, the '$' was already read
+fn receive(buf: &mut [u8; PACKET_SIZE], mut started: bool) -> usize {
+    let serial = Serial::new();
+    loop {
+        while !started {
+            started = serial.read() == b'$';
+        }
+        started = false;
+        let mut len = 0;
+        let mut sum: u8 = 0;
+        loop {
+            let char = serial.read();
+            if char == b'#' {
+                break;
+            }
+            sum = sum.wrapping_add(char);
+            if len < PACKET_SIZE {
+                buf[len] = char;
+                len += 1;
+            }
+        }
+        let checksum = [serial.read(), serial.read()];
+        if parse_hex(&checksum) == Some(sum as u32) {
+            serial.write(b'+');
+            return len;
+        }
+        serial.write(b'-');
+    }
+}
+
+/// Sends the packet until gdb acknowledges it
+fn send(packet: &Packet) {
+    let serial = Serial::new();
+    loop {
+        serial.write(b'$');
+        let mut sum: u8 = 0;
+        for &char in &packet.buf[..packet.len] {
+            serial.write(char);
+            sum = sum.wrapping_add(char);
+        }
+        serial.write(b'#');
+        serial.write(HEX[(sum >> 4) as usize]);
+        serial.write(HEX[(sum & 0xF) as usize]);
+        if serial.read() != b'-' {
+            return;
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+struct Packet {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    fn new() -> Self {
+        Packet {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for &char in s.as_bytes() {
+            if self.len < PACKET_SIZE - 4 {
+                self.buf[self.len] = char;
+                self.len += 1;
+            }
+        }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        let hex = [HEX[(byte >> 4) as usize], HEX[(byte & 0xF) as usize]];
+        self.push_str(core::str::from_utf8(&hex).unwrap());
+    }
+
+    /// Words are sent in target byte order (little endian)
+    fn push_word(&mut self, word: u32) {
+        for byte in word.to_le_bytes() {
+            self.push_byte(byte);
+        }
+    }
+}
+
+impl Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+fn split(args: &[u8], at: u8) -> Option<(&[u8], &[u8])> {
+    let i = args.iter().position(|&c| c == at)?;
+    Some((&args[..i], &args[i + 1..]))
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u32> {
+    if hex.is_empty() || hex.len() > 8 {
+        return None;
+    }
+    hex.iter().try_fold(0, |acc, &c| {
+        let digit = (c as char).to_digit(16)?;
+        Some(acc << 4 | digit)
+    })
+}
+
+/// A little endian word as 8 hex digits
+fn parse_word(hex: &[u8]) -> Option<u32> {
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = parse_hex(hex.get(i * 2..i * 2 + 2)?)? as u8;
+    }
+    Some(u32::from_le_bytes(bytes))
+}
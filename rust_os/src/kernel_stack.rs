@@ -0,0 +1,95 @@
+//! Overflow detection for the kernel mode stacks
+//!
+//! `_start` carves one stack of KERNEL_STACK_SIZE per mode downward from KERNEL_MEM.
+//! The last one (sys) is used by `start` and later by nested interrupt handlers.
+//! We paint these stacks with canaries at startup. The lowest KERNEL_STACK_GUARD_SIZE bytes
+//! of each stack must always keep their canaries, the rest tells us the peak usage.
+
+use crate::{
+    consts::{
+        ABT_MODE, FIQ_MODE, IRQ_MODE, KERNEL_MEM, KERNEL_STACK_GUARD_SIZE, KERNEL_STACK_NUMBER,
+        KERNEL_STACK_SIZE, STACK_CANARY, SVC_MODE, SYS_MODE, UND_MODE,
+    },
+    get_reg, println, show_mode,
+};
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+};
+
+/// The modes with their own kernel stack in the order in which `_start` sets them up
+const MODES: [u32; KERNEL_STACK_NUMBER] =
+    [SVC_MODE, UND_MODE, ABT_MODE, IRQ_MODE, FIQ_MODE, SYS_MODE];
+
+#[inline(always)]
+fn stack_top(index: usize) -> usize {
+    KERNEL_MEM - index * KERNEL_STACK_SIZE
+}
+
+#[inline(always)]
+fn stack_bottom(index: usize) -> *mut u32 {
+    (stack_top(index) - KERNEL_STACK_SIZE) as *mut u32
+}
+
+/// Paints all kernel mode stacks with canaries.
+/// Must be called from `start` (which runs on the sys stack) before any exception can happen.
+/// The part of the sys stack that is already in use is left alone
+pub fn init() {
+    get_reg!(sp = sp);
+    for (index, &mode) in MODES.iter().enumerate() {
+        let bottom = stack_bottom(index);
+        let top = if mode == SYS_MODE {
+            // leave some space for this function
+            sp as usize - 256
+        } else {
+            stack_top(index)
+        };
+        for i in 0..(top - bottom as usize) / 4 {
+            unsafe { write_volatile(bottom.add(i), STACK_CANARY) }
+        }
+    }
+}
+
+/// The peak usage of the stack at the given index in bytes
+fn peak_usage(index: usize) -> usize {
+    let bottom = stack_bottom(index);
+    let untouched = (0..KERNEL_STACK_SIZE / 4)
+        .take_while(|&i| unsafe { read_volatile(bottom.add(i)) } == STACK_CANARY)
+        .count();
+    KERNEL_STACK_SIZE - untouched * 4
+}
+
+/// Whether the guard region of the stack at the given index is still intact
+fn guard_intact(index: usize) -> bool {
+    let bottom = stack_bottom(index);
+    (0..KERNEL_STACK_GUARD_SIZE / 4)
+        .all(|i| unsafe { read_volatile(bottom.add(i)) } == STACK_CANARY)
+}
+
+/// Checks the canaries of all kernel mode stacks and panics if one of them was overwritten.
+/// Called by every trampoline on handler exit
+pub extern "aapcs" fn check() {
+    for (index, &mode) in MODES.iter().enumerate() {
+        if !guard_intact(index) {
+            panic!(
+                "Kernel stack overflow in {} mode (peak usage: {} of {} bytes)",
+                show_mode(mode),
+                peak_usage(index),
+                KERNEL_STACK_SIZE
+            )
+        }
+    }
+}
+
+/// Prints the peak usage of all kernel mode stacks and checks their canaries
+pub fn report() {
+    for (index, &mode) in MODES.iter().enumerate() {
+        println!(
+            "{} stack: {} of {} bytes used",
+            show_mode(mode),
+            peak_usage(index),
+            KERNEL_STACK_SIZE
+        );
+    }
+    check();
+}
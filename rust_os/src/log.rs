@@ -0,0 +1,185 @@
+//! The kernel log
+//!
+//! Records are written with the macros `error!`, `warn!`, `info!`, `debug!` and `trace!`
+//! into a ring buffer. That never waits for the serial line, so it can be used from interrupt context.
+//! Every record starts with the wall clock time (UTC), or with the seconds since boot while the clock isn't set.
+//! The idle thread hands new records to the dbgu whenever nothing else runs, the PDC then sends them.
+//! Which records are kept is decided by LOG_LEVEL and LOG_FILTERS in consts.rs.
+//! User programs can read the buffer with the Dmesg syscall.
+
+use crate::{
+    clock::{self, DateTime},
+    consts::{LOG_BUFFER_SIZE, LOG_FILTERS, LOG_LEVEL},
+    serial::Serial,
+    util::without_interrupts,
+};
+use core::{
+    fmt::{self, Write},
+    ptr::{addr_of, addr_of_mut},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Positions count all bytes ever written, the index into the buffer is position % LOG_BUFFER_SIZE
+struct Ring {
+    buffer: [u8; LOG_BUFFER_SIZE],
+    /// Where the next byte is written
+    head: usize,
+    /// Everything before this was already written to the dbgu
+    flushed: usize,
+}
+
+static mut RING: Ring = Ring {
+    buffer: [0; LOG_BUFFER_SIZE],
+    head: 0,
+    flushed: 0,
+};
+
+impl Ring {
+    /// The oldest position that was not yet overwritten
+    #[inline(always)]
+    fn oldest(&self) -> usize {
+        self.head.saturating_sub(LOG_BUFFER_SIZE)
+    }
+
+    #[inline(always)]
+    fn at(&self, pos: usize) -> u8 {
+        self.buffer[pos % LOG_BUFFER_SIZE]
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.buffer[self.head % LOG_BUFFER_SIZE] = byte;
+            self.head += 1;
+        }
+        Ok(())
+    }
+}
+
+/// The level up to which records of the given module are kept.
+/// The longest matching prefix in LOG_FILTERS wins
+fn max_level(module: &str) -> Level {
+    LOG_FILTERS
+        .iter()
+        .filter(|(prefix, _)| module.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(LOG_LEVEL, |&(_, level)| level)
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &str, args: fmt::Arguments) {
+    if level > max_level(module) {
+        return;
+    }
+    without_interrupts(|| {
+        let ring = unsafe { &mut *addr_of_mut!(RING) };
+        _ = match clock::now() {
+            Some(time) => write!(
+                ring,
+                "[{}.{:03} ",
+                DateTime::from_unix(time.secs as u64),
+                time.usecs / 1000
+            ),
+            None => {
+                let ms = clock::uptime_ms();
+                write!(ring, "[{:>5}.{:03} ", ms / 1000, ms % 1000)
+            }
+        };
+        _ = writeln!(ring, "{} {module}] {args}", level.name());
+    });
+}
+
+/// Queues all records that are not yet on the dbgu, as far as the transmit queue has room.
+/// The rest follows on the next call
+pub fn flush() {
+    let serial = Serial::new();
+    without_interrupts(|| {
+        let ring = unsafe { &mut *addr_of_mut!(RING) };
+        // Records that were overwritten before we got to them are lost
+        ring.flushed = ring.flushed.max(ring.oldest());
+        while ring.flushed != ring.head {
+            let start = ring.flushed % LOG_BUFFER_SIZE;
+            let end = start + (ring.head - ring.flushed).min(LOG_BUFFER_SIZE - start);
+            let queued = serial.write_buffered(&ring.buffer[start..end]);
+            ring.flushed += queued;
+            if queued < end - start {
+                return;
+            }
+        }
+    })
+}
+
+/// Writes the whole buffer to the dbgu, without caring about what was already written.
+/// Used by the panic handler
+pub fn dump() {
+    let ring = unsafe { &*addr_of!(RING) };
+    let serial = Serial::new();
+    for pos in ring.oldest()..ring.head {
+        serial.write(ring.at(pos));
+    }
+}
+
+/// Copies the newest records into buf. Returns the number of copied bytes
+pub fn read_into(buf: &mut [u8]) -> usize {
+    without_interrupts(|| {
+        let ring = unsafe { &*addr_of!(RING) };
+        let start = ring.oldest().max(ring.head.saturating_sub(buf.len()));
+        for (i, pos) in (start..ring.head).enumerate() {
+            buf[i] = ring.at(pos);
+        }
+        ring.head - start
+    })
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::log::_log($level, module_path!(), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
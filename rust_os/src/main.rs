@@ -6,8 +6,18 @@
 #![feature(naked_functions)]
 #![feature(generic_arg_infer)]
 
+mod block;
+mod clock;
 mod consts;
+mod crash;
 mod driver;
+mod fs;
+mod gdb;
+mod kernel_stack;
+mod log;
+mod net;
+mod panic;
+mod symbols;
 mod thread;
 mod user;
 mod util;
@@ -15,20 +25,21 @@ use consts::*;
 use core::arch::asm;
 // stuff
 use driver::*;
+use emac::Emac;
 use exceptions::{AIC, IVT};
+use gpio::{Pio, PORTS};
+use mci::Mci;
 use memory_controller::remap;
-use serial::Serial;
+use power_management::PMC;
+use registry::get_registry;
+use serial::{Serial, TTY_NUMBER};
+use spi::Spi;
 use sys_timer::SysTimer;
 use thread::get_threads;
+use timer_counter::{TimerCounter, CHANNEL_NUMBER};
+use twi::Twi;
 use util::Registers;
 
-#[panic_handler]
-fn panic_handler(info: &core::panic::PanicInfo) -> ! {
-    util::mask_interrupts();
-    println!("\nPanicked: {info:?}");
-    loop {}
-}
-
 #[naked]
 #[no_mangle]
 #[link_section = ".init"]
@@ -59,6 +70,12 @@ extern "aapcs" fn _start() {
             msr CPSR, v2
             mov sp, v1
             sub v1, #{STACK_SIZE}",
+            // fiq
+            "bic v2, #{RESET}
+            orr v2, #{FIQ}
+            msr CPSR, v2
+            mov sp, v1
+            sub v1, #{STACK_SIZE}",
             // sys & usr
             "orr v2, #{SYS}
             msr CPSR, v2
@@ -70,6 +87,7 @@ extern "aapcs" fn _start() {
             UND = const UND_MODE,
             ABT = const ABT_MODE,
             IRQ = const IRQ_MODE,
+            FIQ = const FIQ_MODE,
             SYS = const SYS_MODE,
             RESET = const MODE_RESET,
             start = sym start,
@@ -84,17 +102,44 @@ extern "aapcs" {
 
 #[link_section = ".init"]
 extern "aapcs" fn start() -> ! {
+    kernel_stack::init();
     remap();
     IVT::new().init();
     AIC::new().init();
-    Serial::new().init().enable_interrupts();
-    SysTimer::new().init().set_interval(TIME_SLICE as u16);
-    println!("Initialized the sys timer with {MS_PER_SLICE} ms per slice");
-    println!("Kernel start");
-    // Create the main user thread
-    get_threads()
-        .init()
-        .create_thread(thread!(main_thread()))
-        .unwrap();
-    util::idle(); // we just wait for the first timer interrupt
+    let registry = get_registry();
+    // First, since it may change the master clock the others derive their clocks from
+    registry.register(PMC::new()).unwrap();
+    registry.register(Serial::new()).unwrap();
+    registry.register(SysTimer::new()).unwrap();
+    // The drivers below may already busy wait
+    util::calibrate_delay();
+    for tty in 1..TTY_NUMBER {
+        registry.register(Serial::tty(tty).unwrap()).unwrap();
+    }
+    for port in PORTS {
+        registry.register(Pio::new(port)).unwrap();
+    }
+    led::init_status();
+    for channel in 0..CHANNEL_NUMBER {
+        registry.register(TimerCounter::new(channel)).unwrap();
+    }
+    timer_counter::init_counter();
+    registry.register(Spi::new()).unwrap();
+    registry.register(Twi::new()).unwrap();
+    clock::init();
+    registry.register(Emac::new()).unwrap();
+    registry.register(Mci::new()).unwrap();
+    fs::init();
+    util::delay_self_test();
+    if let Some((handler, src_type)) = FIQ {
+        AIC::new().route_fiq(handler, src_type);
+    }
+    info!("Initialized the sys timer with {MS_PER_SLICE} ms per slice");
+    info!("Kernel start");
+    // Create the main user thread and run it
+    let threads = get_threads().init();
+    threads.create_thread(thread!(main_thread())).unwrap();
+    // Only now, the boot polls devices with masked interrupts and nothing would pet it
+    watchdog::init();
+    threads.start()
 }
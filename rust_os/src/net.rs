@@ -0,0 +1,185 @@
+//! Ein kleiner IPv4-Stack über dem EMAC
+//!
+//! - arp: Löst IP-Adressen im lokalen Netz in MAC-Adressen auf
+//! - ip: IPv4 ohne Fragmentierung, mit Routing über das Gateway
+//! - icmp: Beantwortet Pings
+//! - udp: Datagramme
+//! - tcp: Verbindungen mit Handshake, Retransmission und Abbau, aber ohne Out-of-order-Puffer
+//! - socket: Die Socket-Tabelle und der Net-Syscall
+//!
+//! Alles liegt in statischen Puffern. Empfangen wird im EMAC-Interrupt, die Timer laufen im
+//! Sys-Timer-Interrupt. Beide haben die niedrigste Priorität und unterbrechen sich nicht gegenseitig,
+//! Syscalls laufen mit maskierten Interrupts.
+
+pub mod arp;
+pub mod icmp;
+pub mod ip;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
+
+use crate::{
+    consts::NET_MAC,
+    emac::{self, Emac, FRAME_SIZE},
+    sys_timer::{ticks_between, SysTimer},
+};
+
+pub type Mac = [u8; 6];
+pub type Ipv4 = [u8; 4];
+
+pub const BROADCAST_MAC: Mac = [0xFF; 6];
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+/// Destination, source and ethertype
+const ETHERNET_HEADER: usize = 14;
+/// The biggest payload of a frame
+pub const MTU: usize = 1500;
+
+/// Dispatches a received frame. Called by the EMAC
+pub fn receive(frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER..];
+    match read_u16(frame, 12) {
+        ETHERTYPE_ARP => arp::receive(payload),
+        ETHERTYPE_IPV4 => ip::receive(payload),
+        _ => (),
+    }
+}
+
+/// Sends the payload in a frame to the destination. Returns false if the EMAC is busy
+fn send(destination: Mac, ethertype: u16, payload: &[u8]) -> bool {
+    let len = ETHERNET_HEADER + payload.len();
+    if payload.len() > MTU {
+        return false;
+    }
+    let mut frame = [0; FRAME_SIZE];
+    frame[0..6].copy_from_slice(&destination);
+    frame[6..12].copy_from_slice(&NET_MAC);
+    write_u16(&mut frame, 12, ethertype);
+    frame[ETHERNET_HEADER..len].copy_from_slice(payload);
+    Emac::new().send(&frame[..len])
+}
+
+/// Runs the timers of the stack (TCP retransmissions and the like) and the link check of the EMAC.
+/// Called by the sys timer
+pub fn tick(now: u32) {
+    emac::tick(now);
+    tcp::tick(now);
+}
+
+/// The next tick at which a timer of the stack is due, for the tickless mode
+pub fn next_deadline() -> Option<u32> {
+    let now = SysTimer::new().now();
+    tcp::next_deadline()
+        .into_iter()
+        .chain(emac::next_link_check())
+        .min_by_key(|&deadline| ticks_between(now, deadline))
+}
+
+/// The internet checksum over data, continuing the sum of e.g. a pseudo header
+pub fn checksum(data: &[u8], mut sum: u32) -> u16 {
+    for pair in data.chunks(2) {
+        sum += match *pair {
+            [high, low] => u16::from_be_bytes([high, low]) as u32,
+            // An odd length is padded with a zero byte
+            [high] => (high as u32) << 8,
+            _ => unreachable!(),
+        };
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[inline(always)]
+pub fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[inline(always)]
+pub fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+#[inline(always)]
+pub fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+#[inline(always)]
+pub fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// A byte ring for the socket buffers
+pub struct Ring<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    pub const fn new() -> Self {
+        Ring {
+            buf: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline(always)]
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    /// Appends as much of data as fits and returns how much that was
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let count = data.len().min(self.free());
+        for (i, &byte) in data[..count].iter().enumerate() {
+            self.buf[(self.start + self.len + i) % N] = byte;
+        }
+        self.len += count;
+        count
+    }
+
+    /// Copies the bytes from offset on into buf without removing them
+    pub fn peek(&self, offset: usize, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len.saturating_sub(offset));
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.buf[(self.start + offset + i) % N];
+        }
+        count
+    }
+
+    /// Removes the first count bytes
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.start = (self.start + count) % N;
+        self.len -= count;
+    }
+
+    /// Moves the first bytes into buf
+    pub fn pop(&mut self, buf: &mut [u8]) -> usize {
+        let count = self.peek(0, buf);
+        self.consume(count);
+        count
+    }
+}
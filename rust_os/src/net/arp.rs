@@ -0,0 +1,92 @@
+//! Das Address Resolution Protocol
+//!
+//! Der Cache merkt sich die zuletzt gesehenen Adressen, der älteste Eintrag wird überschrieben.
+//! Ein Paket an eine unbekannte Adresse wird nicht zurückgehalten: Es wird verworfen und eine Anfrage
+//! gesendet, die Schicht darüber versucht es später noch einmal.
+
+use super::{read_u16, write_u16, Ipv4, Mac, BROADCAST_MAC, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use crate::{
+    consts::{NET_IP, NET_MAC},
+    println,
+};
+use core::ptr::{addr_of, addr_of_mut};
+
+const CACHE_SIZE: usize = 8;
+const PACKET_SIZE: usize = 28;
+const REQUEST: u16 = 1;
+const REPLY: u16 = 2;
+/// Ethernet
+const HARDWARE_TYPE: u16 = 1;
+
+static mut CACHE: [Option<(Ipv4, Mac)>; CACHE_SIZE] = [None; CACHE_SIZE];
+/// The entry that is overwritten next
+static mut NEXT: usize = 0;
+
+/// The MAC address of a host in the local network, if it is known
+pub fn lookup(ip: Ipv4) -> Option<Mac> {
+    unsafe { (*addr_of!(CACHE)).iter().flatten() }
+        .find(|(entry, _)| *entry == ip)
+        .map(|&(_, mac)| mac)
+}
+
+fn insert(ip: Ipv4, mac: Mac) {
+    unsafe {
+        match (*addr_of_mut!(CACHE))
+            .iter_mut()
+            .flatten()
+            .find(|(entry, _)| *entry == ip)
+        {
+            Some(entry) => entry.1 = mac,
+            None => {
+                CACHE[NEXT] = Some((ip, mac));
+                NEXT = (NEXT + 1) % CACHE_SIZE;
+            }
+        }
+    }
+}
+
+/// Asks the local network for the MAC address of ip
+pub fn request(ip: Ipv4) {
+    send(REQUEST, BROADCAST_MAC, [0; 6], ip);
+}
+
+fn send(operation: u16, destination: Mac, target_mac: Mac, target_ip: Ipv4) {
+    let mut packet = [0; PACKET_SIZE];
+    write_u16(&mut packet, 0, HARDWARE_TYPE);
+    write_u16(&mut packet, 2, ETHERTYPE_IPV4);
+    packet[4] = 6;
+    packet[5] = 4;
+    write_u16(&mut packet, 6, operation);
+    packet[8..14].copy_from_slice(&NET_MAC);
+    packet[14..18].copy_from_slice(&NET_IP);
+    packet[18..24].copy_from_slice(&target_mac);
+    packet[24..28].copy_from_slice(&target_ip);
+    super::send(destination, ETHERTYPE_ARP, &packet);
+}
+
+/// Learns the sender of every packet and answers requests for our address
+pub fn receive(packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || read_u16(packet, 0) != HARDWARE_TYPE
+        || read_u16(packet, 2) != ETHERTYPE_IPV4
+    {
+        return;
+    }
+    let mut sender_mac = [0; 6];
+    let mut sender_ip = [0; 4];
+    sender_mac.copy_from_slice(&packet[8..14]);
+    sender_ip.copy_from_slice(&packet[14..18]);
+    if packet[24..28] != NET_IP {
+        return;
+    }
+    insert(sender_ip, sender_mac);
+    if read_u16(packet, 6) == REQUEST {
+        send(REPLY, sender_mac, sender_mac, sender_ip);
+    }
+}
+
+pub fn print() {
+    for (ip, mac) in unsafe { (*addr_of!(CACHE)).iter().flatten() } {
+        println!("{ip:?} at {mac:02x?}");
+    }
+}
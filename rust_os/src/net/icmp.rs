@@ -0,0 +1,30 @@
+//! ICMP. Wir beantworten nur Echo Requests (Pings)
+
+use super::{
+    checksum,
+    ip::{self, MAX_PAYLOAD, PROTOCOL_ICMP},
+    write_u16, Ipv4,
+};
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const HEADER_SIZE: usize = 8;
+
+pub fn receive(source: Ipv4, message: &[u8]) {
+    if message.len() < HEADER_SIZE
+        || message.len() > MAX_PAYLOAD
+        || message[0] != ECHO_REQUEST
+        || checksum(message, 0) != 0
+    {
+        return;
+    }
+    // The reply carries the identifier, sequence number and data of the request
+    let mut reply = [0; MAX_PAYLOAD];
+    let reply = &mut reply[..message.len()];
+    reply.copy_from_slice(message);
+    reply[0] = ECHO_REPLY;
+    write_u16(reply, 2, 0);
+    let sum = checksum(reply, 0);
+    write_u16(reply, 2, sum);
+    ip::send(source, PROTOCOL_ICMP, reply);
+}
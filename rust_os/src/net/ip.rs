@@ -0,0 +1,120 @@
+//! IPv4
+//!
+//! Wir senden nie fragmentiert und verwerfen empfangene Fragmente. Optionen werden überlesen.
+//! Pakete außerhalb des lokalen Netzes gehen an das Gateway.
+
+use super::{
+    arp, checksum, icmp, read_u16, tcp, udp, write_u16, Ipv4, BROADCAST_MAC, ETHERTYPE_IPV4, MTU,
+};
+use crate::consts::{NET_GATEWAY, NET_IP, NET_NETMASK};
+
+pub const HEADER_SIZE: usize = 20;
+/// The biggest payload of a packet
+pub const MAX_PAYLOAD: usize = MTU - HEADER_SIZE;
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+pub const BROADCAST: Ipv4 = [255; 4];
+const TTL: u8 = 64;
+/// More fragments and the fragment offset
+const FRAGMENT_MASK: u16 = 0x3FFF;
+
+/// The identification of the next packet
+static mut IDENTIFICATION: u16 = 0;
+
+/// Whether the address is in our network
+fn is_local(ip: Ipv4) -> bool {
+    (0..4).all(|i| ip[i] & NET_NETMASK[i] == NET_IP[i] & NET_NETMASK[i])
+}
+
+/// Whether the address is the broadcast address of our network
+fn is_broadcast(ip: Ipv4) -> bool {
+    ip == BROADCAST || (is_local(ip) && (0..4).all(|i| ip[i] | NET_NETMASK[i] == 0xFF))
+}
+
+/// The sum of the pseudo header that TCP and UDP put in front of their checksum
+pub fn pseudo_header_sum(source: Ipv4, destination: Ipv4, protocol: u8, len: usize) -> u32 {
+    [
+        u16::from_be_bytes([source[0], source[1]]),
+        u16::from_be_bytes([source[2], source[3]]),
+        u16::from_be_bytes([destination[0], destination[1]]),
+        u16::from_be_bytes([destination[2], destination[3]]),
+        protocol as u16,
+        len as u16,
+    ]
+    .into_iter()
+    .map(|word| word as u32)
+    .sum()
+}
+
+/// Checks the header and hands the payload to the protocol
+pub fn receive(packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0xF) as usize * 4;
+    let total_len = read_u16(packet, 2) as usize;
+    if header_len < HEADER_SIZE
+        || total_len < header_len
+        || total_len > packet.len()
+        || checksum(&packet[..header_len], 0) != 0
+        || read_u16(packet, 6) & FRAGMENT_MASK != 0
+    {
+        return;
+    }
+    let mut source = [0; 4];
+    let mut destination = [0; 4];
+    source.copy_from_slice(&packet[12..16]);
+    destination.copy_from_slice(&packet[16..20]);
+    if destination != NET_IP && !is_broadcast(destination) {
+        return;
+    }
+    // Frames are padded to 60 bytes, so only total_len counts
+    let payload = &packet[header_len..total_len];
+    match packet[9] {
+        PROTOCOL_ICMP if destination == NET_IP => icmp::receive(source, payload),
+        PROTOCOL_UDP => udp::receive(source, payload),
+        PROTOCOL_TCP if destination == NET_IP => tcp::receive(source, payload),
+        _ => (),
+    }
+}
+
+/// Sends the payload to the destination.
+/// Returns false if it couldn't be sent yet (unknown MAC address or busy EMAC), then try again later
+pub fn send(destination: Ipv4, protocol: u8, payload: &[u8]) -> bool {
+    if payload.len() > MAX_PAYLOAD {
+        return false;
+    }
+    let mac = match destination {
+        _ if is_broadcast(destination) => BROADCAST_MAC,
+        _ => {
+            let next_hop = match is_local(destination) {
+                true => destination,
+                false => NET_GATEWAY,
+            };
+            match arp::lookup(next_hop) {
+                Some(mac) => mac,
+                None => {
+                    arp::request(next_hop);
+                    return false;
+                }
+            }
+        }
+    };
+    let len = HEADER_SIZE + payload.len();
+    let mut packet = [0; MTU];
+    packet[0] = 0x45;
+    write_u16(&mut packet, 2, len as u16);
+    unsafe {
+        write_u16(&mut packet, 4, IDENTIFICATION);
+        IDENTIFICATION = IDENTIFICATION.wrapping_add(1);
+    }
+    packet[8] = TTL;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&NET_IP);
+    packet[16..20].copy_from_slice(&destination);
+    let sum = checksum(&packet[..HEADER_SIZE], 0);
+    write_u16(&mut packet, 10, sum);
+    packet[HEADER_SIZE..len].copy_from_slice(payload);
+    super::send(mac, ETHERTYPE_IPV4, &packet[..len])
+}
@@ -0,0 +1,312 @@
+//! Die Sockets und der Net-Syscall
+//!
+//! Ein Socket gehört dem Thread, der ihn geöffnet hat, und wird über seinen Index (handle) angesprochen.
+//! Nichts blockiert: Was gerade nicht geht, gibt WOULD_BLOCK zurück und der Thread versucht es später,
+//! siehe die Wrapper in `syscalls.rs`.
+
+use super::{
+    tcp::{State, Tcb},
+    udp::UdpSocket,
+    Ipv4,
+};
+use crate::thread::ID;
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    slice,
+};
+
+pub const SOCKET_NUMBER: usize = 8;
+/// Returned by the Net syscall if the operation has to be tried again later
+pub const WOULD_BLOCK: u32 = u32::MAX - 1;
+/// Local ports that are picked automatically
+const EPHEMERAL_PORTS: u16 = 49152;
+
+/// An address and port of a remote host
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SocketAddr {
+    pub ip: Ipv4,
+    pub port: u16,
+}
+
+pub enum Socket {
+    Udp(UdpSocket),
+    Tcp(Tcb),
+}
+
+struct Slot {
+    owner: ID,
+    socket: Socket,
+}
+
+const EMPTY: Option<Slot> = None;
+static mut SOCKETS: [Option<Slot>; SOCKET_NUMBER] = [EMPTY; SOCKET_NUMBER];
+static mut NEXT_PORT: u16 = EPHEMERAL_PORTS;
+
+/// The socket with the handle
+pub fn socket(handle: usize) -> Option<&'static mut Socket> {
+    unsafe { (*addr_of_mut!(SOCKETS)).get_mut(handle)?.as_mut() }.map(|slot| &mut slot.socket)
+}
+
+/// The socket with the handle, if it belongs to the thread
+fn owned(handle: u32, owner: ID) -> Result<&'static mut Socket, &'static str> {
+    match unsafe { (*addr_of_mut!(SOCKETS)).get_mut(handle as usize) } {
+        Some(Some(slot)) if slot.owner == owner => Ok(&mut slot.socket),
+        _ => Err("Unknown socket"),
+    }
+}
+
+fn allocate(socket: Socket, owner: ID) -> Result<usize, &'static str> {
+    let handle = unsafe { (*addr_of!(SOCKETS)).iter().position(|slot| slot.is_none()) }
+        .ok_or("No free socket")?;
+    unsafe { SOCKETS[handle] = Some(Slot { owner, socket }) };
+    Ok(handle)
+}
+
+fn sockets() -> impl Iterator<Item = &'static mut Socket> {
+    unsafe { (*addr_of_mut!(SOCKETS)).iter_mut() }
+        .flatten()
+        .map(|slot| &mut slot.socket)
+}
+
+/// All TCP connections and listeners
+pub fn tcp_connections() -> impl Iterator<Item = &'static mut Tcb> {
+    sockets().filter_map(|socket| match socket {
+        Socket::Tcp(tcb) => Some(tcb),
+        _ => None,
+    })
+}
+
+/// The connection to remote on the local port
+pub fn tcp_connection(port: u16, remote: SocketAddr) -> Option<&'static mut Tcb> {
+    tcp_connections().find(|tcb| {
+        tcb.local_port == port
+            && tcb.remote == remote
+            && !matches!(tcb.state, State::Closed | State::Listen)
+    })
+}
+
+/// The handle of the socket that listens on the port
+pub fn tcp_listener(port: u16) -> Option<usize> {
+    (0..SOCKET_NUMBER).find(|&handle| match socket(handle) {
+        Some(Socket::Tcp(tcb)) => tcb.local_port == port && tcb.state == State::Listen,
+        _ => false,
+    })
+}
+
+/// Puts a new connection of the listener into a free socket of the same owner
+pub fn accept_connection(listener: usize, tcb: Tcb) -> Result<usize, &'static str> {
+    let owner = unsafe { SOCKETS[listener].as_ref() }
+        .ok_or("Unknown socket")?
+        .owner;
+    allocate(Socket::Tcp(tcb), owner)
+}
+
+/// The UDP socket on the port
+pub fn udp_socket(port: u16) -> Option<&'static mut UdpSocket> {
+    sockets().find_map(|socket| match socket {
+        Socket::Udp(udp) if udp.port == port => Some(udp),
+        _ => None,
+    })
+}
+
+fn port_in_use(port: u16, udp: bool) -> bool {
+    sockets().any(|socket| match socket {
+        Socket::Udp(socket) => udp && socket.port == port,
+        Socket::Tcp(tcb) => !udp && tcb.local_port == port,
+    })
+}
+
+/// A free local port, or the requested one if it is free
+fn local_port(port: u16, udp: bool) -> Result<u16, &'static str> {
+    if port != 0 {
+        return match port_in_use(port, udp) {
+            true => Err("Port in use"),
+            false => Ok(port),
+        };
+    }
+    unsafe {
+        while port_in_use(NEXT_PORT, udp) {
+            NEXT_PORT = NEXT_PORT.checked_add(1).unwrap_or(EPHEMERAL_PORTS);
+        }
+        let port = NEXT_PORT;
+        NEXT_PORT = NEXT_PORT.checked_add(1).unwrap_or(EPHEMERAL_PORTS);
+        Ok(port)
+    }
+}
+
+/// Frees the connections that are over and were closed by their owner
+pub fn free_finished() {
+    for slot in unsafe { (*addr_of_mut!(SOCKETS)).iter_mut() } {
+        if matches!(slot, Some(Slot { socket: Socket::Tcp(tcb), .. }) if tcb.finished()) {
+            *slot = None;
+        }
+    }
+}
+
+/// Closes the socket. A TCP connection still says goodbye to its peer before it is freed
+fn close(handle: usize) {
+    let Some(socket) = socket(handle) else { return };
+    match socket {
+        Socket::Udp(_) => unsafe { SOCKETS[handle] = None },
+        Socket::Tcp(tcb) => {
+            if tcb.state == State::Listen {
+                // Connections that were never accepted are reset
+                for child in tcp_connections().filter(|tcb| tcb.listener == Some(handle)) {
+                    child.abort();
+                }
+            }
+            tcb.close();
+        }
+    }
+    free_finished();
+}
+
+/// Closes all sockets of a thread that ended
+pub fn release(owner: ID) {
+    // close changes SOCKETS, so no reference into it is held while it runs
+    let owned = |&handle: &usize| {
+        unsafe { SOCKETS[handle].as_ref() }.is_some_and(|slot| slot.owner == owner)
+    };
+    (0..SOCKET_NUMBER).filter(owned).for_each(close);
+}
+
+/// What user space can do with sockets. See `syscalls::net`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NetOp {
+    /// Opens a UDP socket on addr.port (0 picks one)
+    Udp,
+    /// Opens a TCP connection to addr
+    Connect,
+    /// Opens a TCP socket that listens on addr.port
+    Listen,
+    /// Takes a new connection of the listening socket
+    Accept,
+    /// Whether the connection is established
+    Status,
+    /// Sends the buffer (for UDP to addr)
+    Send,
+    /// Receives into the buffer (for UDP also sets addr)
+    Recv,
+    Close,
+}
+
+impl TryFrom<u32> for NetOp {
+    type Error = &'static str;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        use NetOp::*;
+        Ok(match value {
+            0 => Udp,
+            1 => Connect,
+            2 => Listen,
+            3 => Accept,
+            4 => Status,
+            5 => Send,
+            6 => Recv,
+            7 => Close,
+            _ => return Err("Unknown net operation"),
+        })
+    }
+}
+
+/// The argument of the Net syscall
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct NetCall {
+    pub op: u32,
+    pub handle: u32,
+    pub addr: SocketAddr,
+    pub buf: u32,
+    pub len: usize,
+}
+
+/// Executes the Net syscall for the thread
+pub fn user_op(call: &mut NetCall, owner: ID) -> Result<u32, &'static str> {
+    let value = match NetOp::try_from(call.op)? {
+        NetOp::Udp => {
+            let port = local_port(call.addr.port, true)?;
+            allocate(Socket::Udp(UdpSocket::new(port)), owner)? as u32
+        }
+        NetOp::Connect => {
+            let port = local_port(0, false)?;
+            let handle = allocate(Socket::Tcp(Tcb::new(port, State::Closed)), owner)?;
+            if let Some(Socket::Tcp(tcb)) = socket(handle) {
+                tcb.connect(call.addr);
+            }
+            handle as u32
+        }
+        NetOp::Listen => {
+            let port = local_port(call.addr.port, false)?;
+            allocate(Socket::Tcp(Tcb::new(port, State::Listen)), owner)? as u32
+        }
+        NetOp::Accept => {
+            let Socket::Tcp(listener) = owned(call.handle, owner)? else {
+                return Err("Not a TCP socket");
+            };
+            if listener.state != State::Listen {
+                return Err("Socket doesn't listen");
+            }
+            let listener = call.handle as usize;
+            let accepted = (0..SOCKET_NUMBER).find(|&handle| match socket(handle) {
+                Some(Socket::Tcp(tcb)) => {
+                    tcb.listener == Some(listener) && tcb.connected() == Ok(true)
+                }
+                _ => false,
+            });
+            match accepted {
+                Some(handle) => {
+                    if let Some(Socket::Tcp(tcb)) = socket(handle) {
+                        tcb.listener = None;
+                        call.addr = tcb.remote;
+                    }
+                    handle as u32
+                }
+                None => WOULD_BLOCK,
+            }
+        }
+        NetOp::Status => match owned(call.handle, owner)? {
+            Socket::Tcp(tcb) => tcb.connected()? as u32,
+            Socket::Udp(_) => 1,
+        },
+        NetOp::Send => {
+            let buf = unsafe { slice::from_raw_parts(call.buf as *const u8, call.len) };
+            match owned(call.handle, owner)? {
+                Socket::Udp(udp) => match udp.send(call.addr, buf)? {
+                    true => buf.len() as u32,
+                    false => WOULD_BLOCK,
+                },
+                Socket::Tcp(tcb) => match tcb.send(buf)? {
+                    0 if !buf.is_empty() => WOULD_BLOCK,
+                    count => count as u32,
+                },
+            }
+        }
+        NetOp::Recv => {
+            let buf = unsafe { slice::from_raw_parts_mut(call.buf as *mut u8, call.len) };
+            match owned(call.handle, owner)? {
+                Socket::Udp(udp) => match udp.recv(buf) {
+                    Some((count, source)) => {
+                        call.addr = source;
+                        count as u32
+                    }
+                    None => WOULD_BLOCK,
+                },
+                Socket::Tcp(tcb) => match tcb.recv(buf) {
+                    // 0 means the peer closed the connection
+                    0 if !tcb.at_end() && !buf.is_empty() => WOULD_BLOCK,
+                    0 => {
+                        tcb.connected()?;
+                        0
+                    }
+                    count => count as u32,
+                },
+            }
+        }
+        NetOp::Close => {
+            owned(call.handle, owner)?;
+            close(call.handle as usize);
+            0
+        }
+    };
+    Ok(value)
+}
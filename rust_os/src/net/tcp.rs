@@ -0,0 +1,566 @@
+//! TCP
+//!
+//! Ein einfaches TCP: Handshake, Abbau in beide Richtungen, Retransmission mit exponentiellem Backoff
+//! und die Fenster beider Seiten. Segmente außer der Reihe werden nicht gepuffert, sondern mit einem
+//! doppelten ACK beantwortet, der Sender wiederholt sie dann.
+//! Der Sendepuffer hält alle Daten ab snd_una, bestätigte werden vorne entfernt.
+//! Eine Retransmission fängt einfach wieder bei snd_una an (go-back-n).
+
+use super::{
+    checksum,
+    ip::{self, pseudo_header_sum, MAX_PAYLOAD, PROTOCOL_TCP},
+    read_u16, read_u32,
+    socket::{self, Socket, SocketAddr},
+    write_u16, write_u32, Ipv4, Ring,
+};
+use crate::{
+    consts::NET_IP,
+    sys_timer::{is_due, ticks_between, SysTimer},
+    timer_counter,
+};
+
+const HEADER_SIZE: usize = 20;
+pub const BUFFER_SIZE: usize = 2048;
+/// The segment size we announce
+const MSS: usize = MAX_PAYLOAD - HEADER_SIZE;
+/// The segment size if the peer doesn't announce one
+const DEFAULT_MSS: usize = 536;
+/// The first retransmission timeout, it doubles with every retry
+const RETRANSMIT_MS: u32 = 500;
+const MAX_RETRIES: u8 = 6;
+const TIME_WAIT_MS: u32 = 2000;
+// Flags
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+/// The option that announces the segment size
+const OPTION_MSS: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+use State::*;
+
+/// Whether sequence number a comes before b
+#[inline(always)]
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// The transmission control block of a connection
+pub struct Tcb {
+    pub state: State,
+    pub local_port: u16,
+    pub remote: SocketAddr,
+    /// The listening socket that received the SYN, until the connection is accepted
+    pub listener: Option<usize>,
+    /// Whether the connection was reset or timed out
+    pub reset: bool,
+    /// Whether the user closed the socket
+    closing: bool,
+    /// The oldest unacknowledged sequence number
+    snd_una: u32,
+    /// The next sequence number we send
+    snd_nxt: u32,
+    snd_wnd: u32,
+    mss: usize,
+    /// The next sequence number we expect
+    rcv_nxt: u32,
+    /// The sequence number of our FIN once it is sent
+    fin_seq: Option<u32>,
+    /// Retransmission or the end of the time wait
+    timer: Option<u32>,
+    retries: u8,
+    /// The sent but unacknowledged and the unsent data
+    tx: Ring<BUFFER_SIZE>,
+    rx: Ring<BUFFER_SIZE>,
+}
+
+impl Tcb {
+    pub const fn new(local_port: u16, state: State) -> Self {
+        Tcb {
+            state,
+            local_port,
+            remote: SocketAddr {
+                ip: [0; 4],
+                port: 0,
+            },
+            listener: None,
+            reset: false,
+            closing: false,
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            mss: DEFAULT_MSS,
+            rcv_nxt: 0,
+            fin_seq: None,
+            timer: None,
+            retries: 0,
+            tx: Ring::new(),
+            rx: Ring::new(),
+        }
+    }
+
+    /// Starts the handshake with the remote
+    pub fn connect(&mut self, remote: SocketAddr) {
+        self.remote = remote;
+        self.state = SynSent;
+        self.start(timer_counter::now());
+    }
+
+    /// Picks the initial sequence number and sends the SYN
+    fn start(&mut self, iss: u32) {
+        self.snd_una = iss;
+        self.snd_nxt = iss.wrapping_add(1);
+        self.send_syn();
+        self.arm(RETRANSMIT_MS);
+    }
+
+    #[inline(always)]
+    fn arm(&mut self, ms: u32) {
+        self.timer = Some(SysTimer::new().deadline_in(ms));
+    }
+
+    /// Whether our SYN is still unacknowledged
+    #[inline(always)]
+    fn syn_pending(&self) -> bool {
+        matches!(self.state, SynSent | SynReceived)
+    }
+
+    /// Whether the handshake is done (or failed)
+    pub fn connected(&self) -> Result<bool, &'static str> {
+        match self.state {
+            Closed if self.reset => Err("Connection reset"),
+            SynSent | SynReceived => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Whether the peer won't send anything anymore
+    pub fn at_end(&self) -> bool {
+        matches!(
+            self.state,
+            Closed | CloseWait | Closing | LastAck | TimeWait
+        )
+    }
+
+    /// Whether the connection is over and the socket can be freed
+    pub fn finished(&self) -> bool {
+        self.state == Closed && (self.closing || self.listener.is_some())
+    }
+
+    fn send_segment(&self, seq: u32, flags: u8, data: &[u8]) -> bool {
+        let window = self.rx.free().min(u16::MAX as usize) as u16;
+        send_segment(
+            self.local_port,
+            self.remote,
+            seq,
+            self.rcv_nxt,
+            flags,
+            window,
+            data,
+        )
+    }
+
+    fn send_syn(&self) {
+        let flags = match self.state {
+            SynReceived => SYN | ACK,
+            _ => SYN,
+        };
+        self.send_segment(self.snd_una, flags, &[]);
+    }
+
+    #[inline(always)]
+    fn send_ack(&self) {
+        self.send_segment(self.snd_nxt, ACK, &[]);
+    }
+
+    /// Sends as much of the buffered data as the window allows, then the FIN if the user closed
+    fn output(&mut self) {
+        if self.syn_pending() || self.state == Closed {
+            return;
+        }
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if offset >= self.tx.len() {
+                break;
+            }
+            let window = (self.snd_wnd as usize).saturating_sub(offset);
+            let len = (self.tx.len() - offset).min(self.mss).min(window);
+            if len == 0 {
+                break;
+            }
+            let mut data = [0; MSS];
+            self.tx.peek(offset, &mut data[..len]);
+            if !self.send_segment(self.snd_nxt, ACK | PSH, &data[..len]) {
+                break;
+            }
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+        let all_sent = self.snd_nxt == self.snd_una.wrapping_add(self.tx.len() as u32);
+        if self.closing
+            && self.fin_seq.is_none()
+            && all_sent
+            && self.send_segment(self.snd_nxt, FIN | ACK, &[])
+        {
+            self.fin_seq = Some(self.snd_nxt);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.state = match self.state {
+                Established => FinWait1,
+                CloseWait => LastAck,
+                state => state,
+            };
+        }
+        let waiting = self.snd_nxt != self.snd_una
+            || !self.tx.is_empty()
+            || (self.closing && self.fin_seq.is_none());
+        if waiting && self.timer.is_none() {
+            self.arm(RETRANSMIT_MS);
+        }
+    }
+
+    fn retransmit(&mut self) {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.state = Closed;
+            self.reset = true;
+            self.timer = None;
+            return;
+        }
+        self.arm(RETRANSMIT_MS << self.retries);
+        if self.syn_pending() {
+            return self.send_syn();
+        }
+        self.snd_nxt = self.snd_una;
+        self.fin_seq = None;
+        // A closed window is probed with one byte, so that we learn when it opens again
+        let window = self.snd_wnd;
+        self.snd_wnd = window.max(1);
+        self.output();
+        self.snd_wnd = window;
+    }
+
+    /// Processes a segment of the connection
+    fn process(&mut self, seq: u32, ack: u32, flags: u8, window: u16, data: &[u8], mss: usize) {
+        if self.state == SynSent {
+            if flags & ACK != 0 && ack != self.snd_nxt {
+                if flags & RST == 0 {
+                    send_reset(self.local_port, self.remote, seq, ack, flags, data.len());
+                }
+                return;
+            }
+            if flags & RST != 0 {
+                if flags & ACK != 0 {
+                    self.abort();
+                }
+                return;
+            }
+            if flags & (SYN | ACK) == SYN | ACK {
+                self.rcv_nxt = seq.wrapping_add(1);
+                self.snd_una = ack;
+                self.snd_wnd = window as u32;
+                self.mss = mss;
+                self.state = Established;
+                self.retries = 0;
+                self.timer = None;
+                self.send_ack();
+                self.output();
+            }
+            return;
+        }
+        if flags & SYN != 0 {
+            // The peer repeats its SYN, our answer got lost
+            match self.state {
+                SynReceived => self.send_syn(),
+                _ => self.send_ack(),
+            }
+            return;
+        }
+        // Only the next expected data is accepted, an old beginning is cut off
+        let mut fin = flags & FIN != 0;
+        if before(self.rcv_nxt, seq) {
+            return self.send_ack();
+        }
+        let old = self.rcv_nxt.wrapping_sub(seq) as usize;
+        if old > 0 && old >= data.len() + fin as usize {
+            return self.send_ack();
+        }
+        let data = &data[old.min(data.len())..];
+        if flags & RST != 0 {
+            self.state = Closed;
+            self.reset = true;
+            self.timer = None;
+            return;
+        }
+        if flags & ACK == 0 {
+            return;
+        }
+        let acked = ack.wrapping_sub(self.snd_una);
+        if acked > self.snd_nxt.wrapping_sub(self.snd_una) {
+            // acknowledges something we never sent
+            return self.send_ack();
+        }
+        if acked > 0 {
+            let mut acked_data = acked as usize;
+            if self.syn_pending() {
+                acked_data -= 1;
+                self.state = Established;
+            }
+            let fin_acked = self.fin_seq == Some(ack.wrapping_sub(1));
+            if fin_acked {
+                acked_data -= 1;
+            }
+            self.tx.consume(acked_data);
+            self.snd_una = ack;
+            self.retries = 0;
+            self.timer = None;
+            if self.snd_una != self.snd_nxt {
+                self.arm(RETRANSMIT_MS);
+            }
+            if fin_acked {
+                match self.state {
+                    FinWait1 => self.state = FinWait2,
+                    Closing => self.time_wait(),
+                    LastAck => self.state = Closed,
+                    _ => (),
+                }
+            }
+        }
+        self.snd_wnd = window as u32;
+        let mut ack_now = false;
+        if !data.is_empty() && matches!(self.state, Established | FinWait1 | FinWait2) {
+            let count = self.rx.push(data);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(count as u32);
+            ack_now = true;
+            // The rest and the FIN come again
+            fin &= count == data.len();
+        }
+        if fin && self.state != Closed {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            ack_now = true;
+            match self.state {
+                Established => self.state = CloseWait,
+                FinWait1 => self.state = Closing,
+                FinWait2 => self.time_wait(),
+                _ => (),
+            }
+        }
+        if ack_now {
+            self.send_ack();
+        }
+        self.output();
+    }
+
+    fn time_wait(&mut self) {
+        self.state = TimeWait;
+        self.arm(TIME_WAIT_MS);
+    }
+
+    /// Queues data. Returns how much of it fit into the send buffer
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, &'static str> {
+        if self.closing {
+            return Err("Socket is closed");
+        }
+        match self.state {
+            SynSent | SynReceived | Established | CloseWait => (),
+            Closed if self.reset => return Err("Connection reset"),
+            _ => return Err("Connection is closing"),
+        }
+        let count = self.tx.push(data);
+        self.output();
+        Ok(count)
+    }
+
+    /// Takes received data
+    pub fn recv(&mut self, buf: &mut [u8]) -> usize {
+        let was_full = self.rx.free() < self.mss;
+        let count = self.rx.pop(buf);
+        // Tell the peer that the window is open again
+        if was_full && count > 0 && !self.at_end() {
+            self.send_ack();
+        }
+        count
+    }
+
+    /// Closes our direction. Buffered data is still sent, then the FIN
+    pub fn close(&mut self) {
+        self.closing = true;
+        match self.state {
+            Listen | SynSent => self.state = Closed,
+            _ => self.output(),
+        }
+    }
+
+    /// Resets the connection right away
+    pub fn abort(&mut self) {
+        if !matches!(self.state, Closed | Listen | SynSent | TimeWait) {
+            self.send_segment(self.snd_nxt, RST | ACK, &[]);
+        }
+        self.state = Closed;
+        self.reset = true;
+        self.timer = None;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn send_segment(
+    local_port: u16,
+    remote: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    data: &[u8],
+) -> bool {
+    // A SYN announces our segment size
+    let header = match flags & SYN {
+        0 => HEADER_SIZE,
+        _ => HEADER_SIZE + 4,
+    };
+    let len = header + data.len();
+    let mut segment = [0; MAX_PAYLOAD];
+    write_u16(&mut segment, 0, local_port);
+    write_u16(&mut segment, 2, remote.port);
+    write_u32(&mut segment, 4, seq);
+    write_u32(&mut segment, 8, if flags & ACK != 0 { ack } else { 0 });
+    segment[12] = ((header / 4) as u8) << 4;
+    segment[13] = flags;
+    write_u16(&mut segment, 14, window);
+    if header > HEADER_SIZE {
+        segment[20] = OPTION_MSS;
+        segment[21] = 4;
+        write_u16(&mut segment, 22, MSS as u16);
+    }
+    segment[header..len].copy_from_slice(data);
+    let sum = checksum(
+        &segment[..len],
+        pseudo_header_sum(NET_IP, remote.ip, PROTOCOL_TCP, len),
+    );
+    write_u16(&mut segment, 16, sum);
+    ip::send(remote.ip, PROTOCOL_TCP, &segment[..len])
+}
+
+/// Answers a segment that belongs to no connection
+fn send_reset(local_port: u16, remote: SocketAddr, seq: u32, ack: u32, flags: u8, len: usize) {
+    match flags & ACK {
+        0 => {
+            let len = len as u32 + (flags & SYN != 0) as u32 + (flags & FIN != 0) as u32;
+            send_segment(
+                local_port,
+                remote,
+                0,
+                seq.wrapping_add(len),
+                RST | ACK,
+                0,
+                &[],
+            )
+        }
+        _ => send_segment(local_port, remote, ack, 0, RST, 0, &[]),
+    };
+}
+
+/// The segment size from the options of a SYN
+fn announced_mss(options: &[u8]) -> usize {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            0 => break,
+            1 => i += 1,
+            OPTION_MSS if i + 4 <= options.len() => {
+                return (read_u16(options, i + 2) as usize).clamp(64, MSS)
+            }
+            _ => match options.get(i + 1) {
+                Some(&len) if len >= 2 => i += len as usize,
+                _ => break,
+            },
+        }
+    }
+    DEFAULT_MSS
+}
+
+/// Hands the segment to its connection, a SYN to a listening socket
+pub fn receive(source: Ipv4, segment: &[u8]) {
+    let len = segment.len();
+    if len < HEADER_SIZE
+        || checksum(
+            segment,
+            pseudo_header_sum(source, NET_IP, PROTOCOL_TCP, len),
+        ) != 0
+    {
+        return;
+    }
+    let offset = (segment[12] >> 4) as usize * 4;
+    if offset < HEADER_SIZE || offset > len {
+        return;
+    }
+    let remote = SocketAddr {
+        ip: source,
+        port: read_u16(segment, 0),
+    };
+    let local_port = read_u16(segment, 2);
+    let seq = read_u32(segment, 4);
+    let ack = read_u32(segment, 8);
+    let flags = segment[13];
+    let window = read_u16(segment, 14);
+    let data = &segment[offset..];
+    let mss = announced_mss(&segment[HEADER_SIZE..offset]);
+    if let Some(tcb) = socket::tcp_connection(local_port, remote) {
+        return tcb.process(seq, ack, flags, window, data, mss);
+    }
+    match socket::tcp_listener(local_port) {
+        Some(listener) if flags & (SYN | ACK | RST) == SYN => {
+            let mut tcb = Tcb::new(local_port, SynReceived);
+            tcb.remote = remote;
+            tcb.listener = Some(listener);
+            tcb.rcv_nxt = seq.wrapping_add(1);
+            tcb.snd_wnd = window as u32;
+            tcb.mss = mss;
+            // Without a free socket the SYN is ignored, the peer tries again
+            if let Ok(handle) = socket::accept_connection(listener, tcb) {
+                if let Some(Socket::Tcp(tcb)) = socket::socket(handle) {
+                    tcb.start(timer_counter::now());
+                }
+            }
+        }
+        _ if flags & RST == 0 => send_reset(local_port, remote, seq, ack, flags, data.len()),
+        _ => (),
+    }
+}
+
+/// Retransmits and ends time waits
+pub fn tick(now: u32) {
+    for tcb in socket::tcp_connections() {
+        match tcb.timer {
+            Some(deadline) if is_due(deadline, now) => {
+                tcb.timer = None;
+                match tcb.state {
+                    TimeWait => tcb.state = Closed,
+                    _ => tcb.retransmit(),
+                }
+            }
+            _ => (),
+        }
+    }
+    socket::free_finished();
+}
+
+/// The next retransmission or end of a time wait
+pub fn next_deadline() -> Option<u32> {
+    let now = SysTimer::new().now();
+    socket::tcp_connections()
+        .filter_map(|tcb| tcb.timer)
+        .min_by_key(|&deadline| ticks_between(now, deadline))
+}
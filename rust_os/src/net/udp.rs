@@ -0,0 +1,110 @@
+//! UDP
+//!
+//! Jeder UDP-Socket hat einen Port und einen Empfangspuffer, in dem die Datagramme
+//! samt Absender hintereinander liegen. Passt ein Datagramm nicht mehr hinein, wird es verworfen.
+
+use super::{
+    checksum,
+    ip::{self, pseudo_header_sum, MAX_PAYLOAD, PROTOCOL_UDP},
+    read_u16, socket,
+    socket::SocketAddr,
+    write_u16, Ipv4, Ring,
+};
+use crate::consts::NET_IP;
+
+const HEADER_SIZE: usize = 8;
+/// The biggest datagram we can send
+pub const MAX_DATAGRAM: usize = MAX_PAYLOAD - HEADER_SIZE;
+const BUFFER_SIZE: usize = 4096;
+/// Every datagram in the buffer starts with the source ip, port and the length
+const RECORD_HEADER: usize = 8;
+
+pub struct UdpSocket {
+    pub port: u16,
+    rx: Ring<BUFFER_SIZE>,
+}
+
+impl UdpSocket {
+    pub const fn new(port: u16) -> Self {
+        UdpSocket {
+            port,
+            rx: Ring::new(),
+        }
+    }
+
+    fn deliver(&mut self, source: SocketAddr, data: &[u8]) {
+        if self.rx.free() < RECORD_HEADER + data.len() {
+            return;
+        }
+        let mut record = [0; RECORD_HEADER];
+        record[..4].copy_from_slice(&source.ip);
+        write_u16(&mut record, 4, source.port);
+        write_u16(&mut record, 6, data.len() as u16);
+        self.rx.push(&record);
+        self.rx.push(data);
+    }
+
+    /// Takes the oldest datagram. If buf is too small, the rest is lost
+    pub fn recv(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let mut record = [0; RECORD_HEADER];
+        if self.rx.pop(&mut record) < RECORD_HEADER {
+            return None;
+        }
+        let source = SocketAddr {
+            ip: [record[0], record[1], record[2], record[3]],
+            port: read_u16(&record, 4),
+        };
+        let len = read_u16(&record, 6) as usize;
+        let count = len.min(buf.len());
+        self.rx.peek(0, &mut buf[..count]);
+        self.rx.consume(len);
+        Some((count, source))
+    }
+
+    /// Sends the datagram. Returns false if it couldn't be sent yet
+    pub fn send(&self, destination: SocketAddr, data: &[u8]) -> Result<bool, &'static str> {
+        if data.len() > MAX_DATAGRAM {
+            return Err("Datagram too big");
+        }
+        let len = HEADER_SIZE + data.len();
+        let mut datagram = [0; MAX_PAYLOAD];
+        write_u16(&mut datagram, 0, self.port);
+        write_u16(&mut datagram, 2, destination.port);
+        write_u16(&mut datagram, 4, len as u16);
+        datagram[HEADER_SIZE..len].copy_from_slice(data);
+        let sum = checksum(
+            &datagram[..len],
+            pseudo_header_sum(NET_IP, destination.ip, PROTOCOL_UDP, len),
+        );
+        // 0 means no checksum, so it is sent as 0xFFFF
+        write_u16(&mut datagram, 6, if sum == 0 { 0xFFFF } else { sum });
+        Ok(ip::send(destination.ip, PROTOCOL_UDP, &datagram[..len]))
+    }
+}
+
+/// Hands the datagram to the socket on its port
+pub fn receive(source: Ipv4, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = read_u16(datagram, 4) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if read_u16(datagram, 6) != 0
+        && checksum(
+            datagram,
+            pseudo_header_sum(source, NET_IP, PROTOCOL_UDP, len),
+        ) != 0
+    {
+        return;
+    }
+    let source = SocketAddr {
+        ip: source,
+        port: read_u16(datagram, 0),
+    };
+    if let Some(socket) = socket::udp_socket(read_u16(datagram, 2)) {
+        socket.deliver(source, &datagram[HEADER_SIZE..]);
+    }
+}
@@ -0,0 +1,80 @@
+//! Der Panic-Handler
+//!
+//! Er gibt den Zustand des Systems aus und hält dann an oder startet neu,
+//! je nach PANIC_RESET_TICKS und PANIC_WAIT_FOR_KEY in consts.rs
+
+use crate::{
+    consts::{PANIC_RESET_TICKS, PANIC_WAIT_FOR_KEY},
+    crash,
+    exceptions::{AIC, IVT},
+    led, log, println,
+    registry::get_registry,
+    serial::Serial,
+    sys_timer::SysTimer,
+    thread::get_threads,
+    util, watchdog,
+};
+
+static mut PANICKING: bool = false;
+
+#[panic_handler]
+fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    util::mask_interrupts();
+    if unsafe { PANICKING } {
+        // A panic while panicking, the state dump is not reliable
+        println!("\nPanicked again: {info:?}");
+        util::idle()
+    }
+    unsafe { PANICKING = true }
+    match PANIC_RESET_TICKS {
+        Some(_) => watchdog::stop(),
+        // Nobody pets it anymore, but halting or waiting for a key must not end in a reset
+        None => watchdog::pause(),
+    }
+    println!("\nPanicked: {info:?}");
+    crash::backtrace_here();
+    dump_state();
+    match PANIC_RESET_TICKS {
+        Some(ticks) => {
+            println!("Rebooting in {} ms", ticks as u32 * 1000 / 256);
+            SysTimer::new().arm_reset(ticks);
+            led::panic_blink(|| false);
+            util::idle()
+        }
+        None if PANIC_WAIT_FOR_KEY => {
+            println!("Press any key to reboot");
+            let serial = Serial::new();
+            led::panic_blink(|| serial.rx_ready());
+            SysTimer::new().reset()
+        }
+        None => {
+            println!("System halted");
+            led::panic_blink(|| false);
+            util::idle()
+        }
+    }
+}
+
+/// Prints the threads, the exception vectors, the interrupt configuration, the drivers and the kernel log
+fn dump_state() {
+    let threads = get_threads();
+    println!(
+        "Current thread: {:#?}",
+        threads.get_thread(threads.curr_thread)
+    );
+    println!("Threads:");
+    for thread in threads.array.iter().filter_map(|t| t.as_ref()) {
+        println!(
+            "{:>2}: {:?}, pc {:08x}, sp {:08x}",
+            thread.id, thread.state, thread.regs.pc, thread.regs.sp
+        );
+    }
+    println!("IVT:");
+    IVT::new().print();
+    println!("AIC:");
+    AIC::new().print();
+    println!("Drivers:");
+    get_registry().print();
+    println!("Kernel log:");
+    log::dump();
+}
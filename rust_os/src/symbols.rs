@@ -0,0 +1,67 @@
+//! The kernels symbol table for crash reports
+//!
+//! The table can only be known after linking, so `ksyms.py` writes it into the
+//! reserved `.ksyms` section of the finished ELF file (see the Makefile).
+//! Format (little endian):
+//! - magic "KSYM"
+//! - count: u32
+//! - count entries of (address: u32, name offset: u32), sorted by address
+//! - the names, each terminated by a 0 byte. The offsets are relative to the first name
+
+use core::{ptr::addr_of, slice, str};
+
+const KSYMS_SIZE: usize = 64 * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+
+// Nur der Platzhalter, gelesen wird über __ksyms_start,
+// damit der Compiler nicht annimmt, dass hier nur Nullen stehen
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    // see kernel.lds
+    static __ksyms_start: u8;
+}
+
+#[inline(always)]
+fn read_u32(offset: usize) -> u32 {
+    let base = addr_of!(__ksyms_start);
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { *base.add(offset + i) };
+    }
+    u32::from_le_bytes(bytes)
+}
+
+/// Reads the 0 terminated name at the given offset
+fn read_name(offset: usize) -> &'static str {
+    let start = unsafe { addr_of!(__ksyms_start).add(offset) };
+    let len = (0..KSYMS_SIZE - offset)
+        .take_while(|&i| unsafe { *start.add(i) } != 0)
+        .count();
+    str::from_utf8(unsafe { slice::from_raw_parts(start, len) }).unwrap_or("<invalid name>")
+}
+
+/// Looks up the function that contains the given address.
+/// Returns its name and the offset of the address into it
+pub fn lookup(addr: u32) -> Option<(&'static str, u32)> {
+    if read_u32(0).to_le_bytes() != *MAGIC {
+        // ksyms.py didn't run
+        return None;
+    }
+    let count = read_u32(4) as usize;
+    let entry = |i: usize| (read_u32(8 + i * 8), read_u32(12 + i * 8) as usize);
+    // binary search for the last symbol at or below addr
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry(mid).0 <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let (start, name) = entry(low.checked_sub(1)?);
+    Some((read_name(8 + count * 8 + name), addr - start))
+}
@@ -1,9 +1,25 @@
 //! Threads and a thread list which includes scheduling
 
-use crate::{consts::THREAD_NUMBER, println, thread, util, Registers};
-use core::arch::asm;
+use crate::{
+    clock,
+    consts::{
+        STACK_CANARY, STACK_GUARD_SIZE, SVC_MODE, THREAD_NUMBER, TICKLESS, USER_MEM,
+        USER_STACK_SIZE,
+    },
+    error, exceptions, fs,
+    gpio::Pin,
+    led, log, net,
+    power_management::PMC,
+    println,
+    sys_timer::{ticks_between, ticks_to_ms, SysTimer},
+    thread, trace, util, watchdog, Registers,
+};
+use core::{
+    arch::asm,
+    ptr::{read_volatile, write_volatile},
+};
 
-/// The idle thread function
+/// The idle thread function. It runs in sys mode, so it may use the PMC
 #[inline(always)]
 pub fn idle() {
     loop {
@@ -12,7 +28,11 @@ pub fn idle() {
         // saving power or switching hyper-threads."
         // Unfortunately however, this yields an undefined instruction exception
         // core::hint::spin_loop();
-        util::nop()
+        // Whenever nothing else runs, we have time for the kernel log
+        exceptions::report_fiqs();
+        log::flush();
+        // Stops the processor clock until the next interrupt
+        PMC::new().idle();
     }
 }
 
@@ -22,15 +42,23 @@ pub static mut THREADS: ThreadList = ThreadList {
         None, None, None, None, None, None, None, None,
     ],
     curr_thread: 0,
+    last_switch: 0,
+    total_time: 0,
 };
 
 /// A threads State. To get whether a thread in ready state is actually running,
 /// check whether its id matches the ThreadLists curr_thread
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum State {
     Ready,
+    /// Sleeping until the given tick of the real-time timer, see sys_timer::deadline_in
     Sleeping(u32),
-    WaitingForChar,
+    /// Waiting for a char on the given TTY, see serial.rs
+    WaitingForChar(usize),
+    /// Waiting for the driver with the given name to finish a transfer
+    WaitingForDevice(&'static str),
+    /// Waiting for an edge of the pin to the given level, see gpio.rs
+    WaitingForPin(Pin, bool),
 }
 
 /// A Thread-ID. Is always also an index into the ThreadList array
@@ -43,9 +71,40 @@ pub struct Thread {
     pub state: State,
     pub regs: Registers,
     pub psr: u32,
+    /// The deepest stack usage in bytes that was observed at a context switch
+    pub stack_peak: u32,
+    /// How long the thread has run (real-time timer ticks)
+    pub cpu_time: u32,
     next_thread: Option<ID>,
 }
 
+/// The top of the user stack of the thread with the given id
+#[inline(always)]
+fn stack_top(id: ID) -> u32 {
+    (USER_MEM - USER_STACK_SIZE * id) as u32
+}
+
+/// The lowest address of the user stack of the thread with the given id.
+/// This is where the guard region starts
+#[inline(always)]
+fn stack_bottom(id: ID) -> u32 {
+    stack_top(id) - USER_STACK_SIZE as u32
+}
+
+/// Fills the guard region of the given threads stack with canaries
+fn paint_guard(id: ID) {
+    let guard = stack_bottom(id) as *mut u32;
+    for i in 0..STACK_GUARD_SIZE / 4 {
+        unsafe { write_volatile(guard.add(i), STACK_CANARY) }
+    }
+}
+
+/// Checks whether all canaries in the guard region of the given threads stack are still intact
+fn guard_intact(id: ID) -> bool {
+    let guard = stack_bottom(id) as *const u32;
+    (0..STACK_GUARD_SIZE / 4).all(|i| unsafe { read_volatile(guard.add(i)) } == STACK_CANARY)
+}
+
 /// Gets the global ThreadList
 #[inline(always)]
 pub fn get_threads() -> &'static mut ThreadList {
@@ -56,6 +115,10 @@ pub fn get_threads() -> &'static mut ThreadList {
 pub struct ThreadList {
     pub array: ThreadArray,
     pub curr_thread: ID,
+    /// When the current thread was scheduled (real-time timer ticks)
+    last_switch: u32,
+    /// All the time that was distributed to threads (real-time timer ticks)
+    total_time: u32,
 }
 
 // TODO: make ThreadList iterable over links
@@ -64,12 +127,17 @@ impl ThreadList {
     /// This mostly means initializing the idle thread.
     #[inline(always)]
     pub fn init(&mut self) -> &mut Self {
-        // The idle thread can run in SYS_MODE
+        // The idle thread can run in SYS_MODE. Its state is never saved, so it starts over
+        // on the top of its stack (the stack of id 0, which no other thread gets) every time
+        let mut regs = thread!(idle());
+        regs.sp = stack_top(0);
         self.array[0] = Some(Thread {
             id: 0,
             state: State::Ready,
-            regs: thread!(idle()),
+            regs,
             psr: crate::SYS_MODE,
+            stack_peak: 0,
+            cpu_time: 0,
             next_thread: None,
         });
         self
@@ -122,13 +190,16 @@ impl ThreadList {
                 .as_ref()
                 .is_none()
             {
-                regs.sp = (crate::USER_MEM - crate::USER_STACK_SIZE * next_id) as u32;
+                regs.sp = stack_top(next_id);
                 regs.lr = util::exit as u32; // Should jump back to exit
+                paint_guard(next_id);
                 let new_thread = Thread {
                     id: next_id,
                     state: State::Ready,
                     psr: crate::USR_MODE,
                     regs,
+                    stack_peak: 0,
+                    cpu_time: 0,
                     next_thread: thread.next_thread,
                 };
                 thread.next_thread = Some(next_id);
@@ -163,14 +234,87 @@ impl ThreadList {
         0
     }
 
+    /// Leaves the boot code and enters the first thread with its mode and unmasked interrupts.
+    /// The sys stack is free for interrupt handlers from then on
+    pub fn start(&mut self) -> ! {
+        self.schedule_next();
+        let thread = self.curr_thread();
+        unsafe {
+            asm!(
+                // svc mode with masked interrupts, its spsr becomes the cpsr of the thread
+                "msr cpsr_c, #({SVC} | (1<<7) | (1<<6))",
+                "msr spsr_cxsf, r1",
+                "mov lr, r0",
+                // The user registers, which the sys mode shares
+                "ldmia lr, {{r0-r14}}^",
+                "nop",
+                "ldr lr, [lr, #(15*4)]",
+                "movs pc, lr",
+                SVC = const SVC_MODE,
+                in("r0") &thread.regs,
+                in("r1") thread.psr,
+                options(noreturn)
+            )
+        }
+    }
+
     /// Schedules the next thread to run
     pub fn schedule_next(&mut self) -> ID {
         let id = self._schedule_next();
-        println!("Scheduled thread {:#?}", self.get_thread(id));
+        let timer = SysTimer::new();
+        let now = timer.now();
+        let elapsed = ticks_between(self.last_switch, now);
+        if let Some(thread) = self.get_mut_thread(self.curr_thread) {
+            thread.cpu_time += elapsed;
+        }
+        self.total_time += elapsed;
+        self.last_switch = now;
+        trace!("Scheduled thread {:#?}", self.get_thread(id));
         self.curr_thread = id;
+        led::show_idle(id == 0);
+        if TICKLESS {
+            timer.program(self.next_deadline(), id == 0);
+        } else {
+            timer.program(self.next_deadline(), false);
+        }
         id
     }
 
+    /// The earliest deadline of all sleeping threads, the heartbeat, the watchdog, the network and the clock
+    fn next_deadline(&self) -> Option<u32> {
+        let now = SysTimer::new().now();
+        self.array
+            .iter()
+            .flatten()
+            .filter_map(|t| match t.state {
+                State::Sleeping(deadline) => Some(deadline),
+                _ => None,
+            })
+            .chain(led::next_heartbeat())
+            .chain(watchdog::next_pet())
+            .chain(net::next_deadline())
+            .chain(clock::next_deadline())
+            .min_by_key(|&deadline| ticks_between(now, deadline))
+    }
+
+    /// Prints how long every thread has run and how much of the time the cpu was idle
+    pub fn print_stats(&self) {
+        for thread in self.array.iter().flatten() {
+            println!(
+                "thread {:>2}: {} ms cpu time, {} bytes stack peak",
+                thread.id,
+                ticks_to_ms(thread.cpu_time),
+                thread.stack_peak
+            );
+        }
+        let idle = self.get_thread(0).map_or(0, |t| t.cpu_time);
+        println!(
+            "idle: {}% of {} ms",
+            idle as u64 * 100 / (self.total_time as u64).max(1),
+            ticks_to_ms(self.total_time)
+        );
+    }
+
     /// Get a reference to the current thread
     #[inline(always)]
     pub fn curr_thread(&self) -> &Thread {
@@ -210,6 +354,8 @@ impl ThreadList {
         let next_thread = self.get_thread(id)?.next_thread;
         // Now we can delete the current thread
         self.array[id] = None;
+        net::socket::release(id);
+        fs::release(id);
         // The thread that points towards the deleted thread
         let thread_before = self
             .iter_mut()
@@ -223,17 +369,43 @@ impl ThreadList {
         Some(())
     }
 
-    /// Saves the context from the given regs and the spsr to the current threads regs
+    /// Saves the context from the given regs and the spsr to the current threads regs.
+    /// Also checks the threads stack and ends the thread if it overflowed.
+    /// Returns false if the current thread had to be ended
     #[inline(always)]
-    pub fn save_state(&mut self, regs: &Registers) {
+    pub fn save_state(&mut self, regs: &Registers) -> bool {
         if self.curr_thread == 0 {
             // We don't save any state into the idle thread
-            return;
+            return true;
         }
         let thread = self.curr_mut_thread();
         thread.regs = regs.clone();
         crate::get_psr!(a = spsr);
         thread.psr = a;
+        self.check_stack()
+    }
+
+    /// Updates the stack high-water mark of the current thread and checks its guard region.
+    /// An overflowing thread is ended. Returns false in that case
+    fn check_stack(&mut self) -> bool {
+        let thread = self.curr_mut_thread();
+        let id = thread.id;
+        let top = stack_top(id);
+        let sp = thread.regs.sp;
+        if sp <= top {
+            thread.stack_peak = thread.stack_peak.max(top - sp);
+        }
+        let in_bounds = sp <= top && sp >= stack_bottom(id) + STACK_GUARD_SIZE as u32;
+        if in_bounds && guard_intact(id) {
+            return true;
+        }
+        error!(
+            "Stack overflow in thread {id} (sp: {sp:x}, peak usage: {} of {} bytes): Ending the thread",
+            thread.stack_peak,
+            USER_STACK_SIZE - STACK_GUARD_SIZE
+        );
+        self.end_thread(id);
+        false
     }
 
     /// Writes the current threads context run into regs and the spsr
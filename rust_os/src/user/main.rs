@@ -1,6 +1,68 @@
-use crate::{thread, Registers};
+use crate::{
+    clock::{DateTime, TimeVal},
+    fs::{APPEND, CREATE},
+    net::socket::SocketAddr,
+    spi::{SpiDevice, TransferMode},
+    thread, Registers,
+};
+use core::{
+    fmt::{self, Write},
+    ops::Range,
+};
 
-use super::syscalls::{exit, fork, put_char, read_char, sleep};
+use super::syscalls::{
+    capture, close, dmesg, exit, file_size, fork, get_time_of_day, gpio_input, gpio_output,
+    gpio_read, gpio_set, gpio_wait, net_close, open, put_char, pwm, read, read_char, receive, seek,
+    set_time, sleep, spi_transfer, suspend, tcp_accept, tcp_connect, tcp_listen, tcp_recv,
+    tcp_send, tty_read, tty_write, twi_read, twi_write, udp_open, udp_recv_from, udp_send_to,
+    watchdog_claim, watchdog_pet, write, write_file,
+};
+
+/// The DataFlash of the board
+const FLASH: SpiDevice = SpiDevice {
+    cs: 0,
+    mode: 0,
+    clock_hz: 1_000_000,
+    transfer: TransferMode::Dma,
+};
+/// Manufacturer and Device ID Read of the DataFlash
+const READ_ID: u8 = 0x9F;
+/// The DS1307 compatible RTC on the TWI
+const RTC: u8 = 0x68;
+/// The battery backed RAM of the RTC behind its clock registers
+const RTC_RAM: u8 = 0x08;
+/// The port of the echo servers
+const ECHO_PORT: u16 = 7;
+/// The host the TCP client talks to, an echo server on the gateway of the board
+const ECHO_HOST: SocketAddr = SocketAddr {
+    ip: [10, 0, 2, 2],
+    port: ECHO_PORT,
+};
+/// The files the file demo appends to, the RAM disk if there is no card
+const NOTES: [&str; 2] = ["/sd/notes.txt", "/ram/notes.txt"];
+/// TIOA3 on PB6, wired to TIOA4 on PB8 to measure it
+const PWM_OUTPUT: u32 = 6;
+const CAPTURE_CHANNEL: u32 = 4;
+/// The tty the echo runs on, USART1
+const ECHO_TTY: usize = 2;
+/// Ends the echo
+const CTRL_D: u32 = 0x04;
+/// A button to ground on PB0 of the expansion header
+const BUTTON_PIN: u32 = 32;
+/// An LED on PB1 of the expansion header
+const LED_PIN: u32 = 33;
+
+/// Prints to the dbgu without waiting for every char
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match write(0, s.as_bytes()) {
+            true => Ok(()),
+            false => Err(fmt::Error),
+        }
+    }
+}
 
 #[allow(improper_ctypes_definitions)]
 extern "aapcs" fn child(c: char) {
@@ -11,10 +73,251 @@ extern "aapcs" fn child(c: char) {
     exit()
 }
 
+/// Prints the newest records of the kernel log
+fn show_log() {
+    let mut buf = [0; 512];
+    let len = dmesg(&mut buf);
+    write(0, &buf[..len]);
+}
+
+/// Toggles the LED on every press of the button, five times
+extern "aapcs" fn toggle_led() {
+    gpio_input(BUTTON_PIN, true);
+    gpio_output(LED_PIN, false);
+    for _ in 0..5 {
+        gpio_wait(BUTTON_PIN, false);
+        gpio_set(LED_PIN, !gpio_read(LED_PIN));
+        gpio_wait(BUTTON_PIN, true);
+    }
+    exit()
+}
+
+/// Receives a block of 16 chars from the tty in one go and prints it
+extern "aapcs" fn receive_block(tty: usize) {
+    let mut buf = [0; 16];
+    match receive(tty, &mut buf) {
+        true => _ = writeln!(Console, "Received {:?}", core::str::from_utf8(&buf)),
+        false => _ = writeln!(Console, "Couldn't receive from tty {tty}"),
+    }
+    exit()
+}
+
+/// Takes the watchdog over from the kernel and pets it every second.
+/// If this thread hangs, the board is reset
+extern "aapcs" fn supervisor() {
+    if watchdog_claim() {
+        while watchdog_pet() {
+            sleep(1000);
+        }
+    }
+    _ = writeln!(Console, "Couldn't take the watchdog over");
+    exit()
+}
+
+/// Sends every char received on the tty back, until ctrl+d
+extern "aapcs" fn echo(tty: usize) {
+    loop {
+        match tty_read(tty) {
+            u32::MAX | CTRL_D => break,
+            c => tty_write(tty, c as u8 as char),
+        }
+    }
+    exit()
+}
+
+/// Outputs a 1 kHz PWM signal and measures its frequency
+fn measure_pwm() {
+    if !pwm(PWM_OUTPUT, 1000, 25) {
+        _ = writeln!(Console, "Couldn't start the PWM");
+        return;
+    }
+    match capture(CAPTURE_CHANNEL) {
+        0 => _ = writeln!(Console, "No signal on TIOA{CAPTURE_CHANNEL}"),
+        hz => _ = writeln!(Console, "Measured {hz} Hz on TIOA{CAPTURE_CHANNEL}"),
+    }
+    pwm(PWM_OUTPUT, 0, 0);
+}
+
+/// Reads the JEDEC id of the DataFlash
+fn flash_id() {
+    let mut buf = [READ_ID, 0, 0, 0];
+    match spi_transfer(&FLASH, &mut buf) {
+        true => _ = writeln!(Console, "DataFlash id {:02x?}", &buf[1..]),
+        false => _ = writeln!(Console, "SPI transfer failed"),
+    }
+}
+
+/// Reads the time from the RTC and counts in its RAM how often this was done
+fn read_rtc() {
+    let mut time = [0; 3];
+    let mut count = [0u8];
+    if !twi_read(RTC, 0, &mut time) || !twi_read(RTC, RTC_RAM, &mut count) {
+        _ = writeln!(Console, "No RTC on the TWI");
+        return;
+    }
+    count[0] = count[0].wrapping_add(1);
+    twi_write(RTC, RTC_RAM, &count);
+    // BCD, the seconds register also holds the clock halt bit
+    let [seconds, minutes, hours] = time;
+    _ = writeln!(
+        Console,
+        "RTC time {:02x}:{minutes:02x}:{:02x}, read {} times",
+        hours & 0x3F,
+        seconds & 0x7F,
+        count[0]
+    );
+}
+
+/// Sends every UDP datagram back to where it came from
+extern "aapcs" fn udp_echo() {
+    let Some(socket) = udp_open(ECHO_PORT) else {
+        _ = writeln!(Console, "Couldn't open UDP port {ECHO_PORT}");
+        exit()
+    };
+    let mut buf = [0; 512];
+    while let Some((len, from)) = udp_recv_from(socket, &mut buf) {
+        udp_send_to(socket, from, &buf[..len]);
+    }
+    net_close(socket);
+    exit()
+}
+
+/// Sends everything received over a TCP connection back, one connection after the other
+extern "aapcs" fn tcp_echo() {
+    let Some(listener) = tcp_listen(ECHO_PORT) else {
+        _ = writeln!(Console, "Couldn't listen on TCP port {ECHO_PORT}");
+        exit()
+    };
+    let mut buf = [0; 512];
+    while let Some((socket, peer)) = tcp_accept(listener) {
+        _ = writeln!(Console, "Echo for {:?}", peer.ip);
+        while let Some(len @ 1..) = tcp_recv(socket, &mut buf) {
+            if !tcp_send(socket, &buf[..len]) {
+                break;
+            }
+        }
+        net_close(socket);
+    }
+    net_close(listener);
+    exit()
+}
+
+/// Sends a line to the echo server of the host and prints the answer
+extern "aapcs" fn tcp_client() {
+    let Some(socket) = tcp_connect(ECHO_HOST) else {
+        _ = writeln!(Console, "Couldn't connect to {:?}", ECHO_HOST.ip);
+        exit()
+    };
+    let mut buf = [0; 64];
+    if tcp_send(socket, b"Hello from the AT91RM9200\n") {
+        if let Some(len) = tcp_recv(socket, &mut buf) {
+            write(0, &buf[..len]);
+        }
+    }
+    net_close(socket);
+    exit()
+}
+
+/// Appends a line to the first file that can be opened and prints it
+fn append_note() {
+    let Some((path, handle)) = NOTES
+        .iter()
+        .find_map(|path| Some((path, open(path, CREATE | APPEND)?)))
+    else {
+        _ = writeln!(Console, "Couldn't open any of {NOTES:?}");
+        return;
+    };
+    write_file(handle, b"A key was pressed\n");
+    let size = file_size(handle).unwrap_or(0);
+    _ = writeln!(Console, "{path} has {size} bytes:");
+    let mut buf = [0; 128];
+    seek(handle, 0);
+    while let Some(len @ 1..) = read(handle, &mut buf) {
+        write(0, &buf[..len]);
+    }
+    close(handle);
+}
+
+/// Prints the wall clock. If nobody set it yet, asks for the time and sets it
+fn show_time() {
+    if let Some(time) = get_time_of_day() {
+        _ = writeln!(Console, "{}", DateTime::from_unix(time.secs as u64));
+        return;
+    }
+    _ = writeln!(
+        Console,
+        "The clock isn't set, enter the UTC time as YYYYMMDDhhmmss:"
+    );
+    let mut digits = [0; 14];
+    for digit in digits.iter_mut() {
+        let c = read_char();
+        put_char(c);
+        let Some(value) = c.to_digit(10) else {
+            _ = writeln!(Console, "\nNot a digit");
+            return;
+        };
+        *digit = value;
+    }
+    let number = |range: Range<usize>| digits[range].iter().fold(0, |n, digit| n * 10 + digit);
+    let date = DateTime {
+        year: number(0..4),
+        month: number(4..6) as u8,
+        day: number(6..8) as u8,
+        hour: number(8..10) as u8,
+        minute: number(10..12) as u8,
+        second: number(12..14) as u8,
+    };
+    let set = date.to_unix().is_ok_and(|secs| {
+        set_time(TimeVal {
+            secs: secs as u32,
+            usecs: 0,
+        })
+    });
+    match set {
+        true => _ = writeln!(Console, "\nSet the clock to {date}"),
+        false => _ = writeln!(Console, "\nInvalid time"),
+    }
+}
+
+/// Starts the function in its own thread, so that the main thread keeps reading keys
+fn spawn(regs: &Registers) {
+    if fork(regs) == 0 {
+        _ = writeln!(Console, "Couldn't start a thread");
+    }
+}
+
+/// Upper case keys try out a syscall. Returns false if the key has none
+fn demo(key: char) -> bool {
+    match key {
+        'D' => show_log(),
+        'S' => {
+            _ = writeln!(Console, "Suspending until the next interrupt");
+            suspend()
+        }
+        'G' => spawn(&thread!(toggle_led())),
+        'W' => spawn(&thread!(supervisor())),
+        'T' => spawn(&thread!(echo(ECHO_TTY))),
+        'R' => spawn(&thread!(receive_block(ECHO_TTY))),
+        'P' => measure_pwm(),
+        'X' => flash_id(),
+        'I' => read_rtc(),
+        'U' => spawn(&thread!(udp_echo())),
+        'E' => spawn(&thread!(tcp_echo())),
+        'N' => spawn(&thread!(tcp_client())),
+        'F' => append_note(),
+        'C' => show_time(),
+        _ => return false,
+    }
+    true
+}
+
 #[no_mangle]
 extern "aapcs" fn main_thread() {
     loop {
         let char = read_char();
+        if demo(char) {
+            continue;
+        }
         let regs = &(thread!(child(char as u8, 4)));
         if fork(regs) == 0 {
             exit()
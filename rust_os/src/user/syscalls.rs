@@ -5,7 +5,12 @@ Then you call swi with the correct code.
 */
 
 // we use some types and an extern function from the os lib
+use crate::clock::TimeVal;
 use crate::exceptions::SWICode::*;
+use crate::fs::{FileCall, FileOp};
+use crate::gpio::GpioOp;
+use crate::net::socket::{NetCall, NetOp, SocketAddr, WOULD_BLOCK};
+use crate::spi::SpiDevice;
 use crate::thread;
 use crate::Registers;
 
@@ -41,7 +46,23 @@ sys_calls! {
     _fork(regs: u32) -> thread::ID as Fork,
     _sleep(time: u32) -> () as Sleep,
     put_char(c: char) -> () as PutChar,
-    read_char() -> char as ReadChar
+    read_char() -> char as ReadChar,
+    _dmesg(buf: u32, len: usize) -> usize as Dmesg,
+    suspend() -> () as Suspend,
+    _gpio(op: u32, pin: u32, value: u32) -> u32 as Gpio,
+    _watchdog(op: u32) -> u32 as Watchdog,
+    tty_read(tty: usize) -> u32 as TtyRead,
+    tty_write(tty: usize, c: char) -> () as TtyWrite,
+    _write(tty: usize, buf: u32, len: usize) -> usize as Write,
+    _pwm(output: u32, freq_hz: u32, duty: u32) -> u32 as Pwm,
+    _spi(device: u32, clock_hz: u32, buf: u32, len: usize) -> usize as Spi,
+    _twi(device: u32, register: u32, buf: u32, len: usize) -> u32 as Twi,
+    _net(call: u32) -> u32 as Net,
+    _file(call: u32) -> u32 as File,
+    _set_time(secs: u32, usecs: u32) -> u32 as SetTime,
+    _get_time_of_day(time: u32) -> u32 as GetTimeOfDay,
+    capture(channel: u32) -> u32 as Capture,
+    _receive(tty: usize, buf: u32, len: usize) -> usize as Receive
 }
 /*
 exit: Exit the current thread
@@ -53,6 +74,56 @@ fork:
 sleep: Lets the current thread sleep for the given number of ms
 put_char: Displays a char to the main serial output
 read_char: Waits for a new char from the main serial input
+dmesg: Copies the newest kernel log records into the buffer and returns the number of bytes
+suspend:
+    Puts the whole system into the slow clock mode until the next timer or serial interrupt.
+    The char that wakes the system is most likely lost
+gpio:
+    Configures, sets or reads a pin, or waits for an edge of it (see GpioOp).
+    Pins are numbered across all ports (PA0 = 0, PB27 = 59)
+    Returns the level of the pin or u32::MAX on failure
+watchdog:
+    0 hands the watchdog from the kernel to the current thread, 1 pets it
+    Returns 1 on success and 0 on failure (disabled or owned by another thread)
+tty_read: Like read_char, but on the given tty (0 is the dbgu, 1 - 4 are USART0 - USART3)
+    Returns u32::MAX for an unknown tty
+tty_write: Like put_char, but on the given tty
+write:
+    Queues the buffer for the given tty, the PDC sends it in the background
+    Returns how many bytes fit into the queue or u32::MAX for an unknown tty
+pwm:
+    Generates a PWM signal with the given frequency and duty cycle (in percent) on a TIOA/TIOB pin
+    Outputs are numbered channel * 2 + (0 for TIOA, 1 for TIOB), only TC2 - TC5 (4 - 11) are allowed
+    A frequency of 0 stops the channel. Returns 1 on success and 0 on failure
+spi:
+    Sends the buffer to the device and replaces it with the answer (full duplex)
+    device is the chip select (bits 0-1), the SPI mode (bits 2-3) and the TransferMode (bits 4-5)
+    Polled returns at once, with Dma and Interrupt the thread waits for the end of the transfer
+    Returns the number of transferred bytes or usize::MAX on failure (e.g. the bus is busy)
+twi:
+    Reads or writes the buffer from/to the I2C device
+    device is the 7-bit address (bits 0-6), 1 for writing (bit 7) and whether register is sent first (bit 8)
+    Returns 1 on success and 0 on failure (no acknowledge or timeout)
+net:
+    Works on a UDP or TCP socket, the NetCall holds the operation and its arguments (see NetOp)
+    Never blocks: Returns WOULD_BLOCK if the operation has to be tried again later
+    Otherwise returns the new socket, the number of bytes or the status. u32::MAX on failure
+file:
+    Works on a file of the kernel namespace (e.g. /sd/...), the FileCall holds the operation (see FileOp)
+    Open takes the path in buf and the flags (fs::CREATE, fs::APPEND) in arg
+    Returns the handle, the number of bytes, the position or the size. u32::MAX on failure
+set_time:
+    Sets the wall clock (and the RTC, if there is one) to the seconds and microseconds since 1970 (UTC)
+    Returns 1 on success and 0 on failure
+get_time_of_day:
+    Writes the current time into the TimeVal
+    Returns 1 on success and 0 if the clock was never set
+capture:
+    Measures the frequency of the signal on TIOA of the channel (TC2 - TC5) in Hz
+    The thread waits for two edges, at most about 100 ms. Returns 0 on failure (e.g. no signal or below about 30 Hz)
+receive:
+    Fills the whole buffer from the given tty with the PDC, the thread waits until it is full
+    Meanwhile the chars don't reach tty_read. Returns the length or usize::MAX on failure
 */
 
 pub fn fork(regs: &Registers) -> usize {
@@ -66,3 +137,232 @@ pub fn sleep(time: u32) {
     }
     _sleep(time)
 }
+
+/// Reads the kernel log
+pub fn dmesg(buf: &mut [u8]) -> usize {
+    _dmesg(buf.as_mut_ptr() as u32, buf.len())
+}
+
+/// Makes the pin an output with the given level
+pub fn gpio_output(pin: u32, level: bool) {
+    _gpio(GpioOp::Output as u32, pin, level as u32);
+}
+
+/// Makes the pin an input
+pub fn gpio_input(pin: u32, pull_up: bool) {
+    _gpio(GpioOp::Input as u32, pin, pull_up as u32);
+}
+
+pub fn gpio_set(pin: u32, level: bool) {
+    _gpio(GpioOp::Set as u32, pin, level as u32);
+}
+
+pub fn gpio_read(pin: u32) -> bool {
+    _gpio(GpioOp::Read as u32, pin, 0) == 1
+}
+
+/// Waits until the pin changes to the level, e.g. for a button. The pin should be an input
+pub fn gpio_wait(pin: u32, level: bool) -> bool {
+    _gpio(GpioOp::Wait as u32, pin, level as u32) == level as u32
+}
+
+/// Takes the watchdog over from the kernel. From then on, the thread must call
+/// watchdog_pet regularly (faster than WATCHDOG_MS) or the board is reset
+pub fn watchdog_claim() -> bool {
+    _watchdog(0) == 1
+}
+
+pub fn watchdog_pet() -> bool {
+    _watchdog(1) == 1
+}
+
+/// Writes the whole buffer to the tty without waiting for each char.
+/// Returns false for an unknown tty
+pub fn write(tty: usize, mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        let written = _write(tty, buf.as_ptr() as u32, buf.len());
+        match written {
+            usize::MAX => return false,
+            // The queue is full
+            0 => sleep(20),
+            _ => buf = &buf[written..],
+        }
+    }
+    true
+}
+
+/// Waits until the buffer is filled from the tty, see the receive syscall
+pub fn receive(tty: usize, buf: &mut [u8]) -> bool {
+    _receive(tty, buf.as_mut_ptr() as u32, buf.len()) == buf.len()
+}
+
+/// Starts a PWM signal on the output, see the pwm syscall
+pub fn pwm(output: u32, freq_hz: u32, duty: u32) -> bool {
+    _pwm(output, freq_hz, duty) == 1
+}
+
+/// Transfers the buffer to the SPI device, see the spi syscall
+pub fn spi_transfer(device: &SpiDevice, buf: &mut [u8]) -> bool {
+    let id = device.cs as u32 | ((device.mode as u32) << 2) | ((device.transfer as u32) << 4);
+    _spi(id, device.clock_hz, buf.as_mut_ptr() as u32, buf.len()) == buf.len()
+}
+
+/// Reads from the register of the I2C device
+pub fn twi_read(device: u8, register: u8, buf: &mut [u8]) -> bool {
+    let id = device as u32 | (1 << 8);
+    _twi(id, register as u32, buf.as_mut_ptr() as u32, buf.len()) == 1
+}
+
+/// Writes into the register of the I2C device
+pub fn twi_write(device: u8, register: u8, data: &[u8]) -> bool {
+    let id = device as u32 | (1 << 7) | (1 << 8);
+    _twi(id, register as u32, data.as_ptr() as u32, data.len()) == 1
+}
+
+/// Executes the net syscall and retries it until it doesn't block anymore
+fn net(op: NetOp, socket: u32, addr: &mut SocketAddr, buf: *const u8, len: usize) -> Option<u32> {
+    let mut call = NetCall {
+        op: op as u32,
+        handle: socket,
+        addr: *addr,
+        buf: buf as u32,
+        len,
+    };
+    loop {
+        match _net(&mut call as *mut NetCall as u32) {
+            WOULD_BLOCK => sleep(20),
+            u32::MAX => return None,
+            value => {
+                *addr = call.addr;
+                return Some(value);
+            }
+        }
+    }
+}
+
+/// Opens a UDP socket on the port, 0 picks a free one
+pub fn udp_open(port: u16) -> Option<u32> {
+    let mut addr = SocketAddr { ip: [0; 4], port };
+    net(NetOp::Udp, 0, &mut addr, core::ptr::null(), 0)
+}
+
+/// Sends the datagram to the address
+pub fn udp_send_to(socket: u32, mut to: SocketAddr, data: &[u8]) -> bool {
+    net(NetOp::Send, socket, &mut to, data.as_ptr(), data.len()).is_some()
+}
+
+/// Waits for a datagram. Returns its length and sender
+pub fn udp_recv_from(socket: u32, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+    let mut from = SocketAddr::default();
+    let len = net(NetOp::Recv, socket, &mut from, buf.as_mut_ptr(), buf.len())?;
+    Some((len as usize, from))
+}
+
+/// Connects to the address and waits until the connection is established
+pub fn tcp_connect(mut to: SocketAddr) -> Option<u32> {
+    let socket = net(NetOp::Connect, 0, &mut to, core::ptr::null(), 0)?;
+    loop {
+        match net(NetOp::Status, socket, &mut to, core::ptr::null(), 0) {
+            Some(1) => return Some(socket),
+            Some(_) => sleep(20),
+            None => {
+                net_close(socket);
+                return None;
+            }
+        }
+    }
+}
+
+/// Opens a TCP socket that listens on the port
+pub fn tcp_listen(port: u16) -> Option<u32> {
+    let mut addr = SocketAddr { ip: [0; 4], port };
+    net(NetOp::Listen, 0, &mut addr, core::ptr::null(), 0)
+}
+
+/// Waits for a connection on the listening socket. Returns the new socket and the peer
+pub fn tcp_accept(listener: u32) -> Option<(u32, SocketAddr)> {
+    let mut peer = SocketAddr::default();
+    let socket = net(NetOp::Accept, listener, &mut peer, core::ptr::null(), 0)?;
+    Some((socket, peer))
+}
+
+/// Sends all of data over the connection
+pub fn tcp_send(socket: u32, mut data: &[u8]) -> bool {
+    let mut addr = SocketAddr::default();
+    while !data.is_empty() {
+        match net(NetOp::Send, socket, &mut addr, data.as_ptr(), data.len()) {
+            Some(sent) => data = &data[sent as usize..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Waits for data on the connection. Some(0) means that the peer closed it
+pub fn tcp_recv(socket: u32, buf: &mut [u8]) -> Option<usize> {
+    let mut addr = SocketAddr::default();
+    net(NetOp::Recv, socket, &mut addr, buf.as_mut_ptr(), buf.len()).map(|len| len as usize)
+}
+
+/// Closes a UDP or TCP socket
+pub fn net_close(socket: u32) {
+    let mut addr = SocketAddr::default();
+    net(NetOp::Close, socket, &mut addr, core::ptr::null(), 0);
+}
+
+/// Executes the file syscall
+fn file(op: FileOp, handle: u32, buf: *const u8, len: usize, arg: u32) -> Option<u32> {
+    let call = FileCall {
+        op: op as u32,
+        handle,
+        buf: buf as u32,
+        len,
+        arg,
+    };
+    match _file(&call as *const FileCall as u32) {
+        u32::MAX => None,
+        value => Some(value),
+    }
+}
+
+/// Opens the file with the flags (fs::CREATE, fs::APPEND) and returns its handle
+pub fn open(path: &str, flags: u32) -> Option<u32> {
+    file(FileOp::Open, 0, path.as_ptr(), path.len(), flags)
+}
+
+/// Reads from the file. Returns 0 at its end
+pub fn read(handle: u32, buf: &mut [u8]) -> Option<usize> {
+    file(FileOp::Read, handle, buf.as_mut_ptr(), buf.len(), 0).map(|len| len as usize)
+}
+
+/// Writes into the file at its position
+pub fn write_file(handle: u32, data: &[u8]) -> Option<usize> {
+    file(FileOp::Write, handle, data.as_ptr(), data.len(), 0).map(|len| len as usize)
+}
+
+/// Moves to the position in the file
+pub fn seek(handle: u32, position: u32) -> Option<u32> {
+    file(FileOp::Seek, handle, core::ptr::null(), 0, position)
+}
+
+pub fn file_size(handle: u32) -> Option<u32> {
+    file(FileOp::Size, handle, core::ptr::null(), 0, 0)
+}
+
+pub fn close(handle: u32) {
+    file(FileOp::Close, handle, core::ptr::null(), 0, 0);
+}
+
+/// Sets the wall clock, e.g. to DateTime::to_unix
+pub fn set_time(time: TimeVal) -> bool {
+    _set_time(time.secs, time.usecs) == 1
+}
+
+/// The time since 1970 (UTC), None if nobody set the clock yet
+pub fn get_time_of_day() -> Option<TimeVal> {
+    let mut time = TimeVal::default();
+    match _get_time_of_day(&mut time as *mut TimeVal as u32) {
+        1 => Some(time),
+        _ => None,
+    }
+}
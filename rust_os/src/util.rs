@@ -1,7 +1,12 @@
 //! All kinds of utilities that have not yet found a right place
 //!
-//! e.g. Makros, simple inlined assembly instructions and the Registers struct
+//! e.g. Makros, simple inlined assembly instructions, calibrated delays and the Registers struct
 
+use crate::{
+    info,
+    sys_timer::{ticks_between, SysTimer, RTT_HZ},
+    timer_counter,
+};
 use core::arch::{arm::__nop, asm};
 
 #[inline(always)]
@@ -9,15 +14,72 @@ pub fn nop() {
     unsafe { __nop() }
 }
 
-/// Waits the given number of cpu cycles
-#[allow(dead_code)]
-#[inline(always)]
+/// Runs x nop loop iterations. How long that takes depends on the clock and the optimization level,
+/// for real time units use delay_us and delay_ms
+#[inline(never)]
 pub fn wait(x: u32) {
     for _ in 0..x {
         nop()
     }
 }
 
+/// wait iterations per ms. Until the calibration, a guess that is too long rather than too short
+static mut LOOPS_PER_MS: u32 = 200_000;
+
+/// Measures how many wait iterations fit into a ms against the real-time timer.
+/// The sys timer must be registered. Takes about 50 ms
+pub fn calibrate_delay() {
+    let timer = SysTimer::new();
+    let mut loops = 10_000;
+    loop {
+        // start right at a tick
+        let tick = timer.now();
+        while timer.now() == tick {}
+        let start = timer.now();
+        wait(loops);
+        let ticks = ticks_between(start, timer.now());
+        // At least 32 ticks, so that the timer resolution is only about 3%
+        if ticks >= 32 {
+            let loops_per_ms = loops as u64 * RTT_HZ as u64 / (ticks as u64 * 1000);
+            unsafe { LOOPS_PER_MS = (loops_per_ms as u32).max(1) };
+            return;
+        }
+        loops *= 2;
+    }
+}
+
+/// Busy waits for the given µs. Works before the scheduler runs and with masked interrupts.
+/// Uses the TC counter once it runs, before that the calibrated loop
+pub fn delay_us(us: u32) {
+    if timer_counter::counting() {
+        let ticks = timer_counter::us_to_ticks(us);
+        let start = timer_counter::now();
+        while timer_counter::now().wrapping_sub(start) < ticks {}
+    } else {
+        let loops = us as u64 * unsafe { LOOPS_PER_MS } as u64 / 1000;
+        wait(loops.min(u32::MAX as u64) as u32);
+    }
+}
+
+/// Busy waits for the given ms
+pub fn delay_ms(ms: u32) {
+    for _ in 0..ms {
+        delay_us(1000);
+    }
+}
+
+/// Measures the calibrated loop against the TC counter and logs how far off it is.
+/// The TC counter must be running
+pub fn delay_self_test() {
+    const MS: u32 = 20;
+    let loops_per_ms = unsafe { LOOPS_PER_MS };
+    let start = timer_counter::now();
+    wait(MS * loops_per_ms);
+    let us = timer_counter::ticks_to_us(timer_counter::now().wrapping_sub(start));
+    let error = (us as i64 - MS as i64 * 1000) * 100 / (MS as i64 * 1000);
+    info!("Delay calibrated to {loops_per_ms} loops per ms, {MS} ms took {us} µs ({error:+}%)");
+}
+
 /// Just does nothing
 #[inline(always)]
 pub fn idle() -> ! {
@@ -79,6 +141,19 @@ pub fn demask_interrupts() {
     }
 }
 
+/// Allows fast interrupts (FIQ) in the current mode
+#[inline(always)]
+pub fn demask_fast_interrupts() {
+    unsafe {
+        asm!(
+          "MRS {reg}, CPSR",
+          "BIC {reg}, #(1<<6)",
+          "MSR CPSR, {reg}",
+          reg = out(reg) _
+        );
+    }
+}
+
 #[inline(always)]
 pub fn mask_interrupts() {
     unsafe {
@@ -91,6 +166,18 @@ pub fn mask_interrupts() {
     }
 }
 
+/// Runs f with masked interrupts and restores the previous mask afterwards
+#[inline(always)]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    get_psr!(psr = cpsr);
+    mask_interrupts();
+    let result = f();
+    if psr & (1 << 7) == 0 {
+        demask_interrupts();
+    }
+    result
+}
+
 // Note: most of this is stolen from beispiel_4
 #[macro_export]
 macro_rules! trampoline {
@@ -108,6 +195,8 @@ macro_rules! trampoline {
                     // pass the stack pointer
                     "mov r0, sp",
                     "bl {handler}",
+                    // Prüfen, ob einer der Kernel-Stacks übergelaufen ist
+                    "bl {check}",
                     // Zuvor gesicherte Register wieder herstellen (R0-R12, R13-R14 im User-Modus).
                     // Laut Doku sollte in der Instruktion nach LDM^ auf
                     // keines der umgeschalteten Register zugegriffen werden.
@@ -118,6 +207,7 @@ macro_rules! trampoline {
                     /* Rücksprung durch Laden des PC mit S-Bit */ 
                     ldmfd	sp!, {{pc}}^",
                     handler = sym $handler,
+                    check = sym $crate::kernel_stack::check,
                     options(noreturn)
                 )
             }
@@ -171,6 +261,18 @@ impl Registers {
     }
 }
 
+impl Registers {
+    /// The registers as r0-r15
+    pub fn as_array(&self) -> &[u32; 16] {
+        unsafe { &*(self as *const Registers as *const [u32; 16]) }
+    }
+
+    /// The registers as r0-r15
+    pub fn as_array_mut(&mut self) -> &mut [u32; 16] {
+        unsafe { &mut *(self as *mut Registers as *mut [u32; 16]) }
+    }
+}
+
 /// This macro helps to generate the correct registers.
 /// Given a function-call like input it will make registers that if executed as a thread will
 /// give the same result as if the given function was actually called